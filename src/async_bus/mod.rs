@@ -0,0 +1,249 @@
+//! Async queues backed by `tokio-postgres`.
+
+use futures_util::stream::{self, Stream, StreamExt};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_postgres::{AsyncMessage, Client, NoTls, Statement};
+use crate::{channel_name, conversion_failed, dlq_table_name_generator, invalid_name, queue_ddl,
+            table_name_generator, AVAILABLE, POSITION};
+use crate::{BusError, BusResult, FromMessageBody, Message, PopError, PushError, ToMessageBody};
+#[cfg(feature = "compression")]
+use crate::compression;
+
+/// Notifications buffered for each waiting consumer. A consumer that falls further behind than
+/// this just checks its queue again.
+const NOTIFICATION_BUFFER: usize = 64;
+
+/// Async counterpart of `PqBus`. Constructs `AsyncQueue`s sharing one connection.
+///
+/// Notifications are read off the connection by a background task and handed to every consumer
+/// waiting in `pop_async`, so queues on the same bus do not need a connection each.
+pub struct AsyncBus {
+    name: String,
+    client: Arc<Client>,
+    notifications: broadcast::Receiver<String>,
+}
+
+/// A named message queue on an `AsyncBus`.
+pub struct AsyncQueue<B> {
+    client: Arc<Client>,
+    notifications: broadcast::Receiver<String>,
+    channel: String,
+    push_stmt: Statement,
+    notify_stmt: Statement,
+    pop_stmt: Statement,
+    dead_letter_stmt: Statement,
+    name: String,
+    bus: String,
+    phantom: PhantomData<B>,
+}
+
+pub async fn connect(uri: String, name: String) -> BusResult<AsyncBus> {
+    if invalid_name(&name) {
+        return Err(BusError::InvalidBusName(name));
+    }
+
+    let (client, mut connection) =
+        tokio_postgres::connect(&uri, NoTls).await.map_err(BusError::Async)?;
+
+    // Only the driver task holds the sender, so waiting consumers see the channel close when
+    // the connection does.
+    let (sender, notifications) = broadcast::channel(NOTIFICATION_BUFFER);
+    let bus = name.clone();
+    tokio::spawn(async move {
+        let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(n)) => {
+                    // Fails only when no one is waiting.
+                    let _ = sender.send(n.channel().to_string());
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Lost async connection to bus {}: {}", bus, e);
+                    break;
+                }
+            }
+        }
+    });
+    info!("Connected to bus {}", name);
+
+    Ok(AsyncBus {
+        name,
+        client: Arc::new(client),
+        notifications,
+    })
+}
+
+impl AsyncBus {
+    /// Constructs a queue on the bus from the given `name`, creating its tables if needed.
+    pub async fn queue<B, N>(&self, name: N) -> BusResult<AsyncQueue<B>>
+        where N: Into<String>
+    {
+        let name = name.into();
+        if invalid_name(&name) {
+            return Err(BusError::InvalidQueueName(name));
+        }
+
+        info!("Creating queue {}.{}", self.name, name);
+
+        let table_name = table_name_generator(&self.name, &name);
+        let dlq_table_name = dlq_table_name_generator(&self.name, &name);
+
+        // A multi-statement batch runs as one transaction, so the advisory lock serializes
+        // concurrent setup as it does for `Queue`.
+        let mut setup = format!("SELECT pg_advisory_xact_lock(hashtext('{}'));", table_name);
+        for ddl in &queue_ddl(&table_name, &dlq_table_name, &self.name, &name) {
+            setup.push_str(ddl);
+            setup.push(';');
+        }
+        self.client.batch_execute(&setup).await.map_err(BusError::Async)?;
+        self.client
+            .batch_execute(&format!("LISTEN {}", channel_name(&table_name)))
+            .await
+            .map_err(BusError::Async)?;
+
+        let prepare = |sql: String| {
+            let client = self.client.clone();
+            async move { client.prepare(&sql).await.map_err(BusError::Async) }
+        };
+
+        Ok(AsyncQueue {
+            client: self.client.clone(),
+            notifications: self.notifications.resubscribe(),
+            channel: channel_name(&table_name),
+            push_stmt: prepare(format!("INSERT INTO {} (message) VALUES ($1) RETURNING id",
+                                       table_name))
+                .await?,
+            notify_stmt: prepare(format!("SELECT pg_notify('{}', $1)", channel_name(&table_name)))
+                .await?,
+            pop_stmt: prepare(format!(r#"
+                    DELETE FROM {0}
+                    WHERE id = (
+                        SELECT id FROM {0}
+                        WHERE {1}
+                        ORDER BY {2}
+                        LIMIT 1
+                        FOR UPDATE SKIP LOCKED
+                    )
+                    RETURNING id, message
+                    "#,
+                                      table_name,
+                                      AVAILABLE,
+                                      POSITION))
+                .await?,
+            dead_letter_stmt: prepare(format!("INSERT INTO {} (message_id, message, error) VALUES \
+                                               ($1, $2, $3)",
+                                              dlq_table_name))
+                .await?,
+            name,
+            bus: self.name.clone(),
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<B> AsyncQueue<B> {
+    /// Pushes a message into the queue and wakes its consumers.
+    pub async fn push_async<E>(&self, obj: B) -> Result<(), PushError<E>>
+        where B: ToMessageBody<E>
+    {
+        let body = obj.to_message_body().map_err(PushError::BodySeralize)?;
+        let row = self.client
+            .query_one(&self.push_stmt, &[&body])
+            .await
+            .map_err(BusError::Async)?;
+        let id: i32 = row.try_get("id").map_err(BusError::Async)?;
+        info!("Message {} pushed to queue {}.{}", id, self.bus, self.name);
+        self.client
+            .execute(&self.notify_stmt, &[&id.to_string()])
+            .await
+            .map_err(BusError::Async)?;
+        Ok(())
+    }
+
+    /// Pops the next message, waiting for one to be pushed if the queue is empty.
+    ///
+    /// A message whose body cannot be converted is moved to the dead-letter table and the error
+    /// returned, as with `Queue::pop`.
+    pub async fn pop_async<E>(&self) -> Result<B, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        loop {
+            // Subscribe before looking so a push in between is not missed.
+            let mut notifications = self.notifications.resubscribe();
+            if let Some(message) = self.try_pop().await? {
+                return Ok(message);
+            }
+
+            loop {
+                match notifications.recv().await {
+                    Ok(ref channel) if *channel == self.channel => break,
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => break,
+                    Err(RecvError::Closed) => {
+                        return Err(PopError::Generic(format!("Connection to bus {} closed",
+                                                             self.bus)))
+                    }
+                }
+            }
+            debug!("Received push notification on queue {}.{}", self.bus, self.name);
+        }
+    }
+
+    /// Returns a stream of messages that waits when the queue is empty. See `pop_async`.
+    pub fn messages_async<'q, E>(&'q self) -> impl Stream<Item = Result<B, PopError<E>>> + 'q
+        where B: FromMessageBody<E> + 'q,
+              E: 'q
+    {
+        stream::unfold(self, |queue| async move { Some((queue.pop_async().await, queue)) })
+    }
+
+    async fn try_pop<E>(&self) -> Result<Option<B>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        let row = match self.client.query_opt(&self.pop_stmt, &[]).await.map_err(BusError::Async)? {
+            None => return Ok(None),
+            Some(row) => row,
+        };
+        let id: i32 = row.try_get("id").map_err(BusError::Async)?;
+        let body: Vec<u8> = row.try_get("message").map_err(BusError::Async)?;
+
+        #[cfg(feature = "compression")]
+        let body = match compression::decompress(body.clone()) {
+            Err(e) => {
+                let reason = format!("Failed to decompress message: {}", e);
+                self.dead_letter(id, body, &reason).await?;
+                return Err(PopError::Generic(reason));
+            }
+            Ok(decompressed) => decompressed,
+        };
+
+        match B::from_message_body(Message::with_id(id, body.clone())) {
+            Ok(message) => {
+                info!("Message {} popped from queue {}.{}", id, self.bus, self.name);
+                Ok(Some(message))
+            }
+            Err(e) => {
+                self.dead_letter(id, body, &conversion_failed::<B, E>()).await?;
+                Err(PopError::BodyDeseralize(e))
+            }
+        }
+    }
+
+    /// Records a popped message that could not be read in the dead-letter table.
+    async fn dead_letter(&self, id: i32, body: Vec<u8>, reason: &str) -> BusResult<()> {
+        warn!("Dead-lettering message {} from queue {}.{}: {}",
+              id,
+              self.bus,
+              self.name,
+              reason);
+        self.client
+            .execute(&self.dead_letter_stmt, &[&id, &body, &reason])
+            .await
+            .map_err(BusError::Async)?;
+        Ok(())
+    }
+}
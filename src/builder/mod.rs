@@ -0,0 +1,179 @@
+//! Queue configuration.
+
+use std::sync::Arc;
+use std::time::Duration;
+use crate::{BusResult, Metrics, PqBus, Queue, QueueOptions};
+#[cfg(feature = "compression")]
+use crate::Compression;
+
+/// The order a queue pops messages of equal priority in. See `QueueBuilder::ordering`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Ordering {
+    /// Oldest first, in the order they were pushed.
+    #[default]
+    Fifo,
+    /// Newest first, e.g. where only the latest message matters.
+    Lifo,
+}
+
+/// Configures and creates a `Queue`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let bus = pqbus::new("postgres://postgres@localhost/pqbus", "myapp").unwrap();
+/// let queue: pqbus::Queue<String> = bus.queue_builder("jobs")
+///     .max_attempts(5)
+///     .priority()
+///     .with_max_depth(10_000)
+///     .build()
+///     .unwrap();
+/// ```
+pub struct QueueBuilder<'a> {
+    bus: &'a PqBus,
+    name: String,
+    options: QueueOptions,
+}
+
+impl<'a> QueueBuilder<'a> {
+    /// Constructs a builder for the queue `name` on `bus`.
+    pub fn new<N>(bus: &'a PqBus, name: N) -> Self
+        where N: Into<String>
+    {
+        QueueBuilder {
+            bus,
+            name: name.into(),
+            options: QueueOptions::default(),
+        }
+    }
+
+    /// Dead-letters a message instead of delivering it again once `pop_delivery` has delivered
+    /// it `max` times without it being acknowledged. Unlimited by default.
+    pub fn max_attempts(mut self, max: u32) -> Self {
+        self.options.max_attempts = Some(max);
+        self
+    }
+
+    /// Rejects pushes with `PushError::QueueFull` while the queue holds `max` or more messages.
+    /// Unlimited by default.
+    pub fn with_max_depth(mut self, max: u32) -> Self {
+        self.options.max_depth = Some(max);
+        self
+    }
+
+    /// Pops messages with the highest priority first, falling back to push order for equal
+    /// priorities. See `Queue::push_with_priority`. Queues pop in push order by default.
+    pub fn priority(mut self) -> Self {
+        self.options.priority = true;
+        self
+    }
+
+    /// Pops messages in `ordering`, by the order they were pushed. Combined with `priority`, it
+    /// orders messages of equal priority. Queues pop oldest first by default.
+    pub fn ordering(mut self, ordering: Ordering) -> Self {
+        self.options.ordering = ordering;
+        self
+    }
+
+    /// Sets the value written to the lock column of messages this queue claims with
+    /// `pop_delivery`, identifying the consumer holding them. Must be a valid name.
+    pub fn consumer_id<S>(mut self, id: S) -> Self
+        where S: Into<String>
+    {
+        self.options.consumer_id = Some(id.into());
+        self
+    }
+
+    /// Stores the queue in `table` instead of the table named after the bus and queue, e.g. to
+    /// place it in another schema with `jobs.my_queue`, or to use an existing table. Dead letters
+    /// go to the same name suffixed with `_dlq`. Queues stored this way are not included in
+    /// `PqBus::list_queues` or dropped by `PqBus::delete_queue`.
+    pub fn with_table_name<S>(mut self, table: S) -> Self
+        where S: Into<String>
+    {
+        self.options.table_name = Some(table.into());
+        self
+    }
+
+    /// Listens and sends push notifications on `channel` instead of the channel named after the
+    /// queue's table, e.g. so several queues wake the consumers of each other. Must be a valid
+    /// name. `PqBus::delete_queue` doesn't stop listening on it.
+    pub fn notify_channel<S>(mut self, channel: S) -> Self
+        where S: Into<String>
+    {
+        self.options.notify_channel = Some(channel.into());
+        self
+    }
+
+    /// Listens for pushes on a connection of the queue's own instead of the bus's, so that
+    /// blocking pops survive losing it. A lost listener connection is replaced, retrying as the
+    /// bus did when it connected, before waiting again; messages pushed meanwhile are found by
+    /// checking the queue. The bus must have been created from a uri, and the listener connects
+    /// with its SSL mode.
+    #[cfg(not(feature = "polling-only"))]
+    pub fn reconnect_listener(mut self) -> Self {
+        self.options.reconnect_listener = true;
+        self
+    }
+
+    /// Drops pushed messages whose body is identical to one already in the queue, pending or in
+    /// flight, so producers may safely send the same message more than once. Bodies are compared
+    /// by SHA-256 hash after any compression. Messages published through a `Topic` are not
+    /// deduplicated.
+    pub fn with_dedup(mut self) -> Self {
+        self.options.dedup = true;
+        self
+    }
+
+    /// Deduplicates as `with_dedup`, but only against copies pushed within `window`, letting an
+    /// identical message in again once its queued copy is older than that.
+    pub fn with_dedup_window(mut self, window: Duration) -> Self {
+        self.options.dedup = true;
+        self.options.dedup_window = Some(window);
+        self
+    }
+
+    /// Stops delivering messages once they have been in the queue for longer than `ttl`. Expired
+    /// messages are not counted as pending, and are deleted by `Queue::expire`. A TTL of zero
+    /// disables expiry.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.options.ttl = Some(ttl);
+        self
+    }
+
+    /// Reports the queue's pushes, pops, dead letters and database errors to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.options.metrics = Some(metrics);
+        self
+    }
+
+    /// Uses the queue's tables as they are instead of creating or updating them, for roles
+    /// without DDL privileges. The tables must already exist with every column pqbus uses,
+    /// e.g. from a queue built without this option.
+    pub fn assume_table_exists(mut self) -> Self {
+        self.options.assume_table_exists = true;
+        self
+    }
+
+    /// Prepares each of the queue's statements the first time it's used instead of when the
+    /// queue is built, so short-lived queues that use few operations start faster. Statements
+    /// are prepared eagerly by default, which reports broken SQL, e.g. from a table missing a
+    /// column, at build time.
+    pub fn lazy_statements(mut self) -> Self {
+        self.options.lazy_statements = true;
+        self
+    }
+
+    /// Compresses message bodies pushed to the queue with `codec`. Compressed bodies are
+    /// decompressed on pop whatever the setting, and uncompressed rows pop as they are.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, codec: Compression) -> Self {
+        self.options.compression = Some(codec);
+        self
+    }
+
+    /// Creates the queue.
+    pub fn build<B>(self) -> BusResult<Queue<'a, B>> {
+        Queue::new(self.bus, &self.name, self.options)
+    }
+}
@@ -0,0 +1,60 @@
+//! Message body compression.
+
+use flate2::Compression as GzipLevel;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{self, Read, Write};
+
+/// Marks a compressed body. It is followed by one byte naming the codec. Bodies without it are
+/// stored as pushed, so queues holding rows from before compression was enabled still decode.
+const MAGIC: &[u8] = b"\0pqz";
+
+const GZIP: u8 = 1;
+const ZSTD: u8 = 2;
+
+/// Codecs for compressing message bodies. See `QueueBuilder::with_compression`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    /// gzip at the default level.
+    Gzip,
+    /// Zstandard at the default level.
+    Zstd,
+}
+
+/// Compresses `body` with `codec`, prefixing the header that `decompress` looks for.
+pub fn compress(codec: Compression, body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = MAGIC.to_vec();
+    match codec {
+        Compression::Gzip => {
+            out.push(GZIP);
+            let mut encoder = GzEncoder::new(out, GzipLevel::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Compression::Zstd => {
+            out.push(ZSTD);
+            out.extend(::zstd::encode_all(body, 0)?);
+            Ok(out)
+        }
+    }
+}
+
+/// Reverses `compress`. Bodies without the compression header are returned unchanged.
+pub fn decompress(body: Vec<u8>) -> io::Result<Vec<u8>> {
+    if body.len() <= MAGIC.len() || !body.starts_with(MAGIC) {
+        return Ok(body);
+    }
+    let data = &body[MAGIC.len() + 1..];
+    match body[MAGIC.len()] {
+        GZIP => {
+            let mut out = vec![];
+            GzDecoder::new(data).read_to_end(&mut out)?;
+            Ok(out)
+        }
+        ZSTD => ::zstd::decode_all(data),
+        codec => {
+            Err(io::Error::new(io::ErrorKind::InvalidData,
+                               format!("Unknown compression codec {}", codec)))
+        }
+    }
+}
@@ -1,9 +1,12 @@
 //! Error types
 
-// use postgres::error::ConnectError;
+use postgres::error::ConnectError;
 use postgres::error::Error as PostgresError;
+use postgres::error::SqlState;
 use retry::RetryError;
+use std::error::Error;
 use std::fmt;
+use std::mem;
 
 /// PqBus error types
 #[derive(Debug)]
@@ -20,24 +23,80 @@ pub enum BusError {
     ReceiveNotification(PostgresError),
     /// Failed to create queue.
     Create(PostgresError),
+    /// Failed to delete queue.
+    Delete(PostgresError),
     /// Failed query the size of the queue.
     Size(PostgresError),
+    /// Failed to purge messages from the queue.
+    Purge(PostgresError),
+    /// Failed to release messages whose lock has expired.
+    Reclaim(PostgresError),
+    /// Failed to move a message to, or read from, the dead-letter table.
+    DeadLetter(PostgresError),
+    /// Failed to create or update a topic's bindings.
+    Topic(PostgresError),
     /// Connection failed.
-    Connection(String, RetryError),
+    Connection(String, ConnectionFailure),
+    /// Failed to create or check out from a connection pool.
+    Pool(String),
     /// SQL query failure.
     Sql(PostgresError),
     /// Name of bus does not match regex
     InvalidBusName(String),
     /// Name of queue does not match regex
     InvalidQueueName(String),
+    /// Name of topic does not match regex
+    InvalidTopicName(String),
+    /// Consumer id does not match regex
+    InvalidConsumerId(String),
+    /// Table name given to `QueueBuilder::with_table_name` does not match regex
+    InvalidTableName(String),
+    /// Schema name given to `PqBus::with_schema` does not match regex
+    InvalidSchemaName(String),
+    /// Channel given to `QueueBuilder::notify_channel` does not match regex
+    InvalidChannelName(String),
+    /// The queue's table already exists but wasn't created for a queue: the table, and the
+    /// columns it lacks.
+    IncompatibleSchema(String, Vec<String>),
+    /// Query on an async connection failed.
+    #[cfg(feature = "async")]
+    Async(tokio_postgres::Error),
+    /// Failed to create or register a queue's prometheus metrics.
+    #[cfg(feature = "prometheus")]
+    Metrics(prometheus::Error),
     Generic(String),
 }
 
+/// Why a connection to the database could not be opened, classified from the error of the last
+/// attempt.
+#[derive(Debug)]
+pub enum ConnectionFailure {
+    /// The server rejected the role or its credentials.
+    Authentication(ConnectError),
+    /// The database named in the uri does not exist.
+    UnknownDatabase(ConnectError),
+    /// The server could not be reached, e.g. a bad host or port.
+    Unreachable(ConnectError),
+    /// Any other failure, e.g. an invalid uri or failed SSL negotiation.
+    Other(ConnectError),
+    /// No attempt was made to connect, e.g. when retrying zero times.
+    Retry(RetryError),
+}
+
 /// Queue push errors
 #[derive(Debug)]
 pub enum PushError<E> {
     Substrate(PostgresError),
     BodySeralize(E),
+    /// The queue holds as many messages as its `QueueBuilder::with_max_depth` allows.
+    QueueFull,
+    /// The message violates a unique constraint on the queue table.
+    DuplicateMessage(PostgresError),
+    /// A queue built with `QueueBuilder::with_dedup` dropped the message as a copy of one already
+    /// queued. Only returned where a pushed message's id is expected.
+    Deduplicated,
+    /// The server is short of a resource, e.g. connections, memory or disk. Worth retrying later.
+    ServerBusy(PostgresError),
     Generic(String),
 }
 
@@ -50,9 +109,229 @@ pub enum PopError<E> {
     Generic(String),
 }
 
+/// Errors ending `Queue::pop_callback_result`
+#[derive(Debug)]
+pub enum CallbackError<E, WE> {
+    /// Failed to pop a message.
+    Pop(PopError<E>),
+    /// The work function failed. The message it failed on was released back to the queue.
+    Work(WE),
+}
+
+impl BusError {
+    /// Returns true if the error was caused by losing the connection to the database, after which
+    /// the bus needs to `reconnect`.
+    pub fn is_connection_error(&self) -> bool {
+        use self::BusError::*;
+        match *self {
+            Push(ref e) | Pop(ref e) | Notify(ref e) | Listen(ref e) |
+            ReceiveNotification(ref e) | Create(ref e) | Delete(ref e) | Size(ref e) |
+            Purge(ref e) | Reclaim(ref e) | DeadLetter(ref e) | Topic(ref e) | Sql(ref e) => {
+                connection_lost(e)
+            }
+            Connection(..) => true,
+            #[cfg(feature = "async")]
+            Async(ref e) => e.is_closed(),
+            _ => false,
+        }
+    }
+}
+
+impl ConnectionFailure {
+    /// Returns the error the last attempt to connect failed with, if one was made.
+    pub fn connect_error(&self) -> Option<&ConnectError> {
+        use self::ConnectionFailure::*;
+        match *self {
+            Authentication(ref e) | UnknownDatabase(ref e) | Unreachable(ref e) | Other(ref e) => {
+                Some(e)
+            }
+            Retry(_) => None,
+        }
+    }
+}
+
+impl From<ConnectError> for ConnectionFailure {
+    fn from(e: ConnectError) -> Self {
+        match e {
+            ConnectError::Db(ref db) if db.code.code().starts_with("28") => {
+                ConnectionFailure::Authentication(e)
+            }
+            ConnectError::Db(ref db) if db.code == SqlState::InvalidCatalogName => {
+                ConnectionFailure::UnknownDatabase(e)
+            }
+            ConnectError::Io(_) => ConnectionFailure::Unreachable(e),
+            _ => ConnectionFailure::Other(e),
+        }
+    }
+}
+
+impl<E> PushError<E> {
+    /// Returns true if the push failed because the connection to the database was lost.
+    pub fn is_connection_error(&self) -> bool {
+        match *self {
+            PushError::Substrate(ref e) => connection_lost(e),
+            _ => false,
+        }
+    }
+}
+
+impl<E> PopError<E> {
+    /// Returns true if the pop failed because the connection to the database was lost.
+    pub fn is_connection_error(&self) -> bool {
+        match *self {
+            PopError::Pop(ref e) => connection_lost(e),
+            _ => false,
+        }
+    }
+
+    /// Returns true if the pop failed on a conflict with a concurrent transaction, i.e. a
+    /// serialization failure or deadlock, so may succeed if tried again.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            PopError::Pop(PostgresError::Db(ref e)) => {
+                matches!(e.code,
+                         SqlState::SerializationFailure | SqlState::DeadlockDetected)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Distinguishes a lost connection from a query that failed.
+pub fn connection_lost(e: &PostgresError) -> bool {
+    match *e {
+        PostgresError::Io(_) => true,
+        PostgresError::Db(ref e) => {
+            matches!(e.code,
+                     SqlState::ConnectionException | SqlState::ConnectionDoesNotExist |
+                     SqlState::ConnectionFailure | SqlState::AdminShutdown |
+                     SqlState::CrashShutdown | SqlState::CannotConnectNow)
+        }
+        PostgresError::Conversion(_) => false,
+    }
+}
+
+/// Compares errors by variant and the fields that say what failed, e.g. names and messages.
+/// Postgres errors are equal if they have the same SQLSTATE, or are I/O errors of the same kind.
+impl PartialEq for BusError {
+    fn eq(&self, other: &Self) -> bool {
+        use self::BusError::*;
+        match (self, other) {
+            (Push(a), Push(b)) |
+            (Pop(a), Pop(b)) |
+            (Notify(a), Notify(b)) |
+            (Listen(a), Listen(b)) |
+            (ReceiveNotification(a), ReceiveNotification(b)) |
+            (Create(a), Create(b)) |
+            (Delete(a), Delete(b)) |
+            (Size(a), Size(b)) |
+            (Purge(a), Purge(b)) |
+            (Reclaim(a), Reclaim(b)) |
+            (DeadLetter(a), DeadLetter(b)) |
+            (Topic(a), Topic(b)) |
+            (Sql(a), Sql(b)) => same_postgres_error(a, b),
+            (Connection(a, x), Connection(b, y)) => {
+                a == b && mem::discriminant(x) == mem::discriminant(y)
+            }
+            (Pool(a), Pool(b)) |
+            (InvalidBusName(a), InvalidBusName(b)) |
+            (InvalidQueueName(a), InvalidQueueName(b)) |
+            (InvalidTopicName(a), InvalidTopicName(b)) |
+            (InvalidConsumerId(a), InvalidConsumerId(b)) |
+            (InvalidTableName(a), InvalidTableName(b)) |
+            (InvalidSchemaName(a), InvalidSchemaName(b)) |
+            (InvalidChannelName(a), InvalidChannelName(b)) |
+            (Generic(a), Generic(b)) => a == b,
+            (IncompatibleSchema(a, x), IncompatibleSchema(b, y)) => a == b && x == y,
+            #[cfg(feature = "async")]
+            (Async(a), Async(b)) => {
+                a.code() == b.code() && a.is_closed() == b.is_closed()
+            }
+            #[cfg(feature = "prometheus")]
+            (Metrics(a), Metrics(b)) => a.to_string() == b.to_string(),
+            _ => false,
+        }
+    }
+}
+
+/// Compares errors as `BusError` does, and conversion errors with `==`.
+impl<E: PartialEq> PartialEq for PushError<E> {
+    fn eq(&self, other: &Self) -> bool {
+        use self::PushError::*;
+        match (self, other) {
+            (Substrate(a), Substrate(b)) |
+            (DuplicateMessage(a), DuplicateMessage(b)) |
+            (ServerBusy(a), ServerBusy(b)) => same_postgres_error(a, b),
+            (BodySeralize(a), BodySeralize(b)) => a == b,
+            (QueueFull, QueueFull) | (Deduplicated, Deduplicated) => true,
+            (Generic(a), Generic(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Compares errors as `BusError` does, and conversion errors with `==`.
+impl<E: PartialEq> PartialEq for PopError<E> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (PopError::Pop(a), PopError::Pop(b)) => same_postgres_error(a, b),
+            (PopError::BodyDeseralize(a), PopError::BodyDeseralize(b)) => a == b,
+            (PopError::Generic(a), PopError::Generic(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Compares postgres errors by what went wrong rather than the details reported with it.
+fn same_postgres_error(a: &PostgresError, b: &PostgresError) -> bool {
+    match (a, b) {
+        (PostgresError::Db(a), PostgresError::Db(b)) => a.code == b.code,
+        (PostgresError::Io(a), PostgresError::Io(b)) => a.kind() == b.kind(),
+        (PostgresError::Conversion(a), PostgresError::Conversion(b)) => {
+            a.to_string() == b.to_string()
+        }
+        _ => false,
+    }
+}
+
+/// Keeps the database error of a failed pop, describing any other error.
 impl<E> From<BusError> for PopError<E> {
     fn from(e: BusError) -> Self {
-        PopError::Generic(format!("{}", e))
+        match e {
+            BusError::Pop(e) => PopError::Pop(e),
+            e => PopError::Generic(format!("{}", e)),
+        }
+    }
+}
+
+/// Picks out the database errors a pusher can act on, leaving the rest as `Substrate`.
+impl<E> From<PostgresError> for PushError<E> {
+    fn from(e: PostgresError) -> Self {
+        let (duplicate, busy) = match e {
+            PostgresError::Db(ref db) => {
+                (db.code == SqlState::UniqueViolation,
+                 // Class 53 is insufficient resources.
+                 db.code.code().starts_with("53"))
+            }
+            _ => (false, false),
+        };
+        if duplicate {
+            PushError::DuplicateMessage(e)
+        } else if busy {
+            PushError::ServerBusy(e)
+        } else {
+            PushError::Substrate(e)
+        }
+    }
+}
+
+/// Keeps the database error of a failed push, describing any other error.
+impl<E> From<BusError> for PushError<E> {
+    fn from(e: BusError) -> Self {
+        match e {
+            BusError::Push(e) => PushError::from(e),
+            e => PushError::Generic(format!("{}", e)),
+        }
     }
 }
 
@@ -78,6 +357,13 @@ impl From<PostgresError> for BusError {
     }
 }
 
+#[cfg(feature = "prometheus")]
+impl From<prometheus::Error> for BusError {
+    fn from(err: prometheus::Error) -> BusError {
+        BusError::Metrics(err)
+    }
+}
+
 impl<E> fmt::Display for PopError<E>
     where E: fmt::Display
 {
@@ -91,6 +377,57 @@ impl<E> fmt::Display for PopError<E>
     }
 }
 
+impl<E, WE> fmt::Display for CallbackError<E, WE>
+    where E: fmt::Display,
+          WE: fmt::Display
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CallbackError::Pop(ref e) => write!(f, "{}", e),
+            CallbackError::Work(ref e) => write!(f, "Work failed: {}", e),
+        }
+    }
+}
+
+impl<E> fmt::Display for PushError<E>
+    where E: fmt::Display
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PushError::*;
+        match *self {
+            Substrate(ref e) => write!(f, "{}", e),
+            BodySeralize(ref e) => write!(f, "{}", e),
+            QueueFull => write!(f, "Queue is full"),
+            DuplicateMessage(ref e) => write!(f, "Duplicate message: {}", e),
+            ServerBusy(ref e) => write!(f, "Server busy: {}", e),
+            Deduplicated => write!(f, "Message is a copy of one already queued"),
+            Generic(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ConnectionFailure::*;
+        match *self {
+            Authentication(ref e) => write!(f, "Authentication failed: {}", e),
+            UnknownDatabase(ref e) => write!(f, "Database does not exist: {}", e),
+            Unreachable(ref e) => write!(f, "Server unreachable: {}", e),
+            Other(ref e) => write!(f, "{}", e),
+            Retry(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for ConnectionFailure {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ConnectionFailure::Retry(ref e) => Some(e),
+            _ => self.connect_error().map(|e| e as &(dyn Error + 'static)),
+        }
+    }
+}
+
 impl fmt::Display for BusError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::BusError::*;
@@ -101,12 +438,90 @@ impl fmt::Display for BusError {
             Listen(ref e) => write!(f, "Failed to register listener form queue updates: {}", e),
             ReceiveNotification(ref e) => write!(f, "Failed to receive notification: {}", e),
             Create(ref e) => write!(f, "Failed to create queue: {}", e),
+            Delete(ref e) => write!(f, "Failed to delete queue: {}", e),
             Size(ref e) => write!(f, "Unable to get size of queue: {}", e),
+            Purge(ref e) => write!(f, "Failed to purge queue: {}", e),
+            Reclaim(ref e) => write!(f, "Failed to reclaim expired messages: {}", e),
+            DeadLetter(ref e) => write!(f, "Failed to dead-letter message: {}", e),
+            Topic(ref e) => write!(f, "Failed to update topic: {}", e),
             Connection(ref uri, ref e) => write!(f, "Failed to connect to bus {}: {}", uri, e),
+            Pool(ref e) => write!(f, "Connection pool failure: {}", e),
             Sql(ref e) => write!(f, "SQL query failed: {}", e),
             InvalidBusName(ref e) => write!(f, "Invalid bus name: {}", e),
             InvalidQueueName(ref e) => write!(f, "Invalid queue name: {}", e),
+            InvalidTopicName(ref e) => write!(f, "Invalid topic name: {}", e),
+            InvalidConsumerId(ref e) => write!(f, "Invalid consumer id: {}", e),
+            InvalidTableName(ref e) => write!(f, "Invalid table name: {}", e),
+            InvalidSchemaName(ref e) => write!(f, "Invalid schema name: {}", e),
+            InvalidChannelName(ref e) => write!(f, "Invalid channel name: {}", e),
+            IncompatibleSchema(ref table, ref missing) => {
+                write!(f,
+                       "Table {} is not a queue table, missing columns: {}",
+                       table,
+                       missing.join(", "))
+            }
+            #[cfg(feature = "async")]
+            Async(ref e) => write!(f, "Async query failed: {}", e),
+            #[cfg(feature = "prometheus")]
+            Metrics(ref e) => write!(f, "Failed to register metrics: {}", e),
             Generic(ref e) => write!(f, "{}", e),
         }
     }
 }
+
+impl Error for BusError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        use self::BusError::*;
+        match *self {
+            Push(ref e) | Pop(ref e) | Notify(ref e) | Listen(ref e) |
+            ReceiveNotification(ref e) | Create(ref e) | Delete(ref e) | Size(ref e) |
+            Purge(ref e) | Reclaim(ref e) | DeadLetter(ref e) | Topic(ref e) | Sql(ref e) => {
+                Some(e)
+            }
+            Connection(_, ref e) => Some(e),
+            #[cfg(feature = "async")]
+            Async(ref e) => Some(e),
+            #[cfg(feature = "prometheus")]
+            Metrics(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<E> Error for PushError<E>
+    where E: Error + 'static
+{
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            PushError::Substrate(ref e) |
+            PushError::DuplicateMessage(ref e) |
+            PushError::ServerBusy(ref e) => Some(e),
+            PushError::BodySeralize(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<E> Error for PopError<E>
+    where E: Error + 'static
+{
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            PopError::Pop(ref e) => Some(e),
+            PopError::BodyDeseralize(ref e) => Some(e),
+            PopError::Generic(_) => None,
+        }
+    }
+}
+
+impl<E, WE> Error for CallbackError<E, WE>
+    where E: Error + 'static,
+          WE: Error + 'static
+{
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            CallbackError::Pop(ref e) => Some(e),
+            CallbackError::Work(ref e) => Some(e),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Prometheus metrics for queues. See `Queue::register_metrics`.
+
+use postgres::Connection;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntCounter, IntGauge, Opts};
+use std::sync::{Arc, Mutex};
+use crate::{BusError, BusResult, Metrics};
+
+/// Counts a queue's activity.
+pub struct Counters {
+    pushed: IntCounter,
+    popped: IntCounter,
+    dead_lettered: IntCounter,
+    errors: IntCounter,
+}
+
+impl Metrics for Counters {
+    fn on_push(&self, _queue: &str) {
+        self.pushed.inc();
+    }
+
+    fn on_pop(&self, _queue: &str) {
+        self.popped.inc();
+    }
+
+    fn on_dead_letter(&self, _queue: &str) {
+        self.dead_lettered.inc();
+    }
+
+    fn on_error(&self, _queue: &str, _error: &BusError) {
+        self.errors.inc();
+    }
+}
+
+/// Exports a queue's counters, and the number of messages in it by state. The depth is read
+/// from the database each time the registry is gathered, on a connection of the collector's own
+/// as the queue's can't be shared between threads.
+pub struct QueueCollector {
+    conn: Mutex<Connection>,
+    stats_sql: String,
+    pending: IntGauge,
+    in_flight: IntGauge,
+    delayed: IntGauge,
+    total: IntGauge,
+    counters: Arc<Counters>,
+    descs: Vec<Desc>,
+}
+
+impl QueueCollector {
+    /// Creates the metrics of queue `queue` on bus `bus`, reading its depth with `stats_sql` on
+    /// `conn`.
+    pub fn new(conn: Connection, stats_sql: String, bus: &str, queue: &str) -> BusResult<Self> {
+        let opts = |name: &str, help: &str| {
+            Opts::new(name, help)
+                .namespace("pqbus")
+                .const_label("bus", bus)
+                .const_label("queue", queue)
+        };
+        let gauge = |name, help| IntGauge::with_opts(opts(name, help));
+        let counter = |name, help| IntCounter::with_opts(opts(name, help));
+
+        let pending = gauge("pending", "Messages a consumer could pop now.")?;
+        let in_flight = gauge("in_flight",
+                              "Messages claimed by a consumer and not yet acknowledged.")?;
+        let delayed = gauge("delayed", "Messages pushed with a delay that has not yet passed.")?;
+        let total = gauge("messages", "All messages in the queue.")?;
+        let counters = Counters {
+            pushed: counter("pushed_total", "Messages pushed to the queue.")?,
+            popped: counter("popped_total", "Messages popped from the queue.")?,
+            dead_lettered: counter("dead_lettered_total",
+                                   "Messages moved to the dead-letter table.")?,
+            errors: counter("errors_total", "Pushes and pops that failed in the database.")?,
+        };
+
+        let mut collector = QueueCollector {
+            conn: Mutex::new(conn),
+            stats_sql,
+            pending,
+            in_flight,
+            delayed,
+            total,
+            counters: Arc::new(counters),
+            descs: Vec::new(),
+        };
+        collector.descs = collector.metrics().iter().flat_map(|m| m.desc()).cloned().collect();
+        Ok(collector)
+    }
+
+    /// Returns the counters the queue reports its activity to.
+    pub fn counters(&self) -> Arc<Counters> {
+        self.counters.clone()
+    }
+
+    fn metrics(&self) -> [&dyn Collector; 8] {
+        [&self.pending,
+         &self.in_flight,
+         &self.delayed,
+         &self.total,
+         &self.counters.pushed,
+         &self.counters.popped,
+         &self.counters.dead_lettered,
+         &self.counters.errors]
+    }
+
+    /// Reads the queue's depth into the gauges.
+    fn update(&self) -> postgres::Result<()> {
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let rows = conn.query(&self.stats_sql, &[])?;
+        let row = rows.get(0);
+        self.pending.set(row.get("pending"));
+        self.in_flight.set(row.get("in_flight"));
+        self.delayed.set(row.get("delayed"));
+        self.total.set(row.get("total"));
+        Ok(())
+    }
+}
+
+impl Collector for QueueCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    /// Reports the depth last read if it can't be read now.
+    fn collect(&self) -> Vec<MetricFamily> {
+        if let Err(e) = self.update() {
+            warn!("Failed to read queue depth for metrics: {}", e);
+        }
+        self.metrics().iter().flat_map(|m| m.collect()).collect()
+    }
+}
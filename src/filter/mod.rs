@@ -0,0 +1,55 @@
+//! Selecting messages for administrative operations.
+
+use std::time::Duration;
+
+/// Selects the messages removed by `Queue::delete_where`. A message must match every condition
+/// set, so a filter with none matches every message.
+///
+/// # Example
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// // Stale messages left over from a retired producer.
+/// let filter = pqbus::DeleteFilter::new()
+///     .older_than(Duration::from_secs(24 * 60 * 60))
+///     .with_header("producer", "legacy");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeleteFilter {
+    older_than: Option<Duration>,
+    headers: Vec<(String, String)>,
+}
+
+impl DeleteFilter {
+    /// Constructs a filter matching every message.
+    pub fn new() -> Self {
+        DeleteFilter::default()
+    }
+
+    /// Matches messages pushed longer than `age` ago.
+    pub fn older_than(mut self, age: Duration) -> Self {
+        self.older_than = Some(age);
+        self
+    }
+
+    /// Matches messages pushed with the header `key` set to `value`. See
+    /// `Queue::push_with_headers`.
+    pub fn with_header<K, V>(mut self, key: K, value: V) -> Self
+        where K: Into<String>,
+              V: Into<String>
+    {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+
+    /// Returns the age set by `older_than`.
+    pub fn age(&self) -> Option<Duration> {
+        self.older_than
+    }
+
+    /// Returns the headers set by `with_header`.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+}
@@ -1,29 +1,68 @@
 //! Queue iterators.
 
+use std::cell::Cell;
 use std::marker::PhantomData;
-use super::{Message, FromMessageBody};
-use {Queue, BusResult, PopError};
+use std::sync::atomic::AtomicBool;
+use super::FromMessageBody;
+use crate::{BusError, BusResult, Delivery, Message, Queue, PopError};
+
+/// How an iterator pops each message: converted to the queue's message type, or raw.
+pub trait PopMessage<B> {
+    /// Message yielded.
+    type Message;
+    /// Error yielded.
+    type Error: From<BusError>;
+
+    /// Pops a message from `queue` if there is one pending.
+    fn pop(queue: &Queue<B>) -> Result<Option<Self::Message>, Self::Error>;
+}
+
+/// Pops messages converted to the queue's message type, as `Queue::pop` does.
+pub struct Converted<E>(PhantomData<E>);
+impl<B, E> PopMessage<B> for Converted<E>
+    where B: FromMessageBody<E>
+{
+    type Message = B;
+    type Error = PopError<E>;
+
+    fn pop(queue: &Queue<B>) -> Result<Option<B>, PopError<E>> {
+        queue.pop()
+    }
+}
+
+/// Pops messages without converting them, as `Queue::pop_raw` does.
+pub struct Raw;
+impl<B> PopMessage<B> for Raw {
+    type Message = Message;
+    type Error = BusError;
+
+    fn pop(queue: &Queue<B>) -> BusResult<Option<Message>> {
+        queue.pop_raw()
+    }
+}
 
 /// Iterator condition.
-pub trait NextMessage<B, E> {
+pub trait NextMessage<B, P: PopMessage<B>> {
     /// Returns the next message. Or `None` if the iterator is complete.
-    fn next(&self, queue: &Queue<B>) -> Option<Result<B, PopError<E>>> where B: FromMessageBody<E>;
+    fn next(&self, queue: &Queue<B>) -> Option<Result<P::Message, P::Error>>;
 }
 
 /// Generic type for iterating through a queue.
-pub struct MessageIter<'bus, 'queue, N, B, E>
-    where N: NextMessage<B, E>,
-          B: FromMessageBody<E> + 'queue,
+pub struct MessageIter<'bus, 'queue, N, B, P>
+    where N: NextMessage<B, P>,
+          P: PopMessage<B>,
+          B: 'queue,
           'bus: 'queue
 {
     next_message: N,
     queue: &'queue Queue<'bus, B>,
-    phantom: PhantomData<(B, E)>,
+    phantom: PhantomData<(B, P)>,
 }
 
-impl<'bus, 'queue, N, B, E> MessageIter<'bus, 'queue, N, B, E>
-    where N: NextMessage<B, E>,
-        B: FromMessageBody<E> + 'queue,
+impl<'bus, 'queue, N, B, P> MessageIter<'bus, 'queue, N, B, P>
+    where N: NextMessage<B, P>,
+        P: PopMessage<B>,
+        B: 'queue,
         'bus: 'queue
 {
     /// Constructs new `MessageIter` given the iterator condition `N`.
@@ -36,37 +75,132 @@ impl<'bus, 'queue, N, B, E> MessageIter<'bus, 'queue, N, B, E>
     }
 }
 
-impl<'bus, 'queue, N, B, E> Iterator for MessageIter<'bus, 'queue, N, B, E>
-    where N: NextMessage<B, E>,
-          B: FromMessageBody<E>
+impl<'bus, 'queue, N, B, P> Iterator for MessageIter<'bus, 'queue, N, B, P>
+    where N: NextMessage<B, P>,
+          P: PopMessage<B>
 {
-    type Item = Result<B, PopError<E>>;
+    type Item = Result<P::Message, P::Error>;
 
-    fn next(&mut self) -> Option<Result<B, PopError<E>>> {
+    fn next(&mut self) -> Option<Result<P::Message, P::Error>> {
         self.next_message.next(self.queue)
     }
 }
 
 /// Iterate forever, blocking when the queue is empty.
 pub struct NextMessageBlocking;
-impl<B, E> NextMessage<B, E> for NextMessageBlocking {
-    fn next(&self, q: &Queue<B>) -> Option<Result<B, PopError<E>>>
-        where B: FromMessageBody<E>
-    {
-        Some(q.pop_blocking())
+impl<B, P: PopMessage<B>> NextMessage<B, P> for NextMessageBlocking {
+    fn next(&self, q: &Queue<B>) -> Option<Result<P::Message, P::Error>> {
+        Some(q.pop_blocking_with::<P>())
+    }
+}
+
+/// Iterate until `max` messages have been received or `cancel` is set, blocking when the queue
+/// is empty.
+pub struct NextMessageLimited<'c> {
+    remaining: Cell<usize>,
+    cancel: &'c AtomicBool,
+}
+
+impl<'c> NextMessageLimited<'c> {
+    /// Constructs the condition stopping after `max` messages or once `cancel` is set.
+    pub fn new(max: usize, cancel: &'c AtomicBool) -> Self {
+        NextMessageLimited {
+            remaining: Cell::new(max),
+            cancel,
+        }
+    }
+}
+
+impl<'c, B, P: PopMessage<B>> NextMessage<B, P> for NextMessageLimited<'c> {
+    fn next(&self, q: &Queue<B>) -> Option<Result<P::Message, P::Error>> {
+        if self.remaining.get() == 0 {
+            return None;
+        }
+        match q.pop_blocking_cancellable_with::<P>(self.cancel) {
+            Ok(Some(m)) => {
+                self.remaining.set(self.remaining.get() - 1);
+                Some(Ok(m))
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
     }
 }
 
 /// Iterate until queue is empty.
 pub struct NextMessagePending;
-impl<B, E> NextMessage<B, E> for NextMessagePending {
-    fn next(&self, q: &Queue<B>) -> Option<Result<B, PopError<E>>>
-        where B: FromMessageBody<E>
-    {
-        match q.pop() {
+impl<B, P: PopMessage<B>> NextMessage<B, P> for NextMessagePending {
+    fn next(&self, q: &Queue<B>) -> Option<Result<P::Message, P::Error>> {
+        match P::pop(q) {
             Ok(Some(m)) => Some(Ok(m)),
             Ok(None) => None,
             Err(e) => Some(Err(e)),
         }
     }
 }
+
+/// Iterates pending messages as `Delivery` guards until the queue is empty.
+///
+/// Each message is deleted only when its delivery is acknowledged. A delivery dropped without
+/// being acknowledged, including while unwinding from a panic, is released back to the queue.
+pub struct DeliveryIter<'queue, B: 'queue, E> {
+    queue: &'queue Queue<'queue, B>,
+    phantom: PhantomData<E>,
+}
+
+impl<'queue, B, E> DeliveryIter<'queue, B, E> {
+    /// Constructs new `DeliveryIter` over `queue`.
+    pub fn new(queue: &'queue Queue<'queue, B>) -> Self {
+        DeliveryIter {
+            queue,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<'queue, B, E> Iterator for DeliveryIter<'queue, B, E>
+    where B: FromMessageBody<E>
+{
+    type Item = Result<Delivery<'queue, B>, PopError<E>>;
+
+    fn next(&mut self) -> Option<Result<Delivery<'queue, B>, PopError<E>>> {
+        match self.queue.pop_delivery() {
+            Ok(Some(d)) => Some(Ok(d)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Iterates pending messages in batches of up to `batch_size`, popped with `Queue::pop_batch`,
+/// until the queue is empty. The last batch may be smaller.
+pub struct BatchIter<'queue, B: 'queue, E> {
+    queue: &'queue Queue<'queue, B>,
+    batch_size: usize,
+    phantom: PhantomData<E>,
+}
+
+impl<'queue, B, E> BatchIter<'queue, B, E> {
+    /// Constructs new `BatchIter` over `queue`.
+    pub fn new(queue: &'queue Queue<'queue, B>, batch_size: usize) -> Self {
+        BatchIter {
+            queue,
+            batch_size,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<'queue, B, E> Iterator for BatchIter<'queue, B, E>
+    where B: FromMessageBody<E>
+{
+    type Item = Result<Vec<B>, PopError<E>>;
+
+    fn next(&mut self) -> Option<Result<Vec<B>, PopError<E>>> {
+        match self.queue.pop_batch(self.batch_size) {
+            Ok(ref batch) if batch.is_empty() => None,
+            Ok(batch) => Some(Ok(batch)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
@@ -66,6 +66,10 @@
 //! ```
 //!
 #![crate_type = "lib"]
+#![allow(clippy::redundant_field_names,
+         clippy::needless_lifetimes,
+         clippy::let_and_return,
+         clippy::unnecessary_unwrap)]
 // #![deny(missing_docs)]
 
 #[macro_use]
@@ -73,45 +77,339 @@ extern crate log;
 extern crate postgres;
 extern crate retry;
 extern crate regex;
+extern crate uuid;
+#[cfg(any(feature = "serde", feature = "bincode", feature = "cbor"))]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+#[cfg(feature = "bincode")]
+extern crate bincode;
+#[cfg(feature = "cbor")]
+extern crate serde_cbor;
+#[cfg(feature = "prometheus")]
+extern crate prometheus;
+#[cfg(feature = "pool")]
+extern crate r2d2;
+#[cfg(feature = "compression")]
+extern crate flate2;
+#[cfg(feature = "compression")]
+extern crate zstd;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "async")]
+extern crate tokio_postgres;
+#[cfg(feature = "async")]
+extern crate futures_util;
 
-use postgres::{Connection, SslMode};
-use postgres::notification::{Notification, Notifications};
-use postgres::stmt::Statement;
+use postgres::Connection;
+use postgres::io::NegotiateSsl;
+use postgres::notification::Notification;
+#[cfg(not(feature = "polling-only"))]
+use postgres::notification::Notifications;
+use postgres::rows::{Row, Rows};
+use postgres::types::ToSql;
+use postgres::transaction::Transaction;
 use retry::retry;
+use std::any;
+use std::convert::Infallible;
+use std::io::{self, Read, Write};
 use std::result;
-use std::time::Duration;
+use std::ptr;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "polling-only")]
+use std::{cmp, thread};
 use std::marker::PhantomData;
+use std::ops::{ControlFlow, Deref};
+#[cfg(not(feature = "polling-only"))]
+use std::cell::RefCell;
+use std::cell::{Cell, OnceCell};
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+use std::sync::atomic::{self, AtomicBool};
 use regex::Regex;
-pub use messages::{FromMessageBody, ToMessageBody, Message};
-pub use error::{BusError, PushError, PopError};
-use iter::{MessageIter, NextMessageBlocking, NextMessagePending};
+use uuid::Uuid;
+pub use messages::{FromMessageBody, ToMessageBody, ToMessageBodyRef, Message, Batch, Delivery,
+                   DeadLetter};
+#[cfg(feature = "serde")]
+pub use messages::Json;
+#[cfg(feature = "bincode")]
+pub use messages::Bincode;
+#[cfg(feature = "cbor")]
+pub use messages::Cbor;
+pub use error::{BusError, CallbackError, ConnectionFailure, PushError, PopError};
+use error::connection_lost;
+pub use postgres::SslMode;
+pub use filter::DeleteFilter;
+pub use metrics::{Metrics, NoMetrics};
+use metrics::Sinks;
+use statement::QueueStatement;
+use stream::CopyRow;
+use transaction::QueueTransaction;
+pub use outcome::{Outcome, ProcessResult, WaitOutcome};
+pub use stats::QueueStats;
+pub use topic::Topic;
+pub use builder::{Ordering, QueueBuilder};
+#[cfg(feature = "pool")]
+pub use pool::PqBusPool;
+#[cfg(feature = "pool")]
+pub use shared::SharedQueue;
+#[cfg(feature = "compression")]
+pub use compression::Compression;
+#[cfg(feature = "async")]
+pub use async_bus::{AsyncBus, AsyncQueue};
+#[cfg(not(feature = "polling-only"))]
+use listener::Listener;
+use iter::{BatchIter, Converted, DeliveryIter, MessageIter, NextMessageBlocking,
+           NextMessageLimited, NextMessagePending, PopMessage, Raw};
 use std::fmt;
 
+#[cfg(feature = "async")]
+mod async_bus;
+mod builder;
+#[cfg(feature = "compression")]
+mod compression;
 mod error;
+#[cfg(feature = "prometheus")]
+mod exporter;
+mod filter;
 mod iter;
+#[cfg(not(feature = "polling-only"))]
+mod listener;
 mod messages;
+mod metrics;
+mod outcome;
+mod stats;
+mod statement;
+mod stream;
+mod topic;
+mod transaction;
+#[cfg(feature = "pool")]
+mod pool;
+#[cfg(feature = "pool")]
+mod shared;
 
 /// Convenience alias
 pub type BusResult<T> = result::Result<T, BusError>;
 
+/// Number of connection attempts made by `new` before giving up.
+pub const DEFAULT_RETRY_ATTEMPTS: u64 = 10;
+
+/// Milliseconds between connection attempts made by `new`.
+pub const DEFAULT_RETRY_DELAY_MS: u64 = 100;
+
+/// Default time between polls for pending messages when built with `polling-only`.
+#[cfg(feature = "polling-only")]
+pub const DEFAULT_POLL_INTERVAL_MS: u64 = 250;
+
+/// Default longest time cancellable blocking pops wait between checks of their cancel flag.
+pub const DEFAULT_CANCEL_CHECK_INTERVAL_MS: u64 = 100;
+
 /// Highest level namespace. Constructs `Queue`s.
 pub struct PqBus {
     name: String,
-    conn: Connection,
+    conn: Connections,
+    /// Uri to reconnect to. Unknown for buses given their connection.
+    uri: Option<String>,
+    /// How to use SSL on reconnecting.
+    ssl: Ssl,
+    /// Schema holding the bus's tables, if not the connection's current schema.
+    schema: Option<String>,
+    /// Application name set on the connection, and again on reconnecting, if pqbus sets it.
+    application_name: Option<String>,
+    retry_attempts: u64,
+    retry_delay_ms: u64,
+    #[cfg(not(feature = "polling-only"))]
+    listening: Listening,
+    /// Position in the queues given to `select_blocking` to check first next time.
+    next_select: Cell<usize>,
+}
+
+/// Number of live queues listening on each channel of a bus's connection, so that only the last
+/// one dropped stops listening.
+#[cfg(not(feature = "polling-only"))]
+type Listening = RefCell<HashMap<String, usize>>;
+
+/// The connection behind a `PqBus`, either its own or one checked out of a pool.
+enum BusConnection {
+    Owned(Connection),
+    #[cfg(feature = "pool")]
+    Pooled(r2d2::PooledConnection<pool::ConnectionManager>),
+}
+
+impl Deref for BusConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match *self {
+            BusConnection::Owned(ref c) => c,
+            #[cfg(feature = "pool")]
+            BusConnection::Pooled(ref c) => c,
+        }
+    }
+}
+
+/// The connection a bus was created with, followed by any it has been reconnected with. Queues
+/// hold statements prepared on the connections, so replaced ones are kept until the bus is
+/// dropped, and dereferencing gives the latest.
+struct Connections {
+    conn: BusConnection,
+    next: OnceCell<Box<Connections>>,
+}
+
+impl Connections {
+    fn new(conn: BusConnection) -> Self {
+        Connections {
+            conn,
+            next: OnceCell::new(),
+        }
+    }
+
+    /// Returns the latest connection.
+    fn current(&self) -> &Connection {
+        match self.next.get() {
+            Some(next) => next.current(),
+            None => &self.conn,
+        }
+    }
+
+    /// Makes `conn` the latest connection.
+    fn replace(&self, conn: BusConnection) {
+        match self.next.get() {
+            Some(next) => next.replace(conn),
+            None => {
+                let _ = self.next.set(Box::new(Connections::new(conn)));
+            }
+        }
+    }
+}
+
+impl Deref for Connections {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.current()
+    }
+}
+
+/// How a bus's connections use SSL. Unlike `SslMode`, it is kept by the bus to `reconnect` with,
+/// so the `postgres::io::NegotiateSsl` implementation must be `'static` and `Sync`.
+#[derive(Clone, Copy, Debug)]
+pub enum Ssl {
+    /// Connections don't use SSL.
+    None,
+    /// Connections use SSL if the server supports it.
+    Prefer(&'static (dyn NegotiateSsl + Sync)),
+    /// Connections must use SSL.
+    Require(&'static (dyn NegotiateSsl + Sync)),
+}
+
+impl Ssl {
+    fn mode(self) -> SslMode<'static> {
+        match self {
+            Ssl::None => SslMode::None,
+            Ssl::Prefer(n) => SslMode::Prefer(n),
+            Ssl::Require(n) => SslMode::Require(n),
+        }
+    }
 }
 
 /// A named message queue
 pub struct Queue<'a, B> {
-    notifications: Notifications<'a>,
-    pop_stmt: Statement<'a>,
-    push_stmt: Statement<'a>,
-    notify_stmt: Statement<'a>,
-    size_stmt: Statement<'a>,
+    pqbus: &'a PqBus,
+    #[cfg(not(feature = "polling-only"))]
+    channel: String,
+    /// Connection of the queue's own it listens on instead of the bus's, if it has one.
+    #[cfg(not(feature = "polling-only"))]
+    listener: Option<Listener>,
+    /// Whether the queue listens on the bus's connection, as queues without a listener of their
+    /// own do unless `SharedQueue` builds them only for calls that don't wait.
+    #[cfg(not(feature = "polling-only"))]
+    listens: bool,
+    pop_stmt: QueueStatement<'a>,
+    pop_filtered_stmt: QueueStatement<'a>,
+    claim_stmt: QueueStatement<'a>,
+    peek_stmt: QueueStatement<'a>,
+    nack_stmt: QueueStatement<'a>,
+    extend_lock_stmt: QueueStatement<'a>,
+    requeue_stmt: QueueStatement<'a>,
+    claim_batch_stmt: QueueStatement<'a>,
+    pop_batch_stmt: QueueStatement<'a>,
+    ack_batch_stmt: QueueStatement<'a>,
+    nack_batch_stmt: QueueStatement<'a>,
+    pop_delete_stmt: QueueStatement<'a>,
+    delete_stmt: QueueStatement<'a>,
+    push_stmt: QueueStatement<'a>,
+    push_delayed_stmt: QueueStatement<'a>,
+    push_priority_stmt: QueueStatement<'a>,
+    push_expiring_stmt: QueueStatement<'a>,
+    push_headers_stmt: QueueStatement<'a>,
+    push_copy_stmt: QueueStatement<'a>,
+    lock_stream_stmt: QueueStatement<'a>,
+    read_chunk_stmt: QueueStatement<'a>,
+    duplicate_stmt: QueueStatement<'a>,
+    release_hash_stmt: QueueStatement<'a>,
+    #[cfg(not(feature = "polling-only"))]
+    notify_stmt: QueueStatement<'a>,
+    #[cfg(not(feature = "polling-only"))]
+    listeners_stmt: QueueStatement<'a>,
+    size_stmt: QueueStatement<'a>,
+    approx_size_stmt: QueueStatement<'a>,
+    empty_stmt: QueueStatement<'a>,
+    has_pending_stmt: QueueStatement<'a>,
+    pending_stmt: QueueStatement<'a>,
+    stats_stmt: QueueStatement<'a>,
+    oldest_pending_stmt: QueueStatement<'a>,
+    purge_stmt: QueueStatement<'a>,
+    delete_where_stmt: QueueStatement<'a>,
+    expire_stmt: QueueStatement<'a>,
+    reclaim_stmt: QueueStatement<'a>,
+    locks_stmt: QueueStatement<'a>,
+    dead_letter_stmt: QueueStatement<'a>,
+    dead_letters_stmt: QueueStatement<'a>,
+    take_sql: Arc<str>,
+    table_name: String,
     name: String,
     bus: String,
+    #[cfg(feature = "polling-only")]
+    poll_interval: Duration,
+    cancel_check_interval: Duration,
+    max_attempts: Option<u32>,
+    max_depth: Option<u32>,
+    dedup: bool,
+    ttl: Option<Duration>,
+    created: bool,
+    metrics: Sinks,
+    #[cfg(feature = "prometheus")]
+    stats_sql: Arc<str>,
+    consumer_id: String,
+    #[cfg(feature = "compression")]
+    compression: Option<Compression>,
     phantom: PhantomData<B>,
 }
 
+/// Settings applied when a `Queue` is created. See `QueueBuilder`.
+#[derive(Default)]
+struct QueueOptions {
+    max_attempts: Option<u32>,
+    max_depth: Option<u32>,
+    priority: bool,
+    ordering: Ordering,
+    consumer_id: Option<String>,
+    table_name: Option<String>,
+    assume_table_exists: bool,
+    dedup: bool,
+    dedup_window: Option<Duration>,
+    ttl: Option<Duration>,
+    notify_channel: Option<String>,
+    #[cfg(not(feature = "polling-only"))]
+    reconnect_listener: bool,
+    lazy_statements: bool,
+    metrics: Option<Arc<dyn Metrics>>,
+    #[cfg(feature = "compression")]
+    compression: Option<Compression>,
+}
+
 /// Constructs a new PqBus
 ///
 /// # Example
@@ -123,254 +421,3037 @@ pub fn new<S, T>(db_uri: S, name: T) -> BusResult<PqBus>
     where S: Into<String>,
           T: Into<String>
 {
-    let uri = db_uri.into();
-    let name = name.into();
+    new_with_ssl(db_uri, name, Ssl::None)
+}
+
+/// Constructs a new PqBus, connecting with the given `Ssl` mode, which `reconnect` uses too.
+///
+/// `Ssl::Prefer` and `Ssl::Require` take a `postgres::io::NegotiateSsl` implementation.
+/// Enable the `openssl` feature of the `postgres` crate to have one provided for
+/// `openssl::ssl::SslContext`, or `security-framework` on OS X.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let bus = pqbus::new_with_ssl("postgres://postgres@localhost/pqbus",
+///                               "myapp",
+///                               pqbus::Ssl::None)
+///     .unwrap();
+/// ```
+pub fn new_with_ssl<S, T>(db_uri: S, name: T, ssl: Ssl) -> BusResult<PqBus>
+    where S: Into<String>,
+          T: Into<String>
+{
+    connect(db_uri.into(),
+            name.into(),
+            ssl,
+            DEFAULT_RETRY_ATTEMPTS,
+            DEFAULT_RETRY_DELAY_MS)
+}
+
+/// Constructs a new PqBus, making up to `attempts` connection attempts `delay_ms` milliseconds
+/// apart before giving up.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let bus = pqbus::new_with_retry("postgres://postgres@localhost/pqbus", "myapp", 120, 1000)
+///     .unwrap();
+/// ```
+pub fn new_with_retry<S, T>(db_uri: S, name: T, attempts: u64, delay_ms: u64) -> BusResult<PqBus>
+    where S: Into<String>,
+          T: Into<String>
+{
+    connect(db_uri.into(), name.into(), Ssl::None, attempts, delay_ms)
+}
+
+fn connect(uri: String,
+           name: String,
+           ssl: Ssl,
+           attempts: u64,
+           delay_ms: u64)
+           -> BusResult<PqBus> {
+    if invalid_name(&name) {
+        return Err(BusError::InvalidBusName(name));
+    }
+
+    let conn = open_connection(&uri, ssl, attempts, delay_ms)?;
+    let application_name = default_application_name(&uri, &name);
+    if let Some(ref application_name) = application_name {
+        set_application_name(&conn, application_name)?;
+    }
+    info!("Connected to bus {}", name.clone());
+
+    Ok(PqBus {
+        conn: Connections::new(BusConnection::Owned(conn)),
+        name: name.clone(),
+        uri: Some(uri),
+        ssl,
+        schema: None,
+        application_name,
+        retry_attempts: attempts,
+        retry_delay_ms: delay_ms,
+        #[cfg(not(feature = "polling-only"))]
+        listening: Listening::default(),
+        next_select: Cell::new(0),
+    })
+}
+
+fn open_connection(uri: &str, ssl: Ssl, attempts: u64, delay_ms: u64) -> BusResult<Connection> {
+    let mut last_err = None;
+
+    let conn = match retry(attempts,
+                           delay_ms,
+                           || match Connection::connect(uri, ssl.mode()) {
+                               Ok(c) => Some(c),
+                               Err(e) => {
+                                   warn!("Failed to connect to postgresql: {}", e);
+                                   last_err = Some(e);
+                                   None
+                               }
+                           },
+                           |c| c.is_some()) {
+        Err(e) => {
+            let failure = match last_err {
+                None => ConnectionFailure::Retry(e),
+                Some(e) => ConnectionFailure::from(e),
+            };
+            error!("Unable to connect to {}: {}", uri, failure);
+            return Err(BusError::Connection(uri.to_string(), failure));
+        }
+        Ok(c) => c.unwrap(),
+    };
+
+    Ok(conn)
+}
+
+/// Returns the application name connections to bus `bus` report in `pg_stat_activity`, or
+/// `None` if `uri` sets one of its own.
+fn default_application_name(uri: &str, bus: &str) -> Option<String> {
+    if uri.contains("application_name=") {
+        None
+    } else {
+        Some(format!("pqbus:{}", bus))
+    }
+}
+
+fn set_application_name(conn: &Connection, name: &str) -> BusResult<()> {
+    conn.execute("SELECT set_config('application_name', $1, false)", &[&name])
+        .map_err(BusError::Sql)?;
+    Ok(())
+}
+
+/// Constructs a pool of up to `size` connections to the bus `name`, for sharing one bus between
+/// threads. See `PqBusPool` for how checked out connections are used.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let pool = pqbus::new_pool("postgres://postgres@localhost/pqbus", "myapp", 8).unwrap();
+/// let bus = pool.get().unwrap();
+/// let queue = bus.queue("new_users").unwrap();
+/// queue.push("sgibbs").unwrap();
+/// ```
+#[cfg(feature = "pool")]
+pub fn new_pool<S, T>(db_uri: S, name: T, size: u32) -> BusResult<PqBusPool>
+    where S: Into<String>,
+          T: Into<String>
+{
+    new_pool_with_ssl(db_uri, name, size, Ssl::None)
+}
 
+/// Constructs a pool as `new_pool`, whose connections, and the buses checked out from it when
+/// they reconnect, connect with the given `Ssl` mode. See `new_with_ssl`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let pool = pqbus::new_pool_with_ssl("postgres://postgres@localhost/pqbus",
+///                                     "myapp",
+///                                     8,
+///                                     pqbus::Ssl::None)
+///     .unwrap();
+/// ```
+#[cfg(feature = "pool")]
+pub fn new_pool_with_ssl<S, T>(db_uri: S, name: T, size: u32, ssl: Ssl) -> BusResult<PqBusPool>
+    where S: Into<String>,
+          T: Into<String>
+{
+    let name = name.into();
     if invalid_name(&name) {
         return Err(BusError::InvalidBusName(name));
     }
+    PqBusPool::new(db_uri.into(), name, size, ssl)
+}
+
+/// Constructs a new `AsyncBus`, whose queues push and pop without blocking the calling thread.
+///
+/// The connection is driven by a task spawned on the current tokio runtime, so this must be called
+/// from within one. Unlike `new`, the connection is attempted only once.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # async fn run() {
+/// let bus = pqbus::new_async("postgres://postgres@localhost/pqbus", "myapp").await.unwrap();
+/// let queue = bus.queue("new_users").await.unwrap();
+/// queue.push_async("sgibbs".to_string()).await.unwrap();
+/// let user: String = queue.pop_async().await.unwrap();
+/// # }
+/// ```
+#[cfg(feature = "async")]
+pub async fn new_async<S, T>(db_uri: S, name: T) -> BusResult<AsyncBus>
+    where S: Into<String>,
+          T: Into<String>
+{
+    async_bus::connect(db_uri.into(), name.into()).await
+}
+
+impl PqBus {
+    /// Constructs a bus on a connection set up by the caller, e.g. with custom TLS or session
+    /// parameters. Only the name is checked. The bus cannot `reconnect`, as it doesn't know
+    /// where the connection came from.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// let conn = postgres::Connection::connect("postgres://postgres@localhost/pqbus",
+    ///                                          pqbus::SslMode::None)
+    ///     .unwrap();
+    /// let bus = pqbus::PqBus::from_connection(conn, "myapp").unwrap();
+    /// ```
+    pub fn from_connection<T>(conn: Connection, name: T) -> BusResult<PqBus>
+        where T: Into<String>
+    {
+        let name = name.into();
+        if invalid_name(&name) {
+            return Err(BusError::InvalidBusName(name));
+        }
+        info!("Connected to bus {}", name);
+
+        Ok(PqBus {
+            conn: Connections::new(BusConnection::Owned(conn)),
+            name,
+            uri: None,
+            ssl: Ssl::None,
+            schema: None,
+            application_name: None,
+            retry_attempts: DEFAULT_RETRY_ATTEMPTS,
+            retry_delay_ms: DEFAULT_RETRY_DELAY_MS,
+            #[cfg(not(feature = "polling-only"))]
+            listening: Listening::default(),
+            next_select: Cell::new(0),
+        })
+    }
+
+    /// Places the bus's tables in `schema`, creating it if needed, instead of the connection's
+    /// current schema, e.g. to keep each tenant's queues apart. Queues created on the bus
+    /// afterwards use it. Notification channels are named after the schema too, so queues of the
+    /// same name in different schemas don't wake each other.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// let bus = pqbus::new("postgres://postgres@localhost/pqbus", "myapp")
+    ///     .and_then(|bus| bus.with_schema("tenant_a"))
+    ///     .unwrap();
+    /// ```
+    pub fn with_schema<S>(mut self, schema: S) -> BusResult<PqBus>
+        where S: Into<String>
+    {
+        let schema = schema.into();
+        if invalid_name(&schema) {
+            return Err(BusError::InvalidSchemaName(schema));
+        }
+        self.conn
+            .execute(&format!("CREATE SCHEMA IF NOT EXISTS {}", schema), &[])
+            .map_err(BusError::Create)?;
+        self.schema = Some(schema);
+        Ok(self)
+    }
+
+    /// Sets the application name the bus's connection reports in `pg_stat_activity`, e.g. to
+    /// tell apart the processes holding locks. Buses connected by pqbus report `pqbus:` followed
+    /// by the bus name by default, unless the uri sets `application_name`. Kept on `reconnect`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// let bus = pqbus::new("postgres://postgres@localhost/pqbus", "myapp")
+    ///     .and_then(|bus| bus.with_application_name("billing-worker"))
+    ///     .unwrap();
+    /// ```
+    pub fn with_application_name<S>(mut self, name: S) -> BusResult<PqBus>
+        where S: Into<String>
+    {
+        let name = name.into();
+        set_application_name(&self.conn, &name)?;
+        self.application_name = Some(name);
+        Ok(self)
+    }
+
+    /// Returns the table holding the queue `queue`, qualified with the bus's schema if it has one.
+    fn table_name(&self, queue: &str) -> String {
+        self.qualify(table_name_generator(&self.name, queue))
+    }
+
+    /// Returns the dead-letter table of the queue `queue`. See `table_name`.
+    fn dlq_table_name(&self, queue: &str) -> String {
+        self.qualify(dlq_table_name_generator(&self.name, queue))
+    }
+
+    fn qualify(&self, table: String) -> String {
+        match self.schema {
+            Some(ref schema) => format!("{}.{}", schema, table),
+            None => table,
+        }
+    }
+
+    /// Constructs a queue on the bus from the given `name`, with default settings. See
+    /// `queue_builder` to configure it.
+    pub fn queue<'a, N, T>(&'a self, name: N) -> BusResult<Queue<'a, T>>
+        where N: Into<String>
+    {
+        self.queue_builder(name).build()
+    }
+
+    /// Constructs the queue `name` as `queue` does, naming the message type up front with
+    /// `bus.typed_queue::<T, _>("jobs")`, so a mismatched type is reported where the queue is
+    /// created instead of as an unsatisfied bound on a later `pop`.
+    pub fn typed_queue<'a, T, N>(&'a self, name: N) -> BusResult<Queue<'a, T>>
+        where N: Into<String>
+    {
+        self.queue(name)
+    }
+
+    /// Constructs the queue `name` of `String` messages. See `typed_queue`.
+    pub fn string_queue<'a, N>(&'a self, name: N) -> BusResult<Queue<'a, String>>
+        where N: Into<String>
+    {
+        self.typed_queue(name)
+    }
+
+    /// Returns a builder for configuring the queue `name` before it is created. Every queue
+    /// option is set through the builder.
+    pub fn queue_builder<'a, N>(&'a self, name: N) -> QueueBuilder<'a>
+        where N: Into<String>
+    {
+        QueueBuilder::new(self, name)
+    }
+
+    /// Constructs the topic `name` on the bus, for publishing messages to many queues at once.
+    pub fn topic<'a, N>(&'a self, name: N) -> BusResult<Topic<'a>>
+        where N: Into<String>
+    {
+        Topic::new(self, name)
+    }
+
+    /// Pops a message from whichever of `queues` has one first, blocking for up to `timeout` if
+    /// none do, and returns it with the index of its queue. Returns `Ok(None)` once the timeout
+    /// has passed with nothing to pop, or straight away if there are no queues.
+    ///
+    /// The queues must be created from this bus, and listen on its connection, so one wait covers
+    /// them all; queues built with `QueueBuilder::reconnect_listener` are rejected. Queues are
+    /// checked in turn starting after the one that last had a message, so a busy queue can't
+    /// starve the others.
+    pub fn select_blocking<'a, B, E>(&self,
+                                     queues: &[&Queue<'a, B>],
+                                     timeout: Duration)
+                                     -> Result<Option<(usize, B)>, PopError<E>>
+        where B: FromMessageBody<E>,
+              E: fmt::Display
+    {
+        for queue in queues {
+            if !ptr::eq(queue.pqbus, self) || queue.has_listener() {
+                return Err(PopError::Generic(format!("Can't select on {}.{} as it doesn't \
+                                                      listen on bus {}",
+                                                     queue.bus,
+                                                     queue.name,
+                                                     self.name)));
+            }
+        }
+        let first = match queues.first() {
+            Some(queue) => queue,
+            None => return Ok(None),
+        };
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let start = self.next_select.get() % queues.len();
+            for i in (start..queues.len()).chain(0..start) {
+                if let Some(m) = queues[i].pop()? {
+                    self.next_select.set(i + 1);
+                    return Ok(Some((i, m)));
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            first.wait_for_bus_notification(deadline - now)?;
+        }
+    }
+
+    /// Deletes the queue `name` from the bus, dropping its table and any messages in it.
+    pub fn delete_queue<N>(&self, name: N) -> BusResult<()>
+        where N: Into<String>
+    {
+        let name = name.into();
+        if invalid_name(&name) {
+            return Err(BusError::InvalidQueueName(name));
+        }
+
+        let table_name = self.table_name(&name);
+        #[cfg(not(feature = "polling-only"))]
+        self.conn
+            .execute(&format!("UNLISTEN {}", channel_name(&table_name)), &[])
+            .map_err(BusError::Listen)?;
+        self.conn
+            .execute(&format!("DROP TABLE IF EXISTS {}, {} CASCADE",
+                              table_name,
+                              self.dlq_table_name(&name)),
+                     &[])
+            .map_err(BusError::Delete)?;
+        info!("Deleted queue {}.{}", self.name, name);
+
+        Ok(())
+    }
+
+    /// Returns the names of the queues on the bus.
+    ///
+    /// Queue tables record the bus and queue they belong to, so queues of a bus whose name
+    /// extends this one's with an underscore (e.g. `app` and `app_jobs`) are left out. Tables
+    /// created by earlier versions are recognised by their name alone until their queue is next
+    /// created, which can't tell such buses apart.
+    pub fn list_queues(&self) -> BusResult<Vec<String>> {
+        let mut prefix = table_name_generator(&self.name, "");
+        let prefix_len = prefix.len() - QUEUE_TABLE_SUFFIX.len();
+        prefix.truncate(prefix_len);
+        // Underscores match any character in LIKE patterns.
+        let pattern = format!("{}%{}", prefix, QUEUE_TABLE_SUFFIX).replace('_', "\\_");
+        let rows = self.conn.query(r#"
+                SELECT c.relname::text AS table, obj_description(c.oid, 'pg_class') AS comment
+                FROM   pg_class c
+                JOIN   pg_namespace n ON n.oid = c.relnamespace
+                WHERE  n.nspname = coalesce($2, current_schema())
+                AND    c.relkind IN ('r', 'p')
+                AND    c.relname LIKE $1
+                ORDER  BY c.relname
+                "#,
+                       &[&pattern, &self.schema])?;
+
+        Ok(rows.iter()
+            .filter_map(|row| {
+                let table: String = row.get("table");
+                let comment: Option<String> = row.get("comment");
+                let name = table[prefix.len()..table.len() - QUEUE_TABLE_SUFFIX.len()].to_string();
+                match comment {
+                    Some(ref c) if c.starts_with(QUEUE_COMMENT_PREFIX) &&
+                                   *c != queue_comment(&self.name, &name) => None,
+                    _ => Some(name),
+                }
+            })
+            .filter(|name| !invalid_name(name))
+            .collect())
+    }
+
+    /// Replaces the bus's connection with a new one, using the uri, SSL mode and retry settings
+    /// it was created with, and listening on the channels of its queues again.
+    ///
+    /// Queues do this themselves when an operation fails because the connection was lost outside
+    /// a transaction, and retry the operation once on the new connection. A push lost with the
+    /// connection after the server committed it is pushed again. Operations failing inside a
+    /// transaction return the error, and the next one reconnects. Queues keep working across a
+    /// reconnect, preparing their statements again on the new connection.
+    pub fn reconnect(&self) -> BusResult<()> {
+        let uri = match self.uri {
+            Some(ref uri) => uri,
+            None => {
+                return Err(BusError::Generic(format!("Bus {} was created from a connection and \
+                                                      cannot reconnect",
+                                                     self.name)))
+            }
+        };
+        let conn = open_connection(uri, self.ssl, self.retry_attempts, self.retry_delay_ms)?;
+        if let Some(ref application_name) = self.application_name {
+            set_application_name(&conn, application_name)?;
+        }
+        #[cfg(not(feature = "polling-only"))]
+        for (channel, count) in self.listening.borrow().iter() {
+            if *count > 0 {
+                conn.execute(&format!("LISTEN {}", channel), &[]).map_err(BusError::Listen)?;
+                hold_listener_lock(&conn, channel).map_err(BusError::Listen)?;
+            }
+        }
+        self.conn.replace(BusConnection::Owned(conn));
+        info!("Reconnected to bus {}", self.name);
+        Ok(())
+    }
+
+    /// Reconnects after `e`, an error an operation on `conn` failed with, if the connection was
+    /// lost outside a transaction, so the operation can be retried. Returns `e` otherwise, or if
+    /// reconnecting fails. Does nothing if `conn` has already been replaced.
+    fn recover(&self, conn: &Connection, e: postgres::error::Error) -> postgres::Result<()> {
+        // A connection is active while it has no transaction open.
+        if !connection_lost(&e) || !conn.is_active() {
+            return Err(e);
+        }
+        if !ptr::eq(conn, self.conn.current()) {
+            return Ok(());
+        }
+        warn!("Lost connection to bus {}, reconnecting: {}", self.name, e);
+        match self.reconnect() {
+            Ok(()) => Ok(()),
+            Err(reconnect) => {
+                error!("Unable to reconnect to bus {}: {}", self.name, reconnect);
+                Err(e)
+            }
+        }
+    }
+
+    /// Begins a transaction, or a savepoint in the caller's, reconnecting first if the
+    /// connection has been lost. See `recover`.
+    fn begin(&self) -> postgres::Result<QueueTransaction<'_>> {
+        let conn = self.conn.current();
+        match QueueTransaction::begin(conn) {
+            Err(e) => {
+                self.recover(conn, e)?;
+                QueueTransaction::begin(self.conn.current())
+            }
+            trans => trans,
+        }
+    }
+
+    /// Checks the connection to the database is alive with a trivial query. Returns an error
+    /// whose `is_connection_error` is true if the connection has been lost.
+    pub fn ping(&self) -> BusResult<()> {
+        // postgres refuses any further use of a connection that lost sync after an IO error.
+        if self.conn.is_desynchronized() {
+            let lost = io::Error::new(io::ErrorKind::NotConnected, "connection desynchronized");
+            return Err(BusError::Sql(postgres::error::Error::Io(lost)));
+        }
+        self.conn.batch_execute("SELECT 1").map_err(BusError::Sql)
+    }
+
+    /// Returns true if `ping` succeeds.
+    pub fn is_connected(&self) -> bool {
+        match self.ping() {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Bus {} is not connected: {}", self.name, e);
+                false
+            }
+        }
+    }
+
+    /// Returns the underlying database connection.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+const QUEUE_TABLE_SUFFIX: &str = "_queue";
+const DLQ_TABLE_SUFFIX: &str = "_dlq";
+
+/// Start of the comment on queue tables naming their bus and queue. See `queue_comment`.
+const QUEUE_COMMENT_PREFIX: &str = "pqbus queue ";
+
+/// Lock value used by consumers that were not given a consumer id.
+const DEFAULT_CONSUMER_ID: &str = "me";
+
+/// Headers pushed with a message. See `Queue::push_with_headers`.
+type Headers = HashMap<String, String>;
+
+/// Columns reading a row's headers as parallel arrays of keys and values, for `read_row`.
+const HEADERS: &str = "ARRAY(SELECT key FROM jsonb_each_text(headers) ORDER BY key) \
+                       AS header_keys, \
+                       ARRAY(SELECT coalesce(value, '') FROM jsonb_each_text(headers) \
+                       ORDER BY key) AS header_values";
+
+/// Columns of the queue table that `queue_ddl` doesn't add to tables missing them, as every
+/// version of it has had them.
+const QUEUE_COLUMNS: [&str; 3] = ["id", "message", "lock"];
+
+/// Key of the advisory lock every connection listening on a channel holds shared, given the
+/// channel as the first parameter. Postgres can't report the channels other sessions listen on,
+/// so this is how pushers find out. It's taken on LISTEN and released on UNLISTEN.
+#[cfg(not(feature = "polling-only"))]
+const LISTENER_LOCK: &str = "hashtext('pqbus_listener'), hashtext($1)";
+
+/// Matches `LISTENER_LOCK` in pg_locks, which shows its two halves as oids.
+#[cfg(not(feature = "polling-only"))]
+const LISTENER_LOCK_ROW: &str = "(classid, objid, objsubid) = \
+                                 (hashtext('pqbus_listener')::oid, hashtext($1)::oid, 2)";
+
+/// Where a message sorts in the queue: by its id, or once requeued by the value `requeue` drew.
+const POSITION: &str = "coalesce(requeued_seq, id)";
+
+/// Condition matching rows a consumer may pop right now.
+const AVAILABLE: &str = "lock IS NULL AND visible_at <= now() \
+                         AND (expires_at IS NULL OR expires_at > now())";
+
+/// Takes the listener lock of `channel` on `conn`, which listens on it.
+#[cfg(not(feature = "polling-only"))]
+fn hold_listener_lock(conn: &Connection, channel: &str) -> postgres::Result<()> {
+    conn.execute(&format!("SELECT pg_advisory_lock_shared({})", LISTENER_LOCK),
+                 &[&channel])?;
+    Ok(())
+}
+
+fn table_name_generator(bus: &str, queue: &str) -> String {
+    format!("pqbus_{}_{}{}", bus, queue, QUEUE_TABLE_SUFFIX)
+}
+
+/// Returns the channel notified of pushes to `table_name`. Channel names cannot be schema
+/// qualified, and LISTEN folds the unquoted name to lower case where pg_notify does not.
+#[cfg(any(not(feature = "polling-only"), feature = "async"))]
+fn channel_name(table_name: &str) -> String {
+    table_name.replace('.', "_").to_lowercase()
+}
+
+/// Parses the id of the pushed message from a push notification's payload. Notifications sent
+/// by `reclaim_expired`, or by older versions, have no id.
+#[cfg(not(feature = "polling-only"))]
+fn notification_id(n: &Notification) -> Option<i32> {
+    n.payload.parse().ok()
+}
+
+/// Converts `d` to fractional seconds for binding as an interval.
+fn duration_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+/// Reads the id column from the row returned by an `INSERT ... RETURNING id`.
+fn returned_id(rows: &Rows) -> postgres::Result<i32> {
+    let row = match rows.iter().next() {
+        None => return Err(postgres::error::Error::Conversion("Insert returned no rows".into())),
+        Some(row) => row,
+    };
+    match row.get_opt("id") {
+        None => Err(postgres::error::Error::Conversion("Insert returned no id column".into())),
+        Some(id) => id,
+    }
+}
+
+fn dlq_table_name_generator(bus: &str, queue: &str) -> String {
+    format!("pqbus_{}_{}{}", bus, queue, DLQ_TABLE_SUFFIX)
+}
+
+/// Statements creating the queue and dead-letter tables of the queue `queue` on bus `bus`, and
+/// bringing tables created by earlier versions up to date.
+fn queue_ddl(table_name: &str, dlq_table_name: &str, bus: &str, queue: &str) -> [String; 8] {
+    // Index names take the table's schema, so must not be qualified.
+    let index_prefix = table_name.rsplit('.').next().unwrap_or(table_name);
+    [format!(r#"
+            CREATE TABLE IF NOT EXISTS {} (
+                id SERIAL PRIMARY KEY,
+                message bytea NOT NULL,
+                lock VARCHAR DEFAULT NULL,
+                visible_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                delivery_attempts INT NOT NULL DEFAULT 0,
+                priority INT NOT NULL DEFAULT 0,
+                locked_at TIMESTAMPTZ DEFAULT NULL,
+                headers JSONB NOT NULL DEFAULT '{{}}',
+                body_hash BYTEA DEFAULT NULL,
+                dedup_until TIMESTAMPTZ DEFAULT NULL,
+                enqueued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                expires_at TIMESTAMPTZ DEFAULT NULL,
+                requeued_seq BIGINT DEFAULT NULL
+            )"#,
+             table_name),
+     format!(r#"
+            CREATE TABLE IF NOT EXISTS {} (
+                id SERIAL PRIMARY KEY,
+                message_id INT NOT NULL,
+                message bytea NOT NULL,
+                error TEXT NOT NULL,
+                failed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )"#,
+             dlq_table_name),
+     // Bodies are stored uncompressed, so `pop_to_writer` reads each chunk of a large one
+     // without the server reading the whole body first.
+     format!(r#"
+            ALTER TABLE {}
+            ADD COLUMN IF NOT EXISTS visible_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            ADD COLUMN IF NOT EXISTS delivery_attempts INT NOT NULL DEFAULT 0,
+            ADD COLUMN IF NOT EXISTS priority INT NOT NULL DEFAULT 0,
+            ADD COLUMN IF NOT EXISTS locked_at TIMESTAMPTZ DEFAULT NULL,
+            ADD COLUMN IF NOT EXISTS headers JSONB NOT NULL DEFAULT '{{}}',
+            ADD COLUMN IF NOT EXISTS body_hash BYTEA DEFAULT NULL,
+            ADD COLUMN IF NOT EXISTS dedup_until TIMESTAMPTZ DEFAULT NULL,
+            ADD COLUMN IF NOT EXISTS enqueued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ DEFAULT NULL,
+            ADD COLUMN IF NOT EXISTS requeued_seq BIGINT DEFAULT NULL,
+            ALTER COLUMN message SET STORAGE EXTERNAL
+            "#,
+             table_name),
+     format!("CREATE INDEX IF NOT EXISTS {}_headers_idx ON {} USING gin (headers)",
+             index_prefix,
+             table_name),
+     // Only unlocked rows can be popped, so pops scan these instead of skipping past every
+     // locked row. Visibility can't be part of the predicate as it depends on now().
+     // Rows pushed without deduplication have no hash, and nulls never conflict.
+     format!("CREATE UNIQUE INDEX IF NOT EXISTS {}_body_hash_idx ON {} (body_hash)",
+             index_prefix,
+             table_name),
+     format!("CREATE INDEX IF NOT EXISTS {}_pending_position_idx ON {} (({})) \
+              WHERE lock IS NULL",
+             index_prefix,
+             table_name,
+             POSITION),
+     format!("CREATE INDEX IF NOT EXISTS {}_pending_priority_position_idx \
+              ON {} (priority DESC, ({})) WHERE lock IS NULL",
+             index_prefix,
+             table_name,
+             POSITION),
+     // Table names can't tell bus `a`'s queue `b_c` from bus `a_b`'s queue `c`, so the table
+     // records whose it is for `PqBus::list_queues`. Names are valid, so need no quoting.
+     format!("COMMENT ON TABLE {} IS '{}'",
+             table_name,
+             queue_comment(bus, queue))]
+}
+
+/// Comment on the table of the queue `queue` on bus `bus`.
+fn queue_comment(bus: &str, queue: &str) -> String {
+    format!("{}{}.{}", QUEUE_COMMENT_PREFIX, bus, queue)
+}
+
+/// Creates, or brings up to date, the tables behind a queue. Returns true if the queue table did
+/// not exist before, or `BusError::IncompatibleSchema` if it exists but isn't a queue table.
+fn create_queue_tables(conn: &Connection,
+                       table_name: &str,
+                       dlq_table_name: &str,
+                       bus: &str,
+                       queue: &str)
+                       -> BusResult<bool> {
+    // Concurrent CREATE TABLE IF NOT EXISTS can still collide in the catalog, so consumers
+    // starting together take turns setting up the queue. Only the first finds it missing.
+    let trans = QueueTransaction::begin(conn).map_err(BusError::Create)?;
+    conn.execute("SELECT pg_advisory_xact_lock(hashtext($1))", &[&table_name])
+        .map_err(BusError::Create)?;
+    let missing: bool = conn.query("SELECT to_regclass($1::text) IS NULL AS missing",
+                                   &[&table_name])
+        .map_err(BusError::Create)?
+        .get(0)
+        .get("missing");
+
+    // The statements after the CREATE TABLEs update a queue table made by an earlier version,
+    // so an existing table is checked to be one first.
+    let ddl = queue_ddl(table_name, dlq_table_name, bus, queue);
+    for ddl in &ddl[..2] {
+        conn.execute(ddl, &[]).map_err(BusError::Create)?;
+    }
+    if !missing {
+        let absent = missing_columns(conn, table_name).map_err(BusError::Create)?;
+        if !absent.is_empty() {
+            return Err(BusError::IncompatibleSchema(table_name.to_string(), absent));
+        }
+    }
+    for ddl in &ddl[2..] {
+        conn.execute(ddl, &[]).map_err(BusError::Create)?;
+    }
+    trans.commit().map_err(BusError::Create)?;
+    Ok(missing)
+}
+
+/// Returns the columns every version of the queue table has had that `table_name` lacks. Read
+/// from pg_attribute rather than information_schema, so that the name resolves through the
+/// search path as it does in queries.
+fn missing_columns(conn: &Connection, table_name: &str) -> postgres::Result<Vec<String>> {
+    let columns: Vec<String> = QUEUE_COLUMNS.iter().map(|c| c.to_string()).collect();
+    let rows = conn.query(r#"
+            SELECT c AS column
+            FROM   unnest($2::text[]) AS c
+            WHERE  c NOT IN (SELECT attname::text
+                             FROM   pg_attribute
+                             WHERE  attrelid = to_regclass($1::text)
+                                    AND attnum > 0
+                                    AND NOT attisdropped)
+            "#,
+                          &[&table_name, &columns])?;
+    Ok(rows.iter().map(|row| row.get("column")).collect())
+}
+
+/// A queue as built from its options: its names, its settings and the SQL of its statements.
+/// Formatted once, so `SharedQueue` can build the queue again cheaply on each connection it
+/// checks out.
+struct QueueDef {
+    name: String,
+    bus: String,
+    table_name: String,
+    dlq_table_name: String,
+    #[cfg(not(feature = "polling-only"))]
+    channel: String,
+    #[cfg(not(feature = "polling-only"))]
+    reconnect_listener: bool,
+    lazy_statements: bool,
+    max_attempts: Option<u32>,
+    max_depth: Option<u32>,
+    dedup: bool,
+    ttl: Option<Duration>,
+    metrics: Arc<dyn Metrics>,
+    consumer_id: String,
+    #[cfg(feature = "compression")]
+    compression: Option<Compression>,
+    sql: QueueSql,
+}
+
+/// The SQL of a queue's statements, shared by the queues built from its `QueueDef`.
+struct QueueSql {
+    push: Arc<str>,
+    push_delayed: Arc<str>,
+    push_priority: Arc<str>,
+    push_expiring: Arc<str>,
+    push_headers: Arc<str>,
+    duplicate: Arc<str>,
+    release_hash: Arc<str>,
+    #[cfg(not(feature = "polling-only"))]
+    notify: Arc<str>,
+    #[cfg(not(feature = "polling-only"))]
+    listeners: Arc<str>,
+    size: Arc<str>,
+    approx_size: Arc<str>,
+    empty: Arc<str>,
+    has_pending: Arc<str>,
+    pending: Arc<str>,
+    oldest_pending: Arc<str>,
+    stats: Arc<str>,
+    purge: Arc<str>,
+    delete_where: Arc<str>,
+    expire: Arc<str>,
+    reclaim: Arc<str>,
+    locks: Arc<str>,
+    dead_letter: Arc<str>,
+    dead_letters: Arc<str>,
+    pop: Arc<str>,
+    pop_filtered: Arc<str>,
+    claim: Arc<str>,
+    peek: Arc<str>,
+    delete: Arc<str>,
+    nack: Arc<str>,
+    extend_lock: Arc<str>,
+    requeue: Arc<str>,
+    claim_batch: Arc<str>,
+    pop_batch: Arc<str>,
+    ack_batch: Arc<str>,
+    nack_batch: Arc<str>,
+    pop_delete: Arc<str>,
+    push_copy: Arc<str>,
+    lock_stream: Arc<str>,
+    read_chunk: Arc<str>,
+    take: Arc<str>,
+}
+
+impl QueueDef {
+    /// Checks the names in `options` and formats the SQL of the queue `name` on `pqbus`.
+    fn new(pqbus: &PqBus, name: &str, options: QueueOptions) -> BusResult<Self> {
+        let bus = &pqbus.name;
+
+        if invalid_name(name) {
+            return Err(BusError::InvalidQueueName(name.to_string()));
+        }
+
+        let consumer_id = options.consumer_id.unwrap_or_else(|| DEFAULT_CONSUMER_ID.to_string());
+        if invalid_name(&consumer_id) {
+            return Err(BusError::InvalidConsumerId(consumer_id));
+        }
+
+        let (table_name, dlq_table_name) = match options.table_name {
+            Some(table_name) => {
+                if invalid_table_name(&table_name) {
+                    return Err(BusError::InvalidTableName(table_name));
+                }
+                let dlq_table_name = format!("{}{}", table_name, DLQ_TABLE_SUFFIX);
+                (table_name, dlq_table_name)
+            }
+            None => (pqbus.table_name(name), pqbus.dlq_table_name(name)),
+        };
+
+        if let Some(ref channel) = options.notify_channel {
+            if invalid_name(channel) {
+                return Err(BusError::InvalidChannelName(channel.clone()));
+            }
+        }
+        #[cfg(not(feature = "polling-only"))]
+        let channel = match options.notify_channel {
+            Some(channel) => channel.to_lowercase(),
+            None => channel_name(&table_name),
+        };
+        // Pushes to a queue with a maximum depth insert nothing once it is full. Concurrent pushes
+        // each count before the others commit, so may overshoot by up to one per pusher.
+        let room = match options.max_depth {
+            Some(max) => format!("WHERE (SELECT count(*) FROM {}) < {}", table_name, max),
+            None => String::new(),
+        };
+
+        // Deduplicating queues store a hash of each body, skipping pushes that conflict with a
+        // queued message. The hash is released after the window, if there is one.
+        let (hash, conflict) = if options.dedup {
+            ("sha256($1::bytea)", "ON CONFLICT (body_hash) DO NOTHING")
+        } else {
+            ("NULL", "")
+        };
+        let dedup_until = match options.dedup_window {
+            Some(window) => format!("now() + make_interval(secs => {})", duration_secs(window)),
+            None => "NULL".to_string(),
+        };
+        let dedup = format!("{}, {}", hash, dedup_until);
+
+        // Messages older than the TTL are never delivered, and are left for `expire` to delete.
+        let ttl = options.ttl.filter(|ttl| *ttl > Duration::from_secs(0));
+        let available = match ttl {
+            Some(ttl) => {
+                format!("{} AND enqueued_at > now() - make_interval(secs => {})",
+                        AVAILABLE,
+                        duration_secs(ttl))
+            }
+            None => AVAILABLE.to_string(),
+        };
+
+        let (id_order, back) = match options.ordering {
+            Ordering::Fifo => (POSITION.to_string(), ""),
+            Ordering::Lifo => (format!("{} DESC", POSITION), "-"),
+        };
+        let order = if options.priority {
+            format!("priority DESC, {}", id_order)
+        } else {
+            id_order
+        };
+
+        let stats = format!(r#"
+                    SELECT count(*) FILTER (WHERE {a}) AS pending,
+                           count(*) FILTER (WHERE lock IS NOT NULL) AS in_flight,
+                           count(*) FILTER (WHERE lock IS NULL AND visible_at > now()) AS delayed,
+                           count(*) AS total
+                    FROM {n}
+                    "#,
+                                n = table_name,
+                                a = available);
+
+        Ok(QueueDef {
+            name: name.to_string(),
+            bus: bus.clone(),
+            #[cfg(not(feature = "polling-only"))]
+            reconnect_listener: options.reconnect_listener,
+            lazy_statements: options.lazy_statements,
+            max_attempts: options.max_attempts,
+            max_depth: options.max_depth,
+            dedup: options.dedup,
+            ttl,
+            metrics: options.metrics.unwrap_or_else(|| Arc::new(NoMetrics)),
+            consumer_id,
+            #[cfg(feature = "compression")]
+            compression: options.compression,
+            sql: QueueSql {
+                push: format!(r#"
+                            INSERT INTO {n} (message, body_hash, dedup_until)
+                            SELECT $1::bytea, {d} {r} {c}
+                            RETURNING id
+                            "#,
+                                n = table_name,
+                                d = dedup,
+                                r = room,
+                                c = conflict).into(),
+                push_delayed: format!(r#"
+                            INSERT INTO {n} (message, visible_at, body_hash, dedup_until)
+                            SELECT $1::bytea, now() + make_interval(secs => $2), {d} {r} {c}
+                            RETURNING id
+                            "#,
+                                n = table_name,
+                                d = dedup,
+                                r = room,
+                                c = conflict).into(),
+                push_priority: format!(r#"
+                            INSERT INTO {n} (message, priority, body_hash, dedup_until)
+                            SELECT $1::bytea, $2::int, {d} {r} {c}
+                            RETURNING id
+                            "#,
+                                n = table_name,
+                                d = dedup,
+                                r = room,
+                                c = conflict).into(),
+                push_expiring: format!(r#"
+                            INSERT INTO {n} (message, expires_at, body_hash, dedup_until)
+                            SELECT $1::bytea, to_timestamp($2), {d} {r} {c}
+                            RETURNING id
+                            "#,
+                                n = table_name,
+                                d = dedup,
+                                r = room,
+                                c = conflict).into(),
+                push_headers: format!(r#"
+                            INSERT INTO {n} (message, headers, body_hash, dedup_until)
+                            SELECT $1::bytea, jsonb_object($2::text[], $3::text[]), {d} {r} {c}
+                            RETURNING id
+                            "#,
+                                n = table_name,
+                                d = dedup,
+                                r = room,
+                                c = conflict).into(),
+                duplicate: format!("SELECT id FROM {} \
+                                                 WHERE body_hash = sha256($1::bytea)",
+                                                table_name).into(),
+                release_hash: format!("UPDATE {} SET body_hash = NULL \
+                                                    WHERE body_hash = sha256($1::bytea) \
+                                                    AND dedup_until <= now()",
+                                                   table_name).into(),
+                #[cfg(not(feature = "polling-only"))]
+                notify: format!("SELECT pg_notify('{}', $1)", channel).into(),
+                #[cfg(not(feature = "polling-only"))]
+                listeners: format!(r#"
+                            SELECT EXISTS (
+                               SELECT 1
+                               FROM   pg_locks
+                               WHERE  locktype = 'advisory'
+                                      AND {k}
+                                      AND database = (SELECT oid
+                                                      FROM   pg_database
+                                                      WHERE  datname = current_database())
+                                      AND granted
+                                      AND pid <> pg_backend_pid()
+                               ) AS listening
+                            "#,
+                                   k = LISTENER_LOCK_ROW).into(),
+                size: format!("SELECT count(*) FROM  {}", table_name).into(),
+                // Tables that have never been analyzed estimate -1 rows.
+                approx_size: format!("SELECT greatest(reltuples, 0)::int8 \
+                                                   AS estimate FROM pg_class \
+                                                   WHERE oid = '{}'::regclass",
+                                                  table_name).into(),
+                empty: format!("SELECT NOT EXISTS (SELECT 1 FROM {}) \
+                                             AS empty",
+                                            table_name).into(),
+                has_pending: format!("SELECT EXISTS (SELECT 1 FROM {} \
+                                                   WHERE {}) AS pending",
+                                                  table_name,
+                                                  available).into(),
+                pending: format!("SELECT count(*) FROM {} WHERE {}",
+                                              table_name,
+                                              available).into(),
+                oldest_pending: format!(r#"
+                        SELECT extract(epoch FROM now() - min(enqueued_at))::float8 AS age
+                        FROM   {n}
+                        WHERE  {a}
+                        "#,
+                                n = table_name,
+                                a = available).into(),
+                purge: format!("DELETE FROM {}", table_name).into(),
+                delete_where: format!(r#"
+                            DELETE FROM {n}
+                            WHERE  ($1::float8 IS NULL
+                                    OR enqueued_at < now() - make_interval(secs => $1))
+                            AND    headers @> jsonb_object($2::text[], $3::text[])
+                            "#,
+                                n = table_name).into(),
+                expire: format!(r#"
+                            DELETE FROM {n}
+                            WHERE  lock IS NULL
+                            AND    (expires_at <= now()
+                                    OR enqueued_at <= now() - make_interval(secs => $1))
+                            "#,
+                                n = table_name).into(),
+                // Returns the lock each message was released from, e.g. a `claim_batch` batch id.
+                reclaim: format!(r#"
+                            WITH stale AS (
+                               SELECT id, lock
+                               FROM   {n}
+                               WHERE  lock IS NOT NULL
+                               AND    locked_at < now() - make_interval(secs => $1)
+                               FOR UPDATE SKIP LOCKED
+                               )
+                            UPDATE {n} q
+                            SET    lock = NULL, locked_at = NULL
+                            FROM   stale
+                            WHERE  q.id = stale.id
+                            RETURNING stale.lock
+                            "#,
+                                n = table_name).into(),
+                locks: format!("SELECT lock, count(*) FROM {} WHERE lock IS NOT NULL \
+                                GROUP BY lock",
+                               table_name).into(),
+                dead_letter: format!(r#"
+                            WITH moved AS (
+                               DELETE FROM {n}
+                               WHERE id = $1
+                               RETURNING id, message
+                               )
+                            INSERT INTO {d} (message_id, message, error)
+                            SELECT id, message, $2
+                            FROM   moved
+                            "#,
+                                n = table_name,
+                                d = dlq_table_name).into(),
+                dead_letters: format!("SELECT message_id, message, error \
+                                                    FROM {} ORDER BY id",
+                                                   dlq_table_name).into(),
+                pop: format!(r#"
+                            SELECT id, message, {h}
+                            FROM   {n}
+                            WHERE  {a}
+                            ORDER  BY {o}
+                            LIMIT  1
+                            FOR UPDATE SKIP LOCKED
+                            "#,
+                                n = table_name,
+                                a = available,
+                                o = order,
+                                h = HEADERS).into(),
+                pop_filtered: format!(r#"
+                            SELECT id, message, {h}
+                            FROM   {n}
+                            WHERE  {a}
+                            AND    headers @> jsonb_object($1::text[], $2::text[])
+                            ORDER  BY {o}
+                            LIMIT  1
+                            FOR UPDATE SKIP LOCKED
+                            "#,
+                                n = table_name,
+                                a = available,
+                                o = order,
+                                h = HEADERS).into(),
+                // Claims the row locked by the subquery in the same statement, so concurrent
+                // consumers skip it rather than racing to update it.
+                claim: format!(r#"
+                            UPDATE {n}
+                            SET    lock = $1,
+                                   locked_at = now(),
+                                   delivery_attempts = delivery_attempts + 1
+                            WHERE  id = (
+                               SELECT id
+                               FROM   {n}
+                               WHERE  {a}
+                               ORDER  BY {o}
+                               LIMIT  1
+                               FOR UPDATE SKIP LOCKED
+                               )
+                            RETURNING id, message, delivery_attempts, {h};
+                            "#,
+                                n = table_name,
+                                a = available,
+                                o = order,
+                                h = HEADERS).into(),
+                peek: format!(r#"
+                            SELECT id, message, {h}
+                            FROM   {n}
+                            WHERE  {a}
+                            ORDER  BY {o}
+                            LIMIT  1
+                            "#,
+                                n = table_name,
+                                a = available,
+                                o = order,
+                                h = HEADERS).into(),
+                delete: format!("DELETE FROM {} WHERE id = $1", table_name).into(),
+                nack: format!("UPDATE {} SET lock = NULL, locked_at = NULL \
+                                            WHERE id = $1",
+                                           table_name).into(),
+                extend_lock: format!(r#"
+                            UPDATE {n}
+                            SET    locked_at = locked_at + make_interval(secs => $2)
+                            WHERE  id = $1
+                            AND    lock IS NOT NULL
+                            "#,
+                                n = table_name).into(),
+                // A value drawn from the id sequence sorts the message after those already queued,
+                // negated for LIFO queues, which pop the highest first.
+                requeue: format!(r#"
+                            UPDATE {n}
+                            SET    requeued_seq = {b}nextval(pg_get_serial_sequence('{n}', 'id')),
+                                   lock = NULL,
+                                   locked_at = NULL,
+                                   visible_at = now() + make_interval(secs => $2)
+                            WHERE  id = $1
+                            "#,
+                                n = table_name,
+                                b = back).into(),
+                claim_batch: format!(r#"
+                            WITH claimed AS (
+                               UPDATE {n}
+                               SET    lock = $1,
+                                      locked_at = now()
+                               WHERE id IN (
+                                  SELECT id
+                                  FROM   {n}
+                                  WHERE  {a}
+                                  ORDER  BY {o}
+                                  LIMIT  $2
+                                  FOR UPDATE SKIP LOCKED
+                                  )
+                               RETURNING id, message, priority, headers, requeued_seq
+                               )
+                            SELECT id, message, {h}
+                            FROM   claimed
+                            ORDER  BY {o};
+                            "#,
+                                n = table_name,
+                                a = available,
+                                o = order,
+                                h = HEADERS).into(),
+                pop_batch: format!(r#"
+                            WITH popped AS (
+                               DELETE FROM {n}
+                               WHERE id IN (
+                                  SELECT id
+                                  FROM   {n}
+                                  WHERE  {a}
+                                  ORDER  BY {o}
+                                  LIMIT  $1
+                                  FOR UPDATE SKIP LOCKED
+                                  )
+                               RETURNING id, message, priority, headers, requeued_seq
+                               )
+                            SELECT id, message, {h}
+                            FROM   popped
+                            ORDER  BY {o};
+                            "#,
+                                n = table_name,
+                                a = available,
+                                o = order,
+                                h = HEADERS).into(),
+                ack_batch: format!("DELETE FROM {} WHERE lock = $1", table_name).into(),
+                nack_batch: format!("UPDATE {} SET lock = NULL, locked_at = NULL \
+                                     WHERE lock = $1",
+                                    table_name).into(),
+                pop_delete: format!(r#"
+                            WITH popped AS (
+                               DELETE FROM {n}
+                               WHERE id = (
+                                  SELECT id
+                                  FROM   {n}
+                                  WHERE  {a}
+                                  ORDER  BY {o}
+                                  LIMIT  1
+                                  FOR UPDATE SKIP LOCKED
+                                  )
+                               RETURNING id, message, headers
+                               )
+                            SELECT id, message, {h}
+                            FROM   popped;
+                            "#,
+                                n = table_name,
+                                a = available,
+                                o = order,
+                                h = HEADERS).into(),
+                push_copy: format!("COPY {} (message) FROM STDIN", table_name).into(),
+                lock_stream: format!(r#"
+                            SELECT id, octet_length(message) AS length
+                            FROM   {n}
+                            WHERE  {a}
+                            ORDER  BY {o}
+                            LIMIT  1
+                            FOR UPDATE SKIP LOCKED
+                            "#,
+                                n = table_name,
+                                a = available,
+                                o = order).into(),
+                read_chunk: format!("SELECT substring(message FROM $2 FOR $3) AS chunk \
+                                     FROM {} WHERE id = $1",
+                                    table_name).into(),
+                // `move_to` completes this with the other queue's table, so it can't be prepared yet.
+                stats: stats.into(),
+                take: format!(r#"
+                            DELETE FROM {n}
+                            WHERE id IN (
+                               SELECT id
+                               FROM   {n}
+                               WHERE  {a}
+                               ORDER  BY {o}
+                               LIMIT  $1
+                               FOR UPDATE SKIP LOCKED
+                               )
+                            RETURNING id, message, priority, headers, enqueued_at, requeued_seq
+                            "#,
+                              n = table_name,
+                              a = available,
+                              o = order).into(),
+            },
+            #[cfg(not(feature = "polling-only"))]
+            channel,
+            table_name,
+            dlq_table_name,
+        })
+    }
+}
+
+/// A push pop message queue.
+impl<'a, B> Queue<'a, B> {
+    fn new(pqbus: &'a PqBus, name: &str, options: QueueOptions) -> BusResult<Self> {
+        let assume_table_exists = options.assume_table_exists;
+        let def = QueueDef::new(pqbus, name, options)?;
+
+        info!("Creating queue {}.{}", def.bus, def.name);
+
+        let created = if assume_table_exists {
+            false
+        } else {
+            create_queue_tables(pqbus.conn.current(),
+                                &def.table_name,
+                                &def.dlq_table_name,
+                                &def.bus,
+                                &def.name)?
+        };
+        if created {
+            info!("Created table {} for queue {}.{}", def.table_name, def.bus, def.name);
+        }
+
+        Queue::from_def(pqbus, &def, true, created)
+    }
+
+    /// Builds the queue `def` on `pqbus`. Unless it has a listener of its own, the queue listens
+    /// for pushes on the bus's connection if `listen`.
+    fn from_def(pqbus: &'a PqBus,
+                def: &QueueDef,
+                listen: bool,
+                created: bool)
+                -> BusResult<Self> {
+        let conn: &'a Connection = pqbus.conn.current();
+        #[cfg(feature = "polling-only")]
+        let _ = (conn, listen);
+
+        #[cfg(not(feature = "polling-only"))]
+        let listener = if def.reconnect_listener {
+            let uri = match pqbus.uri {
+                Some(ref uri) => uri.clone(),
+                None => {
+                    return Err(BusError::Generic(format!("Bus {} was created from a connection \
+                                                          and cannot open a listener connection",
+                                                         def.bus)))
+                }
+            };
+            Some(Listener::connect(uri,
+                                   pqbus.ssl,
+                                   def.channel.clone(),
+                                   pqbus.application_name.clone(),
+                                   pqbus.retry_attempts,
+                                   pqbus.retry_delay_ms)?)
+        } else {
+            None
+        };
+        #[cfg(not(feature = "polling-only"))]
+        let listens = listener.is_none() && listen;
+        #[cfg(not(feature = "polling-only"))]
+        if listens {
+            conn.execute(&format!("LISTEN {}", def.channel), &[]).map_err(BusError::Listen)?;
+        }
+
+        #[cfg(not(feature = "polling-only"))]
+        {
+            if listens {
+                let mut listening = pqbus.listening.borrow_mut();
+                let count = listening.entry(def.channel.clone()).or_insert(0);
+                if *count == 0 {
+                    hold_listener_lock(conn, &def.channel).map_err(BusError::Listen)?;
+                }
+                *count += 1;
+            }
+        }
+
+        let prepare = |sql: &Arc<str>| QueueStatement::new(pqbus, sql.clone(), def.lazy_statements);
+
+        Ok(Queue {
+            pqbus,
+            #[cfg(not(feature = "polling-only"))]
+            channel: def.channel.clone(),
+            #[cfg(not(feature = "polling-only"))]
+            listener,
+            #[cfg(not(feature = "polling-only"))]
+            listens,
+            push_stmt: prepare(&def.sql.push)?,
+            push_delayed_stmt: prepare(&def.sql.push_delayed)?,
+            push_priority_stmt: prepare(&def.sql.push_priority)?,
+            push_expiring_stmt: prepare(&def.sql.push_expiring)?,
+            push_headers_stmt: prepare(&def.sql.push_headers)?,
+            duplicate_stmt: prepare(&def.sql.duplicate)?,
+            release_hash_stmt: prepare(&def.sql.release_hash)?,
+            #[cfg(not(feature = "polling-only"))]
+            notify_stmt: prepare(&def.sql.notify)?,
+            #[cfg(not(feature = "polling-only"))]
+            listeners_stmt: prepare(&def.sql.listeners)?,
+            size_stmt: prepare(&def.sql.size)?,
+            approx_size_stmt: prepare(&def.sql.approx_size)?,
+            empty_stmt: prepare(&def.sql.empty)?,
+            has_pending_stmt: prepare(&def.sql.has_pending)?,
+            pending_stmt: prepare(&def.sql.pending)?,
+            oldest_pending_stmt: prepare(&def.sql.oldest_pending)?,
+            stats_stmt: prepare(&def.sql.stats)?,
+            purge_stmt: prepare(&def.sql.purge)?,
+            delete_where_stmt: prepare(&def.sql.delete_where)?,
+            expire_stmt: prepare(&def.sql.expire)?,
+            reclaim_stmt: prepare(&def.sql.reclaim)?,
+            locks_stmt: prepare(&def.sql.locks)?,
+            dead_letter_stmt: prepare(&def.sql.dead_letter)?,
+            dead_letters_stmt: prepare(&def.sql.dead_letters)?,
+            pop_stmt: prepare(&def.sql.pop)?,
+            pop_filtered_stmt: prepare(&def.sql.pop_filtered)?,
+            claim_stmt: prepare(&def.sql.claim)?,
+            peek_stmt: prepare(&def.sql.peek)?,
+            delete_stmt: prepare(&def.sql.delete)?,
+            nack_stmt: prepare(&def.sql.nack)?,
+            extend_lock_stmt: prepare(&def.sql.extend_lock)?,
+            requeue_stmt: prepare(&def.sql.requeue)?,
+            claim_batch_stmt: prepare(&def.sql.claim_batch)?,
+            pop_batch_stmt: prepare(&def.sql.pop_batch)?,
+            ack_batch_stmt: prepare(&def.sql.ack_batch)?,
+            nack_batch_stmt: prepare(&def.sql.nack_batch)?,
+            pop_delete_stmt: prepare(&def.sql.pop_delete)?,
+            push_copy_stmt: prepare(&def.sql.push_copy)?,
+            lock_stream_stmt: prepare(&def.sql.lock_stream)?,
+            read_chunk_stmt: prepare(&def.sql.read_chunk)?,
+            take_sql: def.sql.take.clone(),
+            table_name: def.table_name.clone(),
+            name: def.name.clone(),
+            bus: def.bus.clone(),
+            #[cfg(feature = "polling-only")]
+            poll_interval: Duration::from_millis(DEFAULT_POLL_INTERVAL_MS),
+            cancel_check_interval: Duration::from_millis(DEFAULT_CANCEL_CHECK_INTERVAL_MS),
+            max_attempts: def.max_attempts,
+            max_depth: def.max_depth,
+            dedup: def.dedup,
+            ttl: def.ttl,
+            created,
+            metrics: Sinks::new(def.metrics.clone()),
+            #[cfg(feature = "prometheus")]
+            stats_sql: def.sql.stats.clone(),
+            consumer_id: def.consumer_id.clone(),
+            #[cfg(feature = "compression")]
+            compression: def.compression,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Sets how long blocking pops sleep between polls for pending messages.
+    #[cfg(feature = "polling-only")]
+    pub fn set_poll_interval(&mut self, interval: Duration) {
+        self.poll_interval = interval;
+    }
+
+    /// Sets the longest time cancellable blocking pops wait between checks of their cancel flag,
+    /// bounding how long they take to return once it is set.
+    pub fn set_cancel_check_interval(&mut self, interval: Duration) {
+        self.cancel_check_interval = interval;
+    }
+
+    /// Returns true if constructing the queue created its table, e.g. to seed a new queue only
+    /// once. Of several consumers creating the same queue at once, only one sees true.
+    pub fn was_created(&self) -> bool {
+        self.created
+    }
+
+    /// Returns the number of messages in the queue.
+    pub fn size(&self) -> BusResult<i64> {
+        self.size_stmt.query(&[], |rows| rows.get(0).get("count")).map_err(BusError::Size)
+    }
+
+    /// Returns an estimate of the number of messages in the queue, from the statistics postgres
+    /// keeps for the table. Unlike `size` it doesn't scan the table, so it stays fast on large
+    /// queues, but it is only updated by VACUUM and ANALYZE, and is zero until the first.
+    pub fn approx_size(&self) -> BusResult<i64> {
+        self.approx_size_stmt.query(&[], |rows| rows.get(0).get("estimate")).map_err(BusError::Size)
+    }
+
+    /// Determines if the queue holds no messages at all, including ones claimed by a consumer or
+    /// delayed. Use `has_pending` to check for messages a pop would return. Stops at the first
+    /// message found, so is cheaper than `size` on large queues.
+    pub fn is_empty(&self) -> BusResult<bool> {
+        self.empty_stmt.query(&[], |rows| rows.get(0).get("empty")).map_err(BusError::Size)
+    }
+
+    /// Determines if there are any messages a pop would return right now, unlike `is_empty`
+    /// leaving out messages claimed by a consumer, delayed or expired. Stops at the first message
+    /// found, so is cheaper than `pending` on large queues.
+    pub fn has_pending(&self) -> BusResult<bool> {
+        self.has_pending_stmt.query(&[], |rows| rows.get(0).get("pending")).map_err(BusError::Size)
+    }
+
+    /// Returns the number of messages waiting to be popped. Delayed messages are not counted
+    /// until they become visible.
+    pub fn pending(&self) -> BusResult<i64> {
+        self.pending_stmt.query(&[], |rows| rows.get(0).get("count")).map_err(BusError::Size)
+    }
+
+    /// Returns how long the oldest pending message has been in the queue, or `None` if there are
+    /// no pending messages. Delayed messages are counted from when they were pushed once they
+    /// become visible.
+    pub fn oldest_pending_age(&self) -> BusResult<Option<Duration>> {
+        let age: Option<f64> = self.oldest_pending_stmt
+            .query(&[], |rows| rows.get(0).get("age"))
+            .map_err(BusError::Size)?;
+        // Clocks of the database and its clients may disagree, so the age can come out negative.
+        Ok(age.map(|secs| Duration::from_secs_f64(secs.max(0.0))))
+    }
+
+    /// Returns the number of messages in the queue that are pending, in flight and delayed.
+    pub fn stats(&self) -> BusResult<QueueStats> {
+        self.stats_stmt
+            .query(&[], |rows| {
+                let row = rows.get(0);
+                QueueStats {
+                    pending: row.get("pending"),
+                    in_flight: row.get("in_flight"),
+                    delayed: row.get("delayed"),
+                    total: row.get("total"),
+                }
+            })
+            .map_err(BusError::Size)
+    }
+
+    /// Exports the queue's depth by state, and counts of its pushes, pops, dead letters and
+    /// errors from now on, to `registry`, labelled with the bus and queue names. The depth is read
+    /// from the database whenever the registry is gathered, on a connection of its own, so the bus
+    /// must have been created from a uri. The connection uses the bus's SSL mode.
+    #[cfg(feature = "prometheus")]
+    pub fn register_metrics(&self, registry: &prometheus::Registry) -> BusResult<()> {
+        let uri = match self.pqbus.uri {
+            Some(ref uri) => uri,
+            None => {
+                return Err(BusError::Generic(format!("Bus {} was created from a connection and \
+                                                      cannot open a metrics connection",
+                                                     self.bus)))
+            }
+        };
+        let conn = open_connection(uri,
+                                   self.pqbus.ssl,
+                                   DEFAULT_RETRY_ATTEMPTS,
+                                   DEFAULT_RETRY_DELAY_MS)?;
+        let collector = exporter::QueueCollector::new(conn,
+                                                      self.stats_sql.to_string(),
+                                                      &self.bus,
+                                                      &self.name)?;
+        let counters = collector.counters();
+        registry.register(Box::new(collector))?;
+        self.metrics.add(counters);
+        Ok(())
+    }
+
+    /// Removes every message from the queue, returning the number removed.
+    pub fn purge(&self) -> BusResult<u64> {
+        let count = self.purge_stmt.execute(&[]).map_err(BusError::Purge)?;
+        info!("Purged {} messages from queue {}.{}", count, self.bus, self.name);
+        Ok(count)
+    }
+
+    /// Removes every message from the queue as `purge` does, then restarts message ids from 1.
+    ///
+    /// Unsafe while consumers hold ids of messages from before the purge, e.g. of unacknowledged
+    /// deliveries, as those ids will be given to new messages. Truncating the table blocks every
+    /// other use of the queue until it is done.
+    pub fn purge_and_reset(&self) -> BusResult<u64> {
+        let trans = self.pqbus.begin().map_err(BusError::Purge)?;
+        self.conn()
+            .execute(&format!("LOCK TABLE {} IN ACCESS EXCLUSIVE MODE", self.table_name), &[])
+            .map_err(BusError::Purge)?;
+        let count: i64 = self.conn()
+            .query(&format!("SELECT count(*) FROM {}", self.table_name), &[])
+            .map_err(BusError::Purge)?
+            .get(0)
+            .get(0);
+        self.conn()
+            .execute(&format!("TRUNCATE {} RESTART IDENTITY", self.table_name), &[])
+            .map_err(BusError::Purge)?;
+        trans.commit().map_err(BusError::Purge)?;
+        info!("Purged {} messages from queue {}.{} and reset its ids",
+              count,
+              self.bus,
+              self.name);
+        Ok(count as u64)
+    }
+
+    /// Vacuums and analyzes the queue table, reclaiming the space of popped and deleted messages
+    /// and refreshing the statistics pops are planned with, e.g. from a periodic janitor on busy
+    /// queues that autovacuum falls behind on. It doesn't block pushes or pops.
+    ///
+    /// Requires owning the table, being a superuser or the database owner, or on postgres 17 the
+    /// MAINTAIN privilege on it. Fails if called inside a transaction.
+    pub fn maintain(&self) -> BusResult<()> {
+        let started = Instant::now();
+        self.conn()
+            .batch_execute(&format!("VACUUM (ANALYZE) {}", self.table_name))
+            .map_err(BusError::Sql)?;
+        info!("Vacuumed queue {}.{} in {:?}",
+              self.bus,
+              self.name,
+              started.elapsed());
+        Ok(())
+    }
+
+    /// Removes the messages matching `filter`, including any claimed by a consumer, returning
+    /// the number removed.
+    pub fn delete_where(&self, filter: &DeleteFilter) -> BusResult<u64> {
+        let age = filter.age().map(duration_secs);
+        let keys: Vec<&str> = filter.headers().iter().map(|(k, _)| k.as_str()).collect();
+        let values: Vec<&str> = filter.headers().iter().map(|(_, v)| v.as_str()).collect();
+        let count =
+            self.delete_where_stmt.execute(&[&age, &keys, &values]).map_err(BusError::Purge)?;
+        info!("Deleted {} messages matching {:?} from queue {}.{}",
+              count,
+              filter,
+              self.bus,
+              self.name);
+        Ok(count)
+    }
+
+    /// Deletes the pending messages that have outlived the queue's TTL, or passed the expiry they
+    /// were pushed with by `push_expiring`, returning the number deleted. Expired messages are
+    /// never delivered, but stay in the queue until this is called.
+    pub fn expire(&self) -> BusResult<u64> {
+        let ttl = self.ttl.map(duration_secs);
+        let count = self.expire_stmt.execute(&[&ttl]).map_err(BusError::Purge)?;
+        if count > 0 {
+            info!("Expired {} messages from queue {}.{}", count, self.bus, self.name);
+        }
+        Ok(count)
+    }
+
+    /// Releases messages claimed by `pop_delivery` or `claim_batch` that have been locked for
+    /// longer than `timeout`, returning the number released. Consumers that crash without
+    /// acknowledging leave their messages locked, so call this periodically to make them
+    /// available again.
+    pub fn reclaim_expired(&self, timeout: Duration) -> BusResult<u64> {
+        Ok(self.reclaim(timeout)?.len() as u64)
+    }
+
+    /// Releases messages as `reclaim_expired` does, returning the locks they were released from:
+    /// the consumer ids of `pop_delivery` consumers, and the batch ids of `claim_batch` calls, each
+    /// listed once. Use it to find which consumers or batches were abandoned.
+    pub fn reclaim_stale(&self, timeout: Duration) -> BusResult<Vec<String>> {
+        let mut locks = self.reclaim(timeout)?;
+        locks.sort();
+        locks.dedup();
+        Ok(locks)
+    }
+
+    /// Releases messages locked for longer than `timeout`, returning the lock of each one.
+    fn reclaim(&self, timeout: Duration) -> BusResult<Vec<String>> {
+        let locks: Vec<String> = self.reclaim_stmt
+            .query(&[&duration_secs(timeout)],
+                   |rows| rows.iter().map(|row| row.get("lock")).collect())
+            .map_err(BusError::Reclaim)?;
+        if !locks.is_empty() {
+            info!("Reclaimed {} expired messages in {}.{}",
+                  locks.len(),
+                  self.bus,
+                  self.name);
+            self.notify_push(None).map_err(BusError::Notify)?;
+        }
+        Ok(locks)
+    }
+
+    /// Returns the number of messages in flight under each lock: the consumer id of a
+    /// `pop_delivery` consumer, or the batch id of a `claim_batch` call.
+    pub fn in_flight_locks(&self) -> BusResult<HashMap<String, i64>> {
+        self.locks_stmt
+            .query(&[], |rows| {
+                rows.iter().map(|row| (row.get("lock"), row.get("count"))).collect()
+            })
+            .map_err(BusError::Size)
+    }
+
+    /// Waits up to `timeout` for a push notification, then returns the number of pending
+    /// messages. Notifications that arrived together are drained so the next call blocks.
+    pub fn wake_and_count(&self, timeout: Duration) -> BusResult<i64> {
+        self.wait_for_notification(timeout)?;
+        self.consume_pending_notifications()?;
+        self.pending()
+    }
+
+    /// Pushes a message into the queue.
+    pub fn push<E>(&self, obj: B) -> Result<(), PushError<E>>
+        where B: ToMessageBody<E>
+    {
+        let body = self.to_body(obj)?;
+        self.push_body(body)?;
+        Ok(())
+    }
+
+    /// Pushes a message into the queue, returning the id assigned to it by the database. This is
+    /// the id later reported by `Message::id`. A message dropped by a deduplicating queue has no
+    /// id, so returns `PushError::Deduplicated`.
+    pub fn push_returning_id<E>(&self, obj: B) -> Result<i32, PushError<E>>
+        where B: ToMessageBody<E>
+    {
+        let body = self.to_body(obj)?;
+        self.push_body(body)?.ok_or(PushError::Deduplicated)
+    }
+
+    /// Pushes a message only if another connection is listening for pushes to the queue, e.g. so
+    /// a request isn't queued when nothing is there to answer it. Returns whether it was pushed.
+    ///
+    /// Every queue listens while it exists, including ones only pushed to, unless built with
+    /// `QueueBuilder::reconnect_listener`, when its own connection does. Consumers that start or
+    /// stop between the check and the push are missed, so one can still find itself alone with
+    /// the message, or be denied it.
+    #[cfg(not(feature = "polling-only"))]
+    pub fn push_if_listeners<E>(&self, obj: B) -> Result<bool, PushError<E>>
+        where B: ToMessageBody<E>
+    {
+        let listening: bool = self.listeners_stmt
+            .query(&[&self.channel], |rows| rows.get(0).get("listening"))
+            .map_err(|e| self.push_failed(e))?;
+        if !listening {
+            debug!("No listeners on queue {}.{}, not pushing", self.bus, self.name);
+            return Ok(false);
+        }
+        self.push(obj)?;
+        Ok(true)
+    }
+
+    /// Pushes a message as part of `trans`, a transaction on the bus's connection, so the message
+    /// is only queued if the caller's other writes in it commit. Consumers are notified when the
+    /// transaction commits, and not at all if it rolls back.
+    ///
+    /// Other queue operations on the connection while `trans` is open run in a savepoint within
+    /// it, so they too are undone if it rolls back.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// let bus = pqbus::new("postgres://postgres@localhost/pqbus", "myapp").unwrap();
+    /// let queue: pqbus::Queue<String> = bus.queue("welcome_emails").unwrap();
+    /// let trans = bus.connection().transaction().unwrap();
+    /// trans.execute("INSERT INTO users (name) VALUES ('sgibbs')", &[]).unwrap();
+    /// queue.push_in_transaction(&trans, "sgibbs".to_string()).unwrap();
+    /// trans.commit().unwrap();
+    /// ```
+    pub fn push_in_transaction<E>(&self, trans: &Transaction, obj: B) -> Result<(), PushError<E>>
+        where B: ToMessageBody<E>
+    {
+        if !ptr::eq(trans.connection(), self.conn()) {
+            return Err(PushError::Generic(format!("Can't push to queue {}.{} in a transaction \
+                                                   on another connection",
+                                                  self.bus,
+                                                  self.name)));
+        }
+        let body = self.to_body(obj)?;
+        self.push_body(body)?;
+        Ok(())
+    }
+
+    /// Pushes a message serialized from a reference, leaving `obj` with the caller, e.g. to push
+    /// the same value into several queues without cloning it.
+    pub fn push_ref<E>(&self, obj: &B) -> Result<(), PushError<E>>
+        where B: ToMessageBodyRef<E>
+    {
+        let body = obj.to_message_body_ref().map_err(PushError::BodySeralize)?;
+        self.push_body(self.encode(body)?)?;
+        Ok(())
+    }
+
+    /// Pushes a message into the queue, returning the size of the queue after the push, as
+    /// `size` would, so a producer can throttle itself without another round trip.
+    ///
+    /// The size is read in the same transaction as the push but other producers and consumers
+    /// carry on meanwhile, so sizes returned to concurrent producers need not increase one by one.
+    pub fn push_with_size<E>(&self, obj: B) -> Result<i64, PushError<E>>
+        where B: ToMessageBody<E>
+    {
+        let body = self.to_body(obj)?;
+        let trans = self.pqbus.begin().map_err(|e| self.push_failed(e))?;
+        self.push_body(body)?;
+        let size = self.size()?;
+        trans.commit().map_err(|e| self.push_failed(e))?;
+        Ok(size)
+    }
+
+    /// Pushes a serialized body with the queue's plain push statement.
+    fn push_body<E>(&self, body: Vec<u8>) -> Result<Option<i32>, PushError<E>> {
+        self.push_with(&self.push_stmt, body, &[])
+    }
+
+    /// Inserts `body` with `stmt`, which takes the body followed by `extra`, then notifies
+    /// consumers of it. Returns the message's id, or `None` if a deduplicating queue dropped it.
+    fn push_with<E>(&self,
+                    stmt: &QueueStatement,
+                    body: Vec<u8>,
+                    extra: &[&dyn ToSql])
+                    -> Result<Option<i32>, PushError<E>> {
+        let id = match self.insert(stmt, body, extra)? {
+            None => return Ok(None),
+            Some(id) => id,
+        };
+        self.notify_push(Some(id)).map_err(|e| self.push_failed(e))?;
+        Ok(Some(id))
+    }
+
+    /// Inserts `body` with `stmt` as `push_with` does, without notifying consumers.
+    fn insert<E>(&self,
+                 stmt: &QueueStatement,
+                 body: Vec<u8>,
+                 extra: &[&dyn ToSql])
+                 -> Result<Option<i32>, PushError<E>> {
+        self.release_stale_hash(&body)?;
+        let mut params: Vec<&dyn ToSql> = vec![&body];
+        params.extend_from_slice(extra);
+        let pushed = stmt.query(&params, |rows| self.pushed_id(rows, &body));
+        let id = match pushed.map_err(|e| self.push_failed(e))?? {
+            None => return Ok(None),
+            Some(id) => id,
+        };
+        info!("Message {} pushed to queue {}.{}", id, self.bus, self.name);
+        self.metrics.on_push(&self.name);
+        Ok(Some(id))
+    }
+
+    /// Pushes a message with the given `priority`. Queues built with `QueueBuilder::priority` pop
+    /// higher priorities first. Otherwise the priority is stored but ignored.
+    pub fn push_with_priority<E>(&self, obj: B, priority: i32) -> Result<(), PushError<E>>
+        where B: ToMessageBody<E>
+    {
+        let body = self.to_body(obj)?;
+        self.push_with(&self.push_priority_stmt, body, &[&priority])?;
+        Ok(())
+    }
+
+    /// Pushes a message that is never delivered after `expires_at`, e.g. a request whose caller
+    /// gives up waiting then, alongside any TTL of the queue. A message that expires before it's
+    /// popped stays in the queue until `expire` deletes it, and one pushed with an expiry in the
+    /// past is never delivered at all.
+    pub fn push_expiring<E>(&self, obj: B, expires_at: SystemTime) -> Result<(), PushError<E>>
+        where B: ToMessageBody<E>
+    {
+        let body = self.to_body(obj)?;
+        let expires_at = match expires_at.duration_since(UNIX_EPOCH) {
+            Ok(since) => duration_secs(since),
+            Err(e) => -duration_secs(e.duration()),
+        };
+        self.push_with(&self.push_expiring_stmt, body, &[&expires_at])?;
+        Ok(())
+    }
+
+    /// Pushes a message with `headers`, such as a content type or trace id, stored alongside the
+    /// body. Popped messages carry them in `Message::headers` and `Delivery::headers`. Messages
+    /// pushed without headers have none.
+    pub fn push_with_headers<E>(&self,
+                                obj: B,
+                                headers: HashMap<String, String>)
+                                -> Result<(), PushError<E>>
+        where B: ToMessageBody<E>
+    {
+        let body = self.to_body(obj)?;
+        let (keys, values): (Vec<String>, Vec<String>) = headers.into_iter().unzip();
+        self.push_with(&self.push_headers_stmt, body, &[&keys, &values])?;
+        Ok(())
+    }
+
+    /// Reads the id of a pushed message, or `None` if a deduplicating queue dropped it. Otherwise
+    /// an insert that returned no row was skipped because the queue is at its maximum depth.
+    fn pushed_id<E>(&self, rows: &Rows, body: &[u8]) -> Result<Option<i32>, PushError<E>> {
+        if rows.is_empty() {
+            if self.dedup && self.is_queued(body)? {
+                info!("Dropped duplicate message pushed to queue {}.{}",
+                      self.bus,
+                      self.name);
+                return Ok(None);
+            }
+            if self.max_depth.is_some() {
+                warn!("Queue {}.{} is full", self.bus, self.name);
+                return Err(PushError::QueueFull);
+            }
+        }
+        returned_id(rows).map(Some).map_err(|e| self.push_failed(e))
+    }
+
+    /// Returns true if a message with the same body as `body` is in the queue. Only bodies pushed
+    /// with deduplication are found.
+    fn is_queued<E>(&self, body: &[u8]) -> Result<bool, PushError<E>> {
+        self.duplicate_stmt
+            .query(&[&body], |rows| !rows.is_empty())
+            .map_err(|e| self.push_failed(e))
+    }
+
+    /// Lets a deduplicating queue accept `body` again if its copy was pushed longer ago than the
+    /// deduplication window.
+    fn release_stale_hash<E>(&self, body: &[u8]) -> Result<(), PushError<E>> {
+        if self.dedup {
+            self.release_hash_stmt.execute(&[&body]).map_err(|e| self.push_failed(e))?;
+        }
+        Ok(())
+    }
+
+    /// Serializes `obj`, compressing the body if the queue was built with compression.
+    fn to_body<E>(&self, obj: B) -> Result<Vec<u8>, PushError<E>>
+        where B: ToMessageBody<E>
+    {
+        let body = obj.to_message_body().map_err(|e| PushError::BodySeralize(e))?;
+        self.encode(body)
+    }
+
+    /// Compresses a serialized body if the queue was built with compression.
+    fn encode<E>(&self, body: Vec<u8>) -> Result<Vec<u8>, PushError<E>> {
+        #[cfg(feature = "compression")]
+        {
+            if let Some(codec) = self.compression {
+                return compression::compress(codec, &body)
+                    .map_err(|e| PushError::Generic(format!("Failed to compress message: {}", e)));
+            }
+        }
+        Ok(body)
+    }
+
+    /// Pushes a message that only becomes visible to consumers after `delay` has elapsed.
+    ///
+    /// No notification is sent, so blocked consumers pick the message up on their next pop
+    /// after it becomes visible rather than being woken for it.
+    pub fn push_delayed<E>(&self, obj: B, delay: Duration) -> Result<(), PushError<E>>
+        where B: ToMessageBody<E>
+    {
+        let body = self.to_body(obj)?;
+        self.insert(&self.push_delayed_stmt, body, &[&duration_secs(delay)])?;
+        Ok(())
+    }
+
+    /// Pushes many messages into the queue in a single transaction, sending one notification.
+    /// Either every message is pushed or none are. Returns the number of messages pushed, which
+    /// leaves out any a deduplicating queue dropped.
+    pub fn push_batch<E, I>(&self, objs: I) -> Result<u64, PushError<E>>
+        where B: ToMessageBody<E>,
+              I: IntoIterator<Item = B>
+    {
+        let mut bodies = vec![];
+        for obj in objs {
+            bodies.push(self.to_body(obj)?);
+        }
+
+        let trans = self.pqbus.begin().map_err(|e| self.push_failed(e))?;
+        let mut last_id = None;
+        let mut pushed = 0;
+        for body in &bodies {
+            self.release_stale_hash(body)?;
+            let id = self.push_stmt.query(&[body], |rows| self.pushed_id(rows, body));
+            if let Some(id) = id.map_err(|e| self.push_failed(e))?? {
+                last_id = Some(id);
+                pushed += 1;
+            }
+        }
+        if last_id.is_some() {
+            self.notify_push(last_id).map_err(|e| self.push_failed(e))?;
+        }
+        trans.commit().map_err(|e| self.push_failed(e))?;
+        info!("{} messages pushed to queue {}.{}", pushed, self.bus, self.name);
+        for _ in 0..pushed {
+            self.metrics.on_push(&self.name);
+        }
+
+        Ok(pushed)
+    }
+
+    /// Pushes a message whose body is read from `body`, streaming it into the queue a chunk at a
+    /// time rather than holding it in memory, e.g. for bodies of many megabytes. The body is
+    /// stored as it is read, so queues built with compression, deduplication or a maximum depth
+    /// are refused, as those need the whole body, or the push to be checked, before storing it.
+    pub fn push_reader<R: Read>(&self, body: R) -> BusResult<()> {
+        #[cfg(feature = "compression")]
+        let compresses = self.compression.is_some();
+        #[cfg(not(feature = "compression"))]
+        let compresses = false;
+        if compresses || self.dedup || self.max_depth.is_some() {
+            return Err(BusError::Generic(format!("Can't stream a message into {}.{} as it \
+                                                  compresses, deduplicates or limits the \
+                                                  depth of its messages",
+                                                 self.bus,
+                                                 self.name)));
+        }
+        let mut row = CopyRow::new(body);
+        self.push_copy_stmt.copy_in(&[], &mut row).map_err(|e| self.push_error(e))?;
+        self.notify_push(None).map_err(|e| self.push_error(e))?;
+        info!("Streamed message pushed to queue {}.{}", self.bus, self.name);
+        self.metrics.on_push(&self.name);
+        Ok(())
+    }
+
+    /// Pops a message from the queue if there is one pending, writing its body to `out` a chunk
+    /// at a time rather than holding it in memory. Returns false if there was none. The message
+    /// is only removed once its whole body has been written, and stays pending if writing fails.
+    /// Compressed bodies are written as they are stored.
+    pub fn pop_to_writer<W: Write>(&self, mut out: W) -> BusResult<bool> {
+        let trans = self.pqbus.begin().map_err(|e| self.pop_error(e))?;
+        let locked = self.lock_stream_stmt
+            .query(&[], |rows| {
+                if rows.is_empty() {
+                    None
+                } else {
+                    let row = rows.get(0);
+                    Some((row.get::<_, i32>("id"), row.get::<_, i32>("length")))
+                }
+            })
+            .map_err(|e| self.pop_error(e))?;
+        let (id, length) = match locked {
+            None => {
+                debug!("No message available in {}.{}", self.bus, self.name);
+                return Ok(false);
+            }
+            Some(locked) => locked,
+        };
+
+        let chunk_size = stream::CHUNK_SIZE as i32;
+        // substring counts from 1.
+        let mut offset = 1;
+        while offset <= length {
+            self.read_chunk_stmt
+                .query(&[&id, &offset, &chunk_size], |rows| {
+                    let chunk: Vec<u8> = rows.get(0).get("chunk");
+                    out.write_all(&chunk)
+                })
+                .and_then(|written| written.map_err(postgres::error::Error::Io))
+                .map_err(|e| self.pop_error(e))?;
+            offset += chunk_size;
+        }
+        out.flush().map_err(|e| self.pop_error(postgres::error::Error::Io(e)))?;
+
+        self.delete_stmt.execute(&[&id]).map_err(|e| self.pop_error(e))?;
+        trans.commit().map_err(|e| self.pop_error(e))?;
+        info!("Streamed message {} popped from {}.{}", id, self.bus, self.name);
+        self.metrics.on_pop(&self.name);
+        Ok(true)
+    }
+
+    /// Pops a message from the queue. Blocks if there are none pending.
+    pub fn pop_blocking<E>(&self) -> Result<B, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        self.pop_blocking_with::<Converted<E>>()
+    }
+
+    /// Pops a message as `P` does. Blocks if there are none pending.
+    fn pop_blocking_with<P: PopMessage<B>>(&self) -> Result<P::Message, P::Error> {
+        loop {
+            let p = P::pop(self)?;
+            if p.is_some() {
+                return Ok(p.unwrap());
+            }
+            self.wait_for_next_notification()?;
+        }
+    }
+
+    /// Pops a message from the queue, blocking if there are none pending until one arrives or
+    /// `cancel` is set. Returns `Ok(None)` once cancelled, at most the queue's cancel check
+    /// interval after `cancel` is set.
+    pub fn pop_blocking_cancellable<E>(&self, cancel: &AtomicBool) -> Result<Option<B>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        self.pop_blocking_cancellable_with::<Converted<E>>(cancel)
+    }
+
+    /// Pops a message as `P` does, blocking until one arrives or `cancel` is set. See
+    /// `pop_blocking_cancellable`.
+    fn pop_blocking_cancellable_with<P>(&self,
+                                        cancel: &AtomicBool)
+                                        -> Result<Option<P::Message>, P::Error>
+        where P: PopMessage<B>
+    {
+        while !cancel.load(atomic::Ordering::SeqCst) {
+            if let Some(p) = P::pop(self)? {
+                return Ok(Some(p));
+            }
+            self.wait_for_notification(self.cancel_check_interval)?;
+        }
+        debug!("Cancelled blocking pop on {}.{}", self.bus, self.name);
+        Ok(None)
+    }
+
+    /// Pops a message from the queue. Blocks for duration of `timeout` if there are none pending.
+    ///
+    /// Waits at most once, so a notification that finds the queue empty ends the call early. Use
+    /// `pop_deadline` to keep waiting until an absolute deadline instead of calling this in a loop.
+    pub fn pop_wait<E>(&self, timeout: Duration) -> Result<Option<B>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        match self.pop_wait_detailed(timeout)? {
+            WaitOutcome::Message(m) => Ok(Some(m)),
+            WaitOutcome::NotifiedButEmpty | WaitOutcome::TimedOut => Ok(None),
+        }
+    }
+
+    /// As `pop_wait`, but reports whether a call that popped nothing was woken by a push
+    /// notification or timed out. Under `polling-only`, finding a pending message while polling
+    /// counts as a notification.
+    pub fn pop_wait_detailed<E>(&self, timeout: Duration) -> Result<WaitOutcome<B>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        if let Some(m) = self.pop()? {
+            return Ok(WaitOutcome::Message(m));
+        }
+        if !self.wait_for_notification(timeout)? {
+            return Ok(WaitOutcome::TimedOut);
+        }
+        match self.pop()? {
+            Some(m) => Ok(WaitOutcome::Message(m)),
+            None => Ok(WaitOutcome::NotifiedButEmpty),
+        }
+    }
+
+    /// Pops a message from the queue, blocking until one arrives or `deadline` passes.
+    ///
+    /// Returns `Ok(None)` once the deadline has passed with nothing to pop. The queue is checked
+    /// one last time after the deadline, so a message arriving right at it is still returned.
+    pub fn pop_deadline<E>(&self, deadline: Instant) -> Result<Option<B>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        loop {
+            if let Some(p) = self.pop()? {
+                return Ok(Some(p));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.wait_for_notification(deadline - now)?;
+        }
+    }
+
+    /// Run a closure on messages in the queue. Blocks if there are none pending.
+    pub fn pop_callback<F, E>(&self, work_fn: F) -> Result<bool, BusError>
+        where F: Fn(B),
+              B: FromMessageBody<E>,
+              E: fmt::Display
+    {
+        self.pop_callback_until(|m| {
+                                    work_fn(m);
+                                    ControlFlow::Continue(())
+                                })?;
+        Ok(true)
+    }
+
+    /// Run a closure on messages in the queue until it returns `ControlFlow::Break`, returning
+    /// the number of messages processed. Blocks if there are none pending.
+    pub fn pop_callback_until<F, E>(&self, mut work_fn: F) -> Result<u64, BusError>
+        where F: FnMut(B) -> ControlFlow<()>,
+              B: FromMessageBody<E>,
+              E: fmt::Display
+    {
+        let mut count = 0;
+        loop {
+            self.consume_pending_notifications()?;
+            if self.consume_pending_items(&mut work_fn, &mut count)?.is_break() {
+                return Ok(count);
+            }
+            self.wait_for_next_notification()?;
+        }
+    }
+
+    /// Run a closure on messages in the queue until `cancel` is set, returning the number of
+    /// messages processed. Blocks if there are none pending. The flag is checked after each
+    /// message and at least once per cancel check interval while waiting.
+    pub fn pop_callback_cancellable<F, E>(&self,
+                                          mut work_fn: F,
+                                          cancel: &AtomicBool)
+                                          -> Result<u64, BusError>
+        where F: FnMut(B),
+              B: FromMessageBody<E>,
+              E: fmt::Display
+    {
+        let mut work = |m| {
+            work_fn(m);
+            if cancel.load(atomic::Ordering::SeqCst) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        };
+        let mut count = 0;
+        while !cancel.load(atomic::Ordering::SeqCst) {
+            self.consume_pending_notifications()?;
+            if self.consume_pending_items(&mut work, &mut count)?.is_break() {
+                break;
+            }
+            self.wait_for_notification(self.cancel_check_interval)?;
+        }
+        debug!("Cancelled callback on {}.{} after {} messages",
+               self.bus,
+               self.name,
+               count);
+        Ok(count)
+    }
+
+    /// Run a fallible closure on messages in the queue, blocking if there are none pending. Each
+    /// message is deleted once the closure succeeds on it. The first failure stops the loop,
+    /// releasing the message back to the queue and returning the closure's error, so this only
+    /// returns on error.
+    pub fn pop_callback_result<F, E, WE>(&self, mut work_fn: F) -> Result<(), CallbackError<E, WE>>
+        where F: FnMut(B) -> Result<(), WE>,
+              B: FromMessageBody<E>,
+              E: fmt::Display
+    {
+        loop {
+            self.consume_pending_notifications().map_err(|e| CallbackError::Pop(e.into()))?;
+            while let Some((id, obj, _)) = self.claim().map_err(CallbackError::Pop)? {
+                if let Err(e) = work_fn(obj) {
+                    warn!("Work failed on message {} from {}.{}, releasing it",
+                          id,
+                          self.bus,
+                          self.name);
+                    self.nack(id).map_err(|e| CallbackError::Pop(e.into()))?;
+                    return Err(CallbackError::Work(e));
+                }
+                self.ack(id).map_err(|e| CallbackError::Pop(e.into()))?;
+            }
+            self.wait_for_next_notification().map_err(|e| CallbackError::Pop(e.into()))?;
+        }
+    }
+
+    /// Run a closure on messages in the queue, sending the outcome of each to `results`.
+    /// Blocks if there are none pending.
+    pub fn pop_callback_results<F, E, WE>(&self,
+                                          work_fn: F,
+                                          results: Sender<ProcessResult>)
+                                          -> Result<bool, BusError>
+        where F: Fn(B) -> Result<(), WE>,
+              B: FromMessageBody<E>,
+              E: fmt::Display,
+              WE: fmt::Display
+    {
+        loop {
+            self.consume_pending_notifications()?;
+            self.consume_pending_results(&work_fn, &results)?;
+            self.wait_for_next_notification()?;
+        }
+    }
+
+    /// Pops messages from the queue and sends them to `tx` until `stop` is set, returning the
+    /// number of messages sent, e.g. from a thread feeding a channel-based worker pool. Blocks
+    /// as `pop_blocking_cancellable` if there are none pending.
+    ///
+    /// Errors are sent to `tx` too. A message that fails to convert has been popped, so pumping
+    /// carries on after it, but a database error stops it. It also stops once the receiver hangs
+    /// up.
+    pub fn pump<E>(&self, tx: Sender<BusResult<B>>, stop: Arc<AtomicBool>) -> u64
+        where B: FromMessageBody<E>,
+              E: fmt::Display
+    {
+        let mut count = 0;
+        loop {
+            let (popped, carry_on) = match self.pop_blocking_cancellable(&stop) {
+                Ok(Some(obj)) => (Ok(obj), true),
+                Ok(None) => break,
+                Err(PopError::Pop(e)) => (Err(BusError::Pop(e)), false),
+                Err(e @ PopError::BodyDeseralize(_)) => (Err(e.into()), true),
+                Err(e) => (Err(e.into()), false),
+            };
+            let sent = popped.is_ok();
+            if tx.send(popped).is_err() {
+                warn!("Receiver of {}.{} hung up, stopped pumping", self.bus, self.name);
+                break;
+            }
+            if sent {
+                count += 1;
+            }
+            if !carry_on {
+                break;
+            }
+        }
+        debug!("Stopped pumping {}.{} after {} messages",
+               self.bus,
+               self.name,
+               count);
+        count
+    }
+
+    /// Pops a message from the queue if there is one pending.
+    pub fn pop<E>(&self) -> Result<Option<B>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        Ok(self.pop_with(B::from_message_body)?.map(|(_id, obj)| obj))
+    }
+
+    /// Pops a message from the queue without converting it to the queue's message type, e.g. to
+    /// route or log it by its headers when conversion is expensive or may fail. The message keeps
+    /// its id and headers, and compressed bodies are decompressed.
+    pub fn pop_raw(&self) -> BusResult<Option<Message>> {
+        match self.pop_with(Ok::<Message, Infallible>) {
+            Ok(popped) => Ok(popped.map(|(_id, m)| m)),
+            Err(PopError::Pop(e)) => Err(BusError::Pop(e)),
+            Err(e) => Err(BusError::Generic(e.to_string())),
+        }
+    }
+
+    /// Pops a message from the queue as `T` rather than the queue's message type, e.g. to try
+    /// each of the types a queue carries in turn. A message that fails to convert is left pending
+    /// instead of being dead-lettered, so it can be popped again as another type.
+    pub fn pop_as<T, E>(&self) -> Result<Option<T>, PopError<E>>
+        where T: FromMessageBody<E>
+    {
+        let trans = self.pqbus.begin().map_err(|e| self.pop_failed(e))?;
+        let next = self.lock_next(&self.pop_stmt, &[]).map_err(|e| self.pop_failed(e))?;
+        let (id, message) = match next {
+            None => {
+                trans.commit().map_err(|e| self.pop_failed(e))?;
+                return Ok(None);
+            }
+            Some(next) => next,
+        };
+        let obj = match T::from_message_body(message) {
+            Ok(obj) => obj,
+            Err(e) => {
+                debug!("Left message {} in {}.{}", id, self.bus, self.name);
+                trans.commit().map_err(|e| self.pop_failed(e))?;
+                return Err(PopError::BodyDeseralize(e));
+            }
+        };
+        self.delete_stmt.execute(&[&id]).map_err(|e| self.pop_failed(e))?;
+        trans.commit().map_err(|e| self.pop_failed(e))?;
+        debug!("Deleted message {} from {}.{}", id, self.bus, self.name);
+        self.metrics.on_pop(&self.name);
+        Ok(Some(obj))
+    }
+
+    /// Pops a message from the queue at most once, deleting it in a single statement before it
+    /// is converted. Unlike `pop`, a message that fails to convert is lost rather than
+    /// dead-lettered, as is one whose consumer crashes before processing it. Use this where
+    /// losing the odd message is better than receiving it twice, and `pop_delivery` where it is
+    /// the other way round.
+    pub fn pop_delete<E>(&self) -> Result<Option<B>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        let popped = self.pop_delete_stmt
+            .query(&[], |rows| {
+                if rows.is_empty() {
+                    None
+                } else {
+                    Some(self.read_row(&rows.get(0)))
+                }
+            })
+            .map_err(|e| self.pop_failed(e))?;
+        let popped = match popped {
+            None => {
+                debug!("No message available in {}.{}", self.bus, self.name);
+                return Ok(None);
+            }
+            Some(popped) => popped,
+        };
+        let (id, message) = popped.map_err(|(_, e)| PopError::Generic(e))?;
+        debug!("Deleted message {} from {}.{}", id, self.bus, self.name);
+        self.metrics.on_pop(&self.name);
+        B::from_message_body(message).map(Some).map_err(PopError::BodyDeseralize)
+    }
+
+    /// Pops the next pending message whose headers include every `(key, value)` pair in
+    /// `filter`, leaving other messages for other consumers. An empty filter matches any
+    /// message, as with `pop`.
+    pub fn pop_filtered<E>(&self, filter: &[(&str, &str)]) -> Result<Option<B>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        if filter.is_empty() {
+            return self.pop();
+        }
+        let keys: Vec<String> = filter.iter().map(|&(k, _)| k.to_string()).collect();
+        let values: Vec<String> = filter.iter().map(|&(_, v)| v.to_string()).collect();
+        let popped =
+            self.pop_from(&self.pop_filtered_stmt, &[&keys, &values], B::from_message_body)?;
+        Ok(popped.map(|(_id, obj)| obj))
+    }
+
+    /// Pops the next pending message, converting it with `convert`. The row is deleted in the
+    /// same transaction once the conversion succeeds, and left pending otherwise.
+    fn pop_with<T, E, F>(&self, convert: F) -> Result<Option<(i32, T)>, PopError<E>>
+        where F: FnOnce(Message) -> Result<T, E>
+    {
+        self.pop_from(&self.pop_stmt, &[], convert)
+    }
+
+    /// Pops the message `stmt` locks, as `pop_with`.
+    fn pop_from<T, E, F>(&self,
+                         stmt: &QueueStatement,
+                         params: &[&dyn ToSql],
+                         convert: F)
+                         -> Result<Option<(i32, T)>, PopError<E>>
+        where F: FnOnce(Message) -> Result<T, E>
+    {
+        let trans = self.pqbus.begin().map_err(|e| self.pop_failed(e))?;
+        let next = self.lock_next(stmt, params).map_err(|e| self.pop_failed(e))?;
+        let (id, message) = match next {
+            None => {
+                // Keep any rows lock_next dead-lettered on the way.
+                trans.commit().map_err(|e| self.pop_failed(e))?;
+                return Ok(None);
+            }
+            Some(next) => next,
+        };
+        let obj = match convert(message) {
+            Ok(obj) => obj,
+            Err(e) => {
+                self.dead_letter(id, &conversion_failed::<T, E>())?;
+                trans.commit().map_err(|e| self.pop_failed(e))?;
+                return Err(PopError::BodyDeseralize(e));
+            }
+        };
+
+        self.delete_stmt.execute(&[&id]).map_err(|e| self.pop_failed(e))?;
+        trans.commit().map_err(|e| self.pop_failed(e))?;
+        debug!("Deleted message {} from {}.{}", id, self.bus, self.name);
+        self.metrics.on_pop(&self.name);
+
+        Ok(Some((id, obj)))
+    }
+
+    /// Pops the messages pending when called, in order, stopping at the first error. Messages
+    /// pushed while draining may be left for later, so a busy queue cannot keep it running.
+    pub fn drain<E>(&self) -> Result<Vec<B>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        let pending = self.pending()?;
+        let mut messages = Vec::new();
+        while (messages.len() as i64) < pending {
+            match self.pop()? {
+                None => break,
+                Some(m) => messages.push(m),
+            }
+        }
+        Ok(messages)
+    }
 
-    let mut last_err = None;
-
-    let conn = match retry(10,
-                           100,
-                           || Connection::connect(uri.as_ref(), SslMode::None),
-                           |r| {
-        if let &Err(ref e) = r {
-            warn!("Failed to connect to postgresql: {}", e);
-            last_err = Some(format!("Unable to connect to {}: {}", uri, e));
-        }
-        r.is_ok()
-    }) {
-        Err(e) => {
-            match last_err {
-                None => error!("Giving up on connection to postgresql: {}", e),
-                Some(e) => error!("{}", e),
+    /// Returns the next pending message without locking or removing it. A message that can't be
+    /// read or converted is returned as the error, and stays at the head of the queue.
+    pub fn peek<E>(&self) -> Result<Option<B>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        let peeked = self.peek_stmt
+            .query(&[], |rows| {
+                if rows.is_empty() {
+                    None
+                } else {
+                    Some(self.read_row(&rows.get(0)))
+                }
+            })
+            .map_err(|e| self.pop_failed(e))?;
+        let peeked = match peeked {
+            None => {
+                debug!("No message to peek in {}.{}", self.bus, self.name);
+                return Ok(None);
             }
-            return Err(BusError::Connection(uri, e));
-        }
-        Ok(c) => c.unwrap(),
-    };
+            Some(peeked) => peeked,
+        };
+        let (_id, message) = peeked.map_err(|(_, e)| PopError::Generic(e))?;
+        Ok(Some(B::from_message_body(message).map_err(PopError::BodyDeseralize)?))
+    }
 
-    info!("Connected to bus {}", name.clone());
+    /// Claims up to `max` messages from the queue without blocking. Returns fewer, possibly none,
+    /// if the queue is shorter. All of the messages are claimed under a single batch id, which
+    /// is recorded in the lock column of their rows, so the messages claimed together can be
+    /// found later.
+    ///
+    /// The messages stay in the queue until `ack_batch` deletes them or `nack_batch` releases
+    /// them, and are released by `reclaim_expired` if neither happens in time. Messages locked by
+    /// other consumers are skipped, so concurrent consumers never receive the same message. If a
+    /// message fails to convert, the rest of the batch is left pending and the bad one is
+    /// dead-lettered.
+    ///
+    /// Every batch size shares one prepared statement, with `max` bound as its limit.
+    pub fn claim_batch<E>(&self, max: usize) -> Result<Batch<B>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        let batch_id = Uuid::new_v4().to_string();
+        let (messages, ()) =
+            self.take_batch(&self.claim_batch_stmt, &[&batch_id, &(max as i64)], || Ok(()))?;
+        info!("Claimed batch {} of {} messages from {}.{}",
+              batch_id,
+              messages.len(),
+              self.bus,
+              self.name);
+        Ok(Batch::new(batch_id, messages))
+    }
 
-    Ok(PqBus {
-        conn: conn,
-        name: name.clone(),
-    })
-}
+    /// Deletes the messages claimed by the `claim_batch` call that returned `batch_id`, returning
+    /// the number deleted. Messages reclaimed since are no longer part of the batch.
+    pub fn ack_batch(&self, batch_id: &str) -> BusResult<u64> {
+        let count = self.ack_batch_stmt.execute(&[&batch_id]).map_err(BusError::Pop)?;
+        debug!("Acknowledged batch {} of {} messages from {}.{}",
+               batch_id,
+               count,
+               self.bus,
+               self.name);
+        Ok(count)
+    }
 
-impl PqBus {
-    /// Constructs a queue on the bus from the given `name`.
-    pub fn queue<'a, N, T>(&'a self, name: N) -> BusResult<Queue<'a, T>>
-        where N: Into<String>
+    /// Releases the messages claimed by the `claim_batch` call that returned `batch_id` so they
+    /// can be delivered again, returning the number released.
+    pub fn nack_batch(&self, batch_id: &str) -> BusResult<u64> {
+        let count = self.nack_batch_stmt.execute(&[&batch_id]).map_err(BusError::Pop)?;
+        debug!("Released batch {} of {} messages back to {}.{}",
+               batch_id,
+               count,
+               self.bus,
+               self.name);
+        Ok(count)
+    }
+
+    /// Pops up to `max` messages from the queue without blocking, deleting them in a single
+    /// statement. Returns fewer, possibly none, if the queue is shorter.
+    ///
+    /// Messages locked by other consumers are skipped, so concurrent consumers never receive the
+    /// same message. The messages are removed from the queue only if every one of them converts;
+    /// otherwise the rest are left pending and the bad one is dead-lettered, as with `pop`.
+    ///
+    /// The whole batch stays locked until every message has been converted, so very large
+    /// batches hold their locks, and keep the transaction open, for longer.
+    pub fn pop_batch<E>(&self, max: usize) -> Result<Vec<B>, PopError<E>>
+        where B: FromMessageBody<E>
     {
-        Queue::new(&self.conn, &name.into(), &self.name)
+        let (messages, ()) = self.take_batch(&self.pop_batch_stmt, &[&(max as i64)], || Ok(()))?;
+        Ok(messages)
     }
-}
 
-fn table_name_generator(bus: &String, queue: &String) -> String {
-    format!("pqbus_{}_{}_queue", bus, queue)
-}
+    /// Pops up to `max` messages as `pop_batch` does, also returning the number of messages still
+    /// pending afterwards, counted in the same transaction. Consumers can use the count to decide
+    /// whether to pop again straight away or wait.
+    pub fn try_pop_up_to<E>(&self, max: usize) -> Result<(Vec<B>, i64), PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        self.take_batch(&self.pop_batch_stmt, &[&(max as i64)], || {
+            self.pending_stmt.query(&[], |rows| rows.get(0).get("count"))
+        })
+    }
 
-/// A push pop message queue.
-impl<'a, B> Queue<'a, B> {
-    fn new(conn: &'a Connection, name: &String, bus: &String) -> BusResult<Self> {
+    /// Converts the messages `stmt` deletes or claims, running `then` in the same transaction
+    /// before it commits. If a row can't be read or converted, the transaction is rolled back,
+    /// returning the whole batch to the queue, and the bad row is dead-lettered.
+    fn take_batch<E, T, F>(&self,
+                           stmt: &QueueStatement,
+                           params: &[&dyn ToSql],
+                           then: F)
+                           -> Result<(Vec<B>, T), PopError<E>>
+        where B: FromMessageBody<E>,
+              F: FnOnce() -> postgres::Result<T>
+    {
+        let trans = self.pqbus.begin().map_err(|e| self.pop_failed(e))?;
+        let rows: Vec<_> = stmt
+            .query(params,
+                   |locked| locked.iter().map(|r| self.read_row(&r)).collect())
+            .map_err(|e| self.pop_failed(e))?;
+        debug!("Received batch of {} messages from {}.{}",
+               rows.len(),
+               self.bus,
+               self.name);
 
-        if invalid_name(name) {
-            return Err(BusError::InvalidQueueName(name.clone()));
+        let mut messages = Vec::with_capacity(rows.len());
+        for row in rows {
+            let (id, message) = match row {
+                Ok(row) => row,
+                Err((id, reason)) => {
+                    trans.finish().map_err(|e| self.pop_failed(e))?;
+                    if let Some(id) = id {
+                        self.dead_letter(id, &reason)?;
+                    }
+                    return Err(PopError::Generic(reason));
+                }
+            };
+            match B::from_message_body(message) {
+                Ok(obj) => messages.push(obj),
+                Err(e) => {
+                    // Return the rest of the batch to the queue before moving the bad one aside.
+                    trans.finish().map_err(|e| self.pop_failed(e))?;
+                    self.dead_letter(id, &conversion_failed::<B, E>())?;
+                    return Err(PopError::BodyDeseralize(e));
+                }
+            }
+        }
+        let then = then().map_err(|e| self.pop_failed(e))?;
+        trans.commit().map_err(|e| self.pop_failed(e))?;
+        for _ in &messages {
+            self.metrics.on_pop(&self.name);
         }
 
-        info!("Creating queue {}.{}", bus, name);
+        Ok((messages, then))
+    }
 
-        let table_name = table_name_generator(bus, name);
+    /// Moves up to `count` pending messages to the queue `other` in a single transaction, e.g. from
+    /// a work queue to a retry queue, returning the number moved. Messages keep their priority,
+    /// headers and enqueue time, are moved as stored without being converted, and are not
+    /// deduplicated against messages in `other`.
+    ///
+    /// Both queues must be created from the same `PqBus`, so they share the transaction.
+    pub fn move_to(&self, other: &Queue<B>, count: usize) -> BusResult<u64> {
+        if !ptr::eq(self.pqbus, other.pqbus) {
+            return Err(BusError::Generic(format!("Can't move messages from {}.{} to {}.{} on \
+                                                  another connection",
+                                                 self.bus,
+                                                 self.name,
+                                                 other.bus,
+                                                 other.name)));
+        }
 
-        conn.execute(&format!(r#"
-                CREATE TABLE IF NOT EXISTS {} (
-                    id SERIAL PRIMARY KEY,
-                    message bytea NOT NULL,
-                    lock VARCHAR DEFAULT NULL
-                )"#,
-                              table_name),
-                     &[])
-            .map_err(|e| BusError::Create(e))?;
+        let trans = self.pqbus.begin().map_err(BusError::Sql)?;
+        let moved = self.conn().execute(&format!(r#"
+                        WITH moved AS ({t})
+                        INSERT INTO {n} (message, priority, headers, enqueued_at)
+                        SELECT message, priority, headers, enqueued_at
+                        FROM   moved
+                        ORDER  BY {o}
+                        "#,
+                                               t = self.take_sql,
+                                               n = other.table_name,
+                                               o = POSITION),
+                                      &[&(count as i64)])
+            .map_err(BusError::Sql)?;
+        if moved > 0 {
+            other.notify_push(None).map_err(BusError::Notify)?;
+        }
+        trans.commit().map_err(BusError::Sql)?;
+        info!("Moved {} messages from queue {}.{} to {}.{}",
+              moved,
+              self.bus,
+              self.name,
+              other.bus,
+              other.name);
+        Ok(moved)
+    }
 
-        conn.execute(&format!("LISTEN {}", table_name), &[]).map_err(|e| BusError::Listen(e))?;
+    /// Pops a message from the queue if there is one pending, leaving its row locked until the
+    /// returned `Delivery` is acknowledged. Dropping the delivery without calling `ack` releases
+    /// the message back to the queue.
+    pub fn pop_delivery<'q, E>(&'q self) -> Result<Option<Delivery<'q, B>>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        let claimed = self.claim()?;
+        Ok(claimed.map(|(id, obj, headers)| Delivery::new(self, id, obj).with_headers(headers)))
+    }
 
-        Ok(Queue {
-            notifications: conn.notifications(),
-            push_stmt:
-                conn.prepare_cached(&format!("INSERT INTO {} (message) VALUES ($1)", table_name))?,
-            notify_stmt: conn.prepare_cached(&format!("NOTIFY {}", table_name))?,
-            size_stmt: conn.prepare_cached(&format!("SELECT count(*) FROM  {}", table_name))?,
-            pop_stmt: conn.prepare_cached(&format!(r#"
-                        UPDATE {n} q
-                        SET lock = 'me'
-                        FROM  (
-                           SELECT id,message
-                           FROM   {n}
-                           WHERE  lock is NULL
-                           LIMIT  1
-                           FOR UPDATE SKIP LOCKED
-                           ) sub
-                        WHERE q.id = sub.id
-                        RETURNING q.id, q.message;
-                        "#,
-                                         n = table_name))?,
-            name: name.clone(),
-            bus: bus.clone(),
-            phantom: PhantomData,
-        })
+    /// Locks the next pending message for this consumer without deleting it, returning its id,
+    /// converted body and headers. Messages that fail to convert are dead-lettered.
+    fn claim<E>(&self) -> Result<Option<(i32, B, Headers)>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        let trans = self.pqbus.begin().map_err(|e| self.pop_failed(e))?;
+        let next = self.lock_next(&self.claim_stmt, &[&self.consumer_id])
+            .map_err(|e| self.pop_failed(e))?;
+        let (id, message) = match next {
+            None => {
+                // Keep any rows lock_next dead-lettered on the way.
+                trans.commit().map_err(|e| self.pop_failed(e))?;
+                return Ok(None);
+            }
+            Some(next) => next,
+        };
+        let headers = message.headers().clone();
+        let obj = match B::from_message_body(message) {
+            Ok(obj) => obj,
+            Err(e) => {
+                self.dead_letter(id, &conversion_failed::<B, E>())?;
+                trans.commit().map_err(|e| self.pop_failed(e))?;
+                return Err(PopError::BodyDeseralize(e));
+            }
+        };
+        trans.commit().map_err(|e| self.pop_failed(e))?;
+        self.metrics.on_pop(&self.name);
+
+        Ok(Some((id, obj, headers)))
     }
 
-    /// Returns the number of messages in the queue.
-    pub fn size(&self) -> BusResult<i64> {
-        let result = self.size_stmt.query(&[]).map_err(|e| BusError::Size(e))?;
-        let row = result.get(0);
-        Ok(row.get("count"))
+    /// Deletes an acknowledged message.
+    fn ack(&self, id: i32) -> BusResult<()> {
+        self.delete_stmt.execute(&[&id]).map_err(BusError::Pop)?;
+        debug!("Acknowledged message {} from {}.{}", id, self.bus, self.name);
+        Ok(())
     }
 
-    /// Determines if there are any pending messages.
-    pub fn is_empty(&self) -> BusResult<bool> {
-        Ok(self.size()? == 0)
+    /// Releases the lock on a message so it can be delivered again.
+    fn nack(&self, id: i32) -> BusResult<()> {
+        self.nack_stmt.execute(&[&id]).map_err(BusError::Pop)?;
+        debug!("Released message {} back to {}.{}", id, self.bus, self.name);
+        Ok(())
     }
 
-    /// Pushes a message into the queue.
-    pub fn push<E>(&self, obj: B) -> Result<(), PushError<E>>
-        where B: ToMessageBody<E>
-    {
-        let body = obj.to_message_body().map_err(|e| PushError::BodySeralize(e))?;
-        self.push_stmt.execute(&[&body]).map_err(|e| PushError::Substrate(e))?;
-        info!("Message pushed to queue {}.{}", self.bus, self.name);
+    /// Moves the time a message was locked forward by `extra`. Returns false if it is no longer
+    /// locked.
+    fn extend_lock(&self, id: i32, extra: Duration) -> BusResult<bool> {
+        let count = self.extend_lock_stmt
+            .execute(&[&id, &duration_secs(extra)])
+            .map_err(BusError::Pop)?;
+        debug!("Extended lock on message {} in {}.{} by {:?}",
+               id,
+               self.bus,
+               self.name,
+               extra);
+        Ok(count > 0)
+    }
 
-        self.notify_stmt.execute(&[]).map_err(|e| PushError::Substrate(e))?;
-        debug!("Sent push notification to queue {}.{}", self.bus, self.name);
+    /// Releases the lock on a message and moves it to the back of the queue, hidden from
+    /// consumers until `delay` has passed.
+    fn requeue(&self, id: i32, delay: Duration) -> BusResult<()> {
+        self.requeue_stmt.execute(&[&id, &duration_secs(delay)]).map_err(BusError::Pop)?;
+        debug!("Requeued message {} to {}.{} for {:?}", id, self.bus, self.name, delay);
+        Ok(())
+    }
 
+    /// Moves a message that can't be delivered to the dead-letter table, recording why.
+    fn dead_letter(&self, id: i32, error: &str) -> BusResult<()> {
+        self.dead_letter_stmt.execute(&[&id, &error]).map_err(BusError::DeadLetter)?;
+        warn!("Dead-lettered message {} from {}.{}: {}",
+              id,
+              self.bus,
+              self.name,
+              error);
+        self.metrics.on_dead_letter(&self.name);
         Ok(())
     }
 
-    /// Pops a message from the queue. Blocks if there are none pending.
-    pub fn pop_blocking<E>(&self) -> Result<B, PopError<E>>
-        where B: FromMessageBody<E>
-    {
-        loop {
-            let p = self.pop()?;
-            if p.is_some() {
-                return Ok(p.unwrap());
-            }
-            self.handle_notification(self.notifications.blocking_iter())?;
-        }
+    /// Reports a failed push to the queue's metrics.
+    fn push_failed<E>(&self, e: postgres::error::Error) -> PushError<E> {
+        PushError::from(self.push_error(e))
     }
 
-    /// Pops a message from the queue. Blocks for duration of `timeout` if there are none pending.
-    pub fn pop_wait<E>(&self, timeout: Duration) -> Result<Option<B>, PopError<E>>
-        where B: FromMessageBody<E>
-    {
-        {
-            let p = self.pop()?;
-            if p.is_some() {
-                return Ok(p);
-            }
-        }
-        if let Some(_n) = self.handle_notification(self.notifications.timeout_iter(timeout))? {
-            let p = self.pop()?;
-            if p.is_some() {
-                return Ok(p);
-            }
-        }
-        Ok(None)
+    /// Reports a failed pop to the queue's metrics.
+    fn pop_failed<E>(&self, e: postgres::error::Error) -> PopError<E> {
+        PopError::from(self.pop_error(e))
     }
 
-    /// Run a closure on messages in the queue. Blocks if there are none pending.
-    pub fn pop_callback<F, E>(&self, work_fn: F) -> Result<bool, BusError>
-        where F: Fn(B),
-              B: FromMessageBody<E>,
-              E: fmt::Display
-    {
+    /// Reports a failed push to the queue's metrics, returning the error.
+    fn push_error(&self, e: postgres::error::Error) -> BusError {
+        let error = BusError::Push(e);
+        self.metrics.on_error(&self.name, &error);
+        error
+    }
+
+    /// Reports a failed pop to the queue's metrics, returning the error.
+    fn pop_error(&self, e: postgres::error::Error) -> BusError {
+        let error = BusError::Pop(e);
+        self.metrics.on_error(&self.name, &error);
+        error
+    }
+
+    /// Returns the messages that have been moved to the dead-letter table, oldest first.
+    /// Compressed bodies are decompressed where possible.
+    pub fn dead_letters(&self) -> BusResult<Vec<DeadLetter>> {
+        self.dead_letters_stmt
+            .query(&[], |rows| {
+                rows.iter()
+                    .map(|row| {
+                             let body: Vec<u8> = row.get("message");
+                             #[cfg(feature = "compression")]
+                             let body = compression::decompress(body.clone()).unwrap_or(body);
+                             DeadLetter::new(Message::with_id(row.get("message_id"), body),
+                                             row.get("error"))
+                         })
+                    .collect()
+            })
+            .map_err(BusError::DeadLetter)
+    }
+
+    /// Locks the next pending row using `stmt`, returning its id and raw message. Rows that can't
+    /// be read are dead-lettered so they don't block the queue.
+    fn lock_next(&self,
+                 stmt: &QueueStatement,
+                 params: &[&dyn ToSql])
+                 -> postgres::Result<Option<(i32, Message)>> {
         loop {
-            self.consume_pending_notifications()?;
-            self.consume_pending_items(&work_fn)?;
-            self.wait_for_next_notification()?;
+            if let Some(next) = stmt.query(params, |locked| self.check_locked(locked))?? {
+                return Ok(next);
+            }
         }
     }
 
-    /// Pops a message from the queue if there is one pending.
-    pub fn pop<E>(&self) -> Result<Option<B>, PopError<E>>
-        where B: FromMessageBody<E>
-    {
-        let locked = self.pop_stmt.query(&[]).map_err(|e| PopError::Pop(e))?;
+    /// Reads the row locked by `lock_next`, returning `None` if it was dead-lettered and the next
+    /// should be locked instead.
+    fn check_locked(&self, locked: &Rows) -> postgres::Result<Option<Option<(i32, Message)>>> {
         if locked.is_empty() {
             debug!("No message available in {}.{}", self.bus, self.name);
-            return Ok(None);
+            return Ok(Some(None));
         }
 
-        let locked_row = locked.get(0);
-        let _id: i32 = match locked_row.get_opt("id") {
-            None => {
-                warn!("No id column in {}.{}", self.bus, self.name);
+        let row = locked.get(0);
+        let (id, message) = match self.read_row(&row) {
+            Ok(next) => next,
+            Err((None, _)) => return Ok(Some(None)),
+            Err((Some(id), error)) => {
+                self.dead_letter_stmt.execute(&[&id, &error])?;
+                warn!("Dead-lettered unreadable message {} from {}.{}: {}",
+                      id,
+                      self.bus,
+                      self.name,
+                      error);
                 return Ok(None);
             }
-            Some(Err(e)) => {
-                warn!("Failed to convert id column value in {}.{}: {}",
+        };
+
+        if let (Some(max), Some(Ok(attempts))) =
+            (self.max_attempts, row.get_opt::<_, i32>("delivery_attempts")) {
+            if i64::from(attempts) > i64::from(max) {
+                self.dead_letter_stmt
+                    .execute(&[&id, &format!("Exceeded {} delivery attempts", max)])?;
+                warn!("Dead-lettered message {} from {}.{} after {} delivery attempts",
+                      id,
                       self.bus,
                       self.name,
-                      e);
+                      max);
                 return Ok(None);
             }
+        }
+        info!("Received message from {}.{}", self.bus, self.name);
+        Ok(Some(Some((id, message))))
+    }
+
+    /// Extracts the id and message from a locked row. If the row can't be read, returns its id,
+    /// when that much could be read, and the reason.
+    fn read_row(&self, row: &Row) -> Result<(i32, Message), (Option<i32>, String)> {
+        let id: i32 = match row.get_opt("id") {
+            None => return Err(self.unreadable(None, "No id column".to_string())),
+            Some(Err(e)) => {
+                return Err(self.unreadable(None,
+                                           format!("Failed to convert id column value: {}", e)))
+            }
             Some(Ok(r)) => r,
         };
 
-        let body: Vec<u8> = match locked_row.get_opt("message") {
-            None => {
-                warn!("No message column in {}.{}", self.bus, self.name);
-                return Ok(None);
-            }
+        let body: Vec<u8> = match row.get_opt("message") {
+            None => return Err(self.unreadable(Some(id), "No message column".to_string())),
             Some(Err(e)) => {
-                warn!("Failed to convert message column value in {}.{}: {}",
-                      self.bus,
-                      self.name,
-                      e);
-                return Ok(None);
+                return Err(self.unreadable(Some(id),
+                                           format!("Failed to convert message column value: {}",
+                                                   e)))
             }
             Some(Ok(r)) => r,
         };
 
-        let message = Message::new(body);
+        #[cfg(feature = "compression")]
+        let body = match compression::decompress(body) {
+            Err(e) => {
+                return Err(self.unreadable(Some(id),
+                                           format!("Failed to decompress message: {}", e)))
+            }
+            Ok(body) => body,
+        };
 
-        info!("Received message from {}.{}", self.bus, self.name);
+        let mut headers = HashMap::new();
+        if let (Some(Ok(keys)), Some(Ok(values))) =
+            (row.get_opt::<_, Vec<String>>("header_keys"),
+             row.get_opt::<_, Vec<String>>("header_values")) {
+            headers.extend(keys.into_iter().zip(values));
+        }
 
-        return Ok(Some(B::from_message_body(message).map_err(|e| PopError::BodyDeseralize(e))?));
+        Ok((id, Message::with_id(id, body).with_headers(headers)))
     }
 
+    /// Logs why a row couldn't be read, passing the details on.
+    fn unreadable(&self, id: Option<i32>, reason: String) -> (Option<i32>, String) {
+        warn!("{} in {}.{}", reason, self.bus, self.name);
+        (id, reason)
+    }
+
+    /// Returns the bus's connection, the latest if it has reconnected.
+    fn conn(&self) -> &'a Connection {
+        self.pqbus.conn.current()
+    }
+
+    #[cfg(not(feature = "polling-only"))]
     fn consume_pending_notifications(&self) -> BusResult<Option<Notification>> {
         let mut last = None;
-        while !&self.notifications.is_empty() {
-            last = self.handle_notification(self.notifications.iter())?;
+        if let Some(ref listener) = self.listener {
+            // Nothing else reads from the listener connection, so its notifications are still
+            // waiting to be read rather than buffered.
+            while let Some(Some(n)) =
+                self.wait_on_listener(listener, |n| self.handle_notification(n.iter()))? {
+                last = Some(n);
+            }
+            return Ok(last);
+        }
+        let notifications = self.conn().notifications();
+        while !notifications.is_empty() {
+            last = self.handle_notification(notifications.iter())?;
         }
         Ok(last)
     }
 
-    fn consume_pending_items<F, E>(&self, work_fn: F) -> Result<u32, BusError>
-        where F: Fn(B),
+    fn consume_pending_items<F, E>(&self,
+                                   work_fn: &mut F,
+                                   count: &mut u64)
+                                   -> Result<ControlFlow<()>, BusError>
+        where F: FnMut(B) -> ControlFlow<()>,
               B: FromMessageBody<E>,
               E: fmt::Display
+    {
+        while let Some(message) = self.pop()? {
+            *count += 1;
+            if work_fn(message).is_break() {
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn consume_pending_results<F, E, WE>(&self,
+                                         work_fn: F,
+                                         results: &Sender<ProcessResult>)
+                                         -> Result<u32, BusError>
+        where F: Fn(B) -> Result<(), WE>,
+              B: FromMessageBody<E>,
+              E: fmt::Display,
+              WE: fmt::Display
     {
         let mut i = 0;
         loop {
-            match self.pop()? {
+            let (id, obj) = match self.pop_with(B::from_message_body)? {
                 None => return Ok(i),
-                Some(message) => {
-                    work_fn(message);
-                    i += 1;
+                Some(next) => next,
+            };
+
+            let start = Instant::now();
+            let outcome = match work_fn(obj) {
+                Ok(()) => Outcome::Success,
+                Err(e) => {
+                    warn!("Failed to process message {} from {}.{}: {}",
+                          id,
+                          self.bus,
+                          self.name,
+                          e);
+                    Outcome::Failure(format!("{}", e))
                 }
+            };
+            let result = ProcessResult {
+                id: i64::from(id),
+                outcome,
+                duration: start.elapsed(),
+            };
+            if results.send(result).is_err() {
+                debug!("Result receiver for {}.{} has hung up", self.bus, self.name);
+            }
+            i += 1;
+        }
+    }
+
+    #[cfg(not(feature = "polling-only"))]
+    fn wait_for_next_notification(&self) -> BusResult<Option<Notification>> {
+        match self.listener {
+            Some(ref listener) => {
+                let n = self.wait_on_listener(listener,
+                                              |n| self.handle_notification(n.blocking_iter()))?;
+                Ok(n.unwrap_or(None))
+            }
+            None => {
+                let n = self.wait_on_bus(|n| self.handle_notification(n.blocking_iter()))?;
+                Ok(n.unwrap_or(None))
+            }
+        }
+    }
+
+    #[cfg(not(feature = "polling-only"))]
+    fn wait_for_notification(&self, timeout: Duration) -> BusResult<bool> {
+        match self.listener {
+            Some(ref listener) => {
+                let wait = |n: Notifications| self.handle_notification(n.timeout_iter(timeout));
+                let n = self.wait_on_listener(listener, wait)?;
+                // A replaced listener counts as woken, so the queue is checked again.
+                Ok(n.is_none_or(|n| n.is_some()))
+            }
+            None => {
+                let n = self.wait_on_bus(|n| self.handle_notification(n.timeout_iter(timeout)))?;
+                // A replaced connection counts as woken, as with a listener.
+                Ok(n.is_none_or(|n| n.is_some()))
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for a notification to any queue listening on the bus's connection.
+    #[cfg(not(feature = "polling-only"))]
+    fn wait_for_bus_notification(&self, timeout: Duration) -> BusResult<()> {
+        self.wait_on_bus(|n| self.handle_notification(n.timeout_iter(timeout)))?;
+        Ok(())
+    }
+
+    /// Waits for notifications on the bus's connection with `wait`, reconnecting the bus if the
+    /// connection has been lost. Returns `Ok(None)` once it has reconnected. See
+    /// `wait_on_listener`.
+    #[cfg(not(feature = "polling-only"))]
+    fn wait_on_bus<T, F>(&self, wait: F) -> BusResult<Option<T>>
+        where F: FnOnce(Notifications) -> BusResult<T>
+    {
+        let conn = self.conn();
+        match listener::read_notifications(conn, wait) {
+            Ok(t) => Ok(Some(t)),
+            Err(BusError::ReceiveNotification(e)) => {
+                self.pqbus.recover(conn, e).map_err(BusError::ReceiveNotification)?;
+                info!("Reconnected {}.{} to its bus", self.bus, self.name);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(not(feature = "polling-only"))]
+    fn has_listener(&self) -> bool {
+        self.listener.is_some()
+    }
+
+    /// Waits for notifications on the queue's own listener connection with `wait`, replacing
+    /// the connection if it has been lost. Returns `Ok(None)` once it has been replaced, as
+    /// messages pushed while nothing was listening can only be found by checking the queue.
+    #[cfg(not(feature = "polling-only"))]
+    fn wait_on_listener<T, F>(&self, listener: &Listener, wait: F) -> BusResult<Option<T>>
+        where F: FnOnce(Notifications) -> BusResult<T>
+    {
+        match listener.read(wait) {
+            Ok(t) => Ok(Some(t)),
+            Err(BusError::ReceiveNotification(e)) => {
+                warn!("Lost listener connection of {}.{}, reconnecting: {}",
+                      self.bus,
+                      self.name,
+                      e);
+                listener.reconnect()?;
+                info!("Reconnected listener of {}.{}", self.bus, self.name);
+                Ok(None)
             }
+            Err(e) => Err(e),
         }
     }
 
+    /// Wakes consumers listening on the queue. The payload is the id of the pushed message, or
+    /// the last of a batch, when there is one.
+    #[cfg(not(feature = "polling-only"))]
+    fn notify_push(&self, id: Option<i32>) -> postgres::Result<()> {
+        let payload = id.map(|id| id.to_string()).unwrap_or_default();
+        self.notify_stmt.query(&[&payload], |_| ())?;
+        debug!("Sent push notification to queue {}.{}: payload={}",
+               self.bus,
+               self.name,
+               payload);
+        Ok(())
+    }
+
+    #[cfg(feature = "polling-only")]
+    fn notify_push(&self, _id: Option<i32>) -> postgres::Result<()> {
+        Ok(())
+    }
+
+    #[cfg(feature = "polling-only")]
+    fn consume_pending_notifications(&self) -> BusResult<Option<Notification>> {
+        Ok(None)
+    }
+
+    #[cfg(feature = "polling-only")]
     fn wait_for_next_notification(&self) -> BusResult<Option<Notification>> {
-        Ok(self.handle_notification(self.notifications.blocking_iter())?)
+        loop {
+            thread::sleep(self.poll_interval);
+            if self.pending()? > 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Waits for the poll interval, or `timeout` if shorter, before the bus's queues are checked.
+    #[cfg(feature = "polling-only")]
+    fn wait_for_bus_notification(&self, timeout: Duration) -> BusResult<()> {
+        thread::sleep(cmp::min(timeout, self.poll_interval));
+        Ok(())
+    }
+
+    #[cfg(feature = "polling-only")]
+    fn has_listener(&self) -> bool {
+        false
+    }
+
+    #[cfg(feature = "polling-only")]
+    fn wait_for_notification(&self, timeout: Duration) -> BusResult<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            thread::sleep(cmp::min(deadline - now, self.poll_interval));
+            if self.pending()? > 0 {
+                return Ok(true);
+            }
+        }
     }
 
+    #[cfg(not(feature = "polling-only"))]
     fn handle_notification<N>(&self, mut n: N) -> BusResult<Option<Notification>>
         where N: Iterator<Item = postgres::Result<Notification>>
     {
@@ -387,34 +3468,160 @@ impl<'a, B> Queue<'a, B> {
                 Err(BusError::ReceiveNotification(e))
             }
             Some(Ok(n)) => {
-                debug!("Received push notification from {}.{}: pid={}, payload={}",
-                       self.bus,
-                       self.name,
-                       n.pid,
-                       n.payload);
+                match notification_id(&n) {
+                    Some(id) => {
+                        debug!("Received push notification from {}.{} for message {}: pid={}",
+                               self.bus,
+                               self.name,
+                               id,
+                               n.pid)
+                    }
+                    None => {
+                        debug!("Received push notification from {}.{}: pid={}, payload={}",
+                               self.bus,
+                               self.name,
+                               n.pid,
+                               n.payload)
+                    }
+                }
                 Ok(Some(n))
             }
         }
     }
 
     /// Returns an iterator over pending messages. Ends when the queue is empty.
-    pub fn messages<'queue, E>(&'queue self) -> MessageIter<'a, 'queue, NextMessagePending, B, E>
+    pub fn messages<'queue, E>(&'queue self)
+                               -> MessageIter<'a, 'queue, NextMessagePending, B, Converted<E>>
+        where B: FromMessageBody<E>
+    {
+        MessageIter::new(self, NextMessagePending {})
+    }
+
+    /// Returns an iterator over pending messages in batches of up to `batch_size`. Ends when the
+    /// queue is empty, so never yields an empty batch.
+    pub fn messages_batched<'q, E>(&'q self, batch_size: usize) -> BatchIter<'q, B, E>
         where B: FromMessageBody<E>
     {
+        BatchIter::new(self, batch_size)
+    }
+
+    /// Returns an iterator over pending messages as `pop_raw` pops them, without converting them
+    /// to the queue's message type. Ends when the queue is empty. Messages claimed by a
+    /// `Delivery` are not pending, so are left to their consumer.
+    pub fn raw_messages<'queue>(&'queue self)
+                                -> MessageIter<'a, 'queue, NextMessagePending, B, Raw> {
         MessageIter::new(self, NextMessagePending {})
     }
 
+    /// Returns an iterator over pending messages that leaves each one in the queue until its
+    /// `Delivery` is acknowledged. Ends when the queue is empty.
+    pub fn deliveries<'q, E>(&'q self) -> DeliveryIter<'q, B, E>
+        where B: FromMessageBody<E>
+    {
+        DeliveryIter::new(self)
+    }
+
     /// Returns an iterator over messages that blocks until a message is received if none are pending.
     /// This function never returns.
-    pub fn messages_blocking<'queue, E>(&'queue self)
-                                        -> MessageIter<'a, 'queue, NextMessageBlocking, B, E>
+    pub fn messages_blocking<'queue, E>
+        (&'queue self)
+         -> MessageIter<'a, 'queue, NextMessageBlocking, B, Converted<E>>
         where B: FromMessageBody<E>
     {
         MessageIter::new(self, NextMessageBlocking {})
     }
+
+    /// Returns an iterator over raw messages, as `raw_messages`, that blocks until a message is
+    /// received if none are pending. This function never returns.
+    pub fn raw_messages_blocking<'queue>(&'queue self)
+                                         -> MessageIter<'a, 'queue, NextMessageBlocking, B, Raw> {
+        MessageIter::new(self, NextMessageBlocking {})
+    }
+
+    /// Returns an iterator over messages that blocks until a message is received if none are
+    /// pending, ending after `max` messages or once `cancel` is set. See
+    /// `pop_blocking_cancellable`.
+    pub fn messages_blocking_limited<'queue, E>
+        (&'queue self,
+         max: usize,
+         cancel: &'queue AtomicBool)
+         -> MessageIter<'a, 'queue, NextMessageLimited<'queue>, B, Converted<E>>
+        where B: FromMessageBody<E>
+    {
+        MessageIter::new(self, NextMessageLimited::new(max, cancel))
+    }
+
+    /// Returns an iterator over raw messages, as `raw_messages`, that blocks until a message is
+    /// received if none are pending, ending after `max` messages or once `cancel` is set.
+    pub fn raw_messages_blocking_limited<'queue>
+        (&'queue self,
+         max: usize,
+         cancel: &'queue AtomicBool)
+         -> MessageIter<'a, 'queue, NextMessageLimited<'queue>, B, Raw> {
+        MessageIter::new(self, NextMessageLimited::new(max, cancel))
+    }
+}
+
+/// Stops listening for pushes once the last queue on the channel is dropped, so a connection
+/// outliving many short-lived queues does not keep receiving their notifications.
+#[cfg(not(feature = "polling-only"))]
+impl<'a, B> Drop for Queue<'a, B> {
+    fn drop(&mut self) {
+        // Queues with a listener of their own close it on drop instead, and those built
+        // without listening have nothing to stop.
+        if !self.listens {
+            return;
+        }
+        let mut listening = self.pqbus.listening.borrow_mut();
+        if let Some(count) = listening.get_mut(&self.channel) {
+            *count -= 1;
+            if *count > 0 {
+                return;
+            }
+        }
+        listening.remove(&self.channel);
+
+        // A connection lost to an IO error takes its registrations with it, and using it again
+        // trips a debug assertion in postgres.
+        let conn = self.conn();
+        if conn.is_desynchronized() {
+            return;
+        }
+        if let Err(e) = conn.execute(&format!("UNLISTEN {}", self.channel), &[]) {
+            warn!("Failed to stop listening to queue {}.{}: {}", self.bus, self.name, e);
+        }
+        let unlock = format!("SELECT pg_advisory_unlock_shared({})", LISTENER_LOCK);
+        if let Err(e) = conn.execute(&unlock, &[&self.channel]) {
+            warn!("Failed to release listener lock of queue {}.{}: {}",
+                  self.bus,
+                  self.name,
+                  e);
+        }
+    }
+}
+
+/// Bus, queue and consumer names, which are interpolated into SQL unquoted.
+static NAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[A-Za-z][A-Za-z0-9_]*$").expect("valid name regex"));
+
+/// Table names, which may be qualified with a schema.
+static TABLE_NAME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^([A-Za-z][A-Za-z0-9_]*\.)?[A-Za-z][A-Za-z0-9_]*$").expect("valid table regex")
+});
+
+fn invalid_name(n: &str) -> bool {
+    !NAME.is_match(n)
+}
+
+fn invalid_table_name(n: &str) -> bool {
+    !TABLE_NAME.is_match(n)
 }
 
-fn invalid_name(n: &String) -> bool {
-    let re = Regex::new(r"^[A-Za-z][A-Za-z0-9_]*$").unwrap();
-    !re.is_match(n)
+/// The reason a message that failed to convert to `T` is dead-lettered. Conversion errors aren't
+/// required to be printable, so only the type of the error is recorded. The error itself is
+/// returned to the caller.
+fn conversion_failed<T, E>() -> String {
+    format!("Failed to convert message to {}: {}",
+            any::type_name::<T>(),
+            any::type_name::<E>())
 }
@@ -0,0 +1,99 @@
+//! Connections queues listen for pushes on by themselves. See `QueueBuilder::reconnect_listener`.
+
+use postgres::Connection;
+use postgres::notification::Notifications;
+use std::cell::RefCell;
+use std::io;
+use crate::{hold_listener_lock, open_connection, set_application_name, BusError, BusResult, Ssl};
+
+/// A connection of a queue's own that listens on its channel, and can be replaced by a new one
+/// if it's lost.
+pub struct Listener {
+    uri: String,
+    ssl: Ssl,
+    channel: String,
+    application_name: Option<String>,
+    retry_attempts: u64,
+    retry_delay_ms: u64,
+    conn: RefCell<Connection>,
+}
+
+impl Listener {
+    /// Connects to `uri` with `ssl` as `application_name`, if given, and listens on `channel`,
+    /// retrying the connection as `new_with_retry`.
+    pub fn connect(uri: String,
+                   ssl: Ssl,
+                   channel: String,
+                   application_name: Option<String>,
+                   retry_attempts: u64,
+                   retry_delay_ms: u64)
+                   -> BusResult<Self> {
+        let conn = listen(&uri,
+                          ssl,
+                          &channel,
+                          application_name.as_deref(),
+                          retry_attempts,
+                          retry_delay_ms)?;
+        Ok(Listener {
+            uri,
+            ssl,
+            channel,
+            application_name,
+            retry_attempts,
+            retry_delay_ms,
+            conn: RefCell::new(conn),
+        })
+    }
+
+    /// Reads notifications from the connection with `read`. See `read_notifications`.
+    pub fn read<T, F>(&self, read: F) -> BusResult<T>
+        where F: FnOnce(Notifications) -> BusResult<T>
+    {
+        read_notifications(&self.conn.borrow(), read)
+    }
+
+    /// Replaces the connection with a new one listening on the same channel.
+    pub fn reconnect(&self) -> BusResult<()> {
+        let conn = listen(&self.uri,
+                          self.ssl,
+                          &self.channel,
+                          self.application_name.as_deref(),
+                          self.retry_attempts,
+                          self.retry_delay_ms)?;
+        *self.conn.borrow_mut() = conn;
+        Ok(())
+    }
+}
+
+/// Reads notifications from `conn` with `read`. A connection found lost, e.g. by an iterator
+/// returning an error as the server closes it, is reported as `BusError::ReceiveNotification`.
+pub fn read_notifications<T, F>(conn: &Connection, read: F) -> BusResult<T>
+    where F: FnOnce(Notifications) -> BusResult<T>
+{
+    // postgres refuses any further use of a connection that lost sync after an IO error.
+    if conn.is_desynchronized() {
+        return Err(lost("connection desynchronized"));
+    }
+    read(conn.notifications())
+}
+
+fn lost(reason: &str) -> BusError {
+    let lost = io::Error::new(io::ErrorKind::ConnectionAborted, reason);
+    BusError::ReceiveNotification(postgres::error::Error::Io(lost))
+}
+
+fn listen(uri: &str,
+          ssl: Ssl,
+          channel: &str,
+          application_name: Option<&str>,
+          attempts: u64,
+          delay_ms: u64)
+          -> BusResult<Connection> {
+    let conn = open_connection(uri, ssl, attempts, delay_ms)?;
+    if let Some(application_name) = application_name {
+        set_application_name(&conn, application_name)?;
+    }
+    hold_listener_lock(&conn, channel).map_err(BusError::Listen)?;
+    conn.execute(&format!("LISTEN {}", channel), &[]).map_err(BusError::Listen)?;
+    Ok(conn)
+}
@@ -1,5 +1,14 @@
 //! Built-in message types.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::str::{self, Utf8Error};
 use std::string::FromUtf8Error;
+use std::time::Duration;
+#[cfg(any(feature = "serde", feature = "bincode", feature = "cbor"))]
+use serde::Serialize;
+#[cfg(any(feature = "serde", feature = "bincode", feature = "cbor"))]
+use serde::de::DeserializeOwned;
+use crate::{BusResult, Queue};
 
 pub trait FromMessageBody<E> {
     fn from_message_body(m: Message) -> Result<Self, E> where Self: Sized;
@@ -9,26 +18,213 @@ pub trait ToMessageBody<E> {
     fn to_message_body(self) -> Result<Vec<u8>, E>;
 }
 
+/// Serializes a message without consuming it. See `Queue::push_ref`.
+pub trait ToMessageBodyRef<E> {
+    fn to_message_body_ref(&self) -> Result<Vec<u8>, E>;
+}
+
 /// Raw message format
+#[derive(Clone)]
 pub struct Message {
+    id: Option<i32>,
     body: Vec<u8>,
+    headers: HashMap<String, String>,
 }
 
 impl Message {
-    /// Construct a new message using body
+    /// Construct a new message using body. The message has no id until it is read from a queue.
     pub fn new(body: Vec<u8>) -> Self {
-        Message { body: body }
+        Message {
+            id: None,
+            body,
+            headers: HashMap::new(),
+        }
+    }
+    /// Construct a message read from the queue row `id`
+    pub fn with_id(id: i32, body: Vec<u8>) -> Self {
+        Message {
+            id: Some(id),
+            body,
+            headers: HashMap::new(),
+        }
+    }
+    /// Sets the headers the message was pushed with
+    pub fn with_headers(mut self, headers: HashMap<String, String>) -> Self {
+        self.headers = headers;
+        self
+    }
+    /// Get the id of the queue row the message was read from
+    pub fn id(&self) -> Option<i32> {
+        self.id
+    }
+    /// Get reference to the headers the message was pushed with
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
     }
     /// Get reference to body
     pub fn body(&self) -> &[u8] {
         &self.body
     }
+    /// Get the length of the body in bytes
+    pub fn len(&self) -> usize {
+        self.body.len()
+    }
+    /// Determines if the body is empty
+    pub fn is_empty(&self) -> bool {
+        self.body.is_empty()
+    }
+    /// Get the body as a string, if it is valid UTF-8
+    pub fn as_str(&self) -> Result<&str, Utf8Error> {
+        str::from_utf8(&self.body)
+    }
     /// Consumes the message returning it's body
     pub fn to_body(self) -> Vec<u8> {
         self.body
     }
 }
 
+/// Messages claimed together by a single `claim_batch` call.
+pub struct Batch<B> {
+    id: String,
+    messages: Vec<B>,
+}
+
+impl<B> Batch<B> {
+    /// Construct a new batch
+    pub fn new(id: String, messages: Vec<B>) -> Self {
+        Batch { id, messages }
+    }
+    /// Get the id shared by every message in the batch
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    /// Get reference to the messages
+    pub fn messages(&self) -> &[B] {
+        &self.messages
+    }
+    /// Consumes the batch returning it's messages
+    pub fn into_messages(self) -> Vec<B> {
+        self.messages
+    }
+}
+
+impl<B> IntoIterator for Batch<B> {
+    type Item = B;
+    type IntoIter = ::std::vec::IntoIter<B>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.messages.into_iter()
+    }
+}
+
+/// A message moved to a queue's dead-letter table because it could not be delivered.
+pub struct DeadLetter {
+    message: Message,
+    error: String,
+}
+
+impl DeadLetter {
+    /// Construct a new dead letter
+    pub fn new(message: Message, error: String) -> Self {
+        DeadLetter { message, error }
+    }
+    /// Get reference to the raw message. Its id is the one it had in the queue.
+    pub fn message(&self) -> &Message {
+        &self.message
+    }
+    /// Get the reason the message was dead-lettered
+    pub fn error(&self) -> &str {
+        &self.error
+    }
+    /// Consumes the dead letter returning it's message
+    pub fn into_message(self) -> Message {
+        self.message
+    }
+}
+
+/// A message popped from a queue that has not yet been acknowledged.
+///
+/// The message stays locked in the queue until `ack` deletes it or `nack` releases it. Dropping
+/// an unacknowledged delivery releases it.
+pub struct Delivery<'q, B: 'q> {
+    queue: &'q Queue<'q, B>,
+    id: i32,
+    message: B,
+    headers: HashMap<String, String>,
+    settled: bool,
+}
+
+impl<'q, B> Delivery<'q, B> {
+    /// Construct a new delivery of row `id` from `queue`
+    pub fn new(queue: &'q Queue<'q, B>, id: i32, message: B) -> Self {
+        Delivery {
+            queue,
+            id,
+            message,
+            headers: HashMap::new(),
+            settled: false,
+        }
+    }
+    /// Sets the headers the message was pushed with
+    pub fn with_headers(mut self, headers: HashMap<String, String>) -> Self {
+        self.headers = headers;
+        self
+    }
+    /// Get the id of the message's row
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+    /// Get reference to the message
+    pub fn message(&self) -> &B {
+        &self.message
+    }
+    /// Get reference to the headers the message was pushed with
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+    /// Deletes the message from the queue
+    pub fn ack(mut self) -> BusResult<()> {
+        self.settled = true;
+        self.queue.ack(self.id)
+    }
+    /// Releases the message so it can be delivered again
+    pub fn nack(mut self) -> BusResult<()> {
+        self.settled = true;
+        self.queue.nack(self.id)
+    }
+    /// Releases the message to the back of the queue, to be delivered again once `delay` has
+    /// passed. Unlike `nack`, other pending messages are delivered first. The message keeps its
+    /// id.
+    pub fn requeue(mut self, delay: Duration) -> BusResult<()> {
+        self.settled = true;
+        self.queue.requeue(self.id, delay)
+    }
+    /// Moves the time the message was claimed forward by `extra`, so `Queue::reclaim_expired`
+    /// leaves it locked for that much longer. Call it periodically while working on messages that
+    /// may take longer than the reclaim timeout. Returns false if the lock had already been
+    /// released, e.g. reclaimed, in which case the message may be delivered to another consumer.
+    pub fn extend_lock(&self, extra: Duration) -> BusResult<bool> {
+        self.queue.extend_lock(self.id, extra)
+    }
+}
+
+impl<'q, B> Drop for Delivery<'q, B> {
+    fn drop(&mut self) {
+        if self.settled {
+            return;
+        }
+        // Using a connection lost to an IO error again trips a debug assertion in postgres. The
+        // message stays claimed, for `Queue::reclaim_expired` to release.
+        if self.queue.conn().is_desynchronized() {
+            warn!("Unacknowledged message {} not released as the connection was lost", self.id);
+            return;
+        }
+        if let Err(e) = self.queue.nack(self.id) {
+            warn!("Failed to release unacknowledged message {}: {}", self.id, e);
+        }
+    }
+}
+
 impl ToMessageBody<FromUtf8Error> for String {
     fn to_message_body(self) -> Result<Vec<u8>, FromUtf8Error> {
         Ok(self.into())
@@ -41,6 +237,18 @@ impl<'a> ToMessageBody<FromUtf8Error> for &'a str {
     }
 }
 
+impl ToMessageBodyRef<FromUtf8Error> for String {
+    fn to_message_body_ref(&self) -> Result<Vec<u8>, FromUtf8Error> {
+        Ok(self.as_bytes().to_vec())
+    }
+}
+
+impl ToMessageBodyRef<FromUtf8Error> for &str {
+    fn to_message_body_ref(&self) -> Result<Vec<u8>, FromUtf8Error> {
+        Ok(self.as_bytes().to_vec())
+    }
+}
+
 impl FromMessageBody<FromUtf8Error> for String {
     fn from_message_body(m: Message) -> Result<Self, FromUtf8Error>
         where Self: Sized
@@ -49,6 +257,34 @@ impl FromMessageBody<FromUtf8Error> for String {
     }
 }
 
+impl ToMessageBody<Infallible> for Vec<u8> {
+    fn to_message_body(self) -> Result<Vec<u8>, Infallible> {
+        Ok(self)
+    }
+}
+
+impl ToMessageBodyRef<Infallible> for Vec<u8> {
+    fn to_message_body_ref(&self) -> Result<Vec<u8>, Infallible> {
+        Ok(self.clone())
+    }
+}
+
+impl FromMessageBody<Infallible> for Vec<u8> {
+    fn from_message_body(m: Message) -> Result<Self, Infallible>
+        where Self: Sized
+    {
+        Ok(m.to_body())
+    }
+}
+
+impl FromMessageBody<Infallible> for Message {
+    fn from_message_body(m: Message) -> Result<Self, Infallible>
+        where Self: Sized
+    {
+        Ok(m)
+    }
+}
+
 impl From<Message> for String {
     fn from(m: Message) -> String {
         let s = String::from_utf8(m.to_body()).unwrap();
@@ -61,3 +297,112 @@ impl From<String> for Message {
         Message::new(s.into())
     }
 }
+
+/// Wraps a serde type so it is sent over a queue as JSON.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "serde")]
+impl<T> Json<T> {
+    /// Consumes the wrapper returning the inner value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: Serialize> ToMessageBody<::serde_json::Error> for Json<T> {
+    fn to_message_body(self) -> Result<Vec<u8>, ::serde_json::Error> {
+        ::serde_json::to_vec(&self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: Serialize> ToMessageBodyRef<::serde_json::Error> for Json<T> {
+    fn to_message_body_ref(&self) -> Result<Vec<u8>, ::serde_json::Error> {
+        ::serde_json::to_vec(&self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: DeserializeOwned> FromMessageBody<::serde_json::Error> for Json<T> {
+    fn from_message_body(m: Message) -> Result<Self, ::serde_json::Error>
+        where Self: Sized
+    {
+        ::serde_json::from_slice(m.body()).map(Json)
+    }
+}
+
+/// Wraps a serde type so it is sent over a queue in bincode's compact binary encoding.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bincode<T>(pub T);
+
+#[cfg(feature = "bincode")]
+impl<T> Bincode<T> {
+    /// Consumes the wrapper returning the inner value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<T: Serialize> ToMessageBody<::bincode::Error> for Bincode<T> {
+    fn to_message_body(self) -> Result<Vec<u8>, ::bincode::Error> {
+        ::bincode::serialize(&self.0)
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<T: Serialize> ToMessageBodyRef<::bincode::Error> for Bincode<T> {
+    fn to_message_body_ref(&self) -> Result<Vec<u8>, ::bincode::Error> {
+        ::bincode::serialize(&self.0)
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<T: DeserializeOwned> FromMessageBody<::bincode::Error> for Bincode<T> {
+    fn from_message_body(m: Message) -> Result<Self, ::bincode::Error>
+        where Self: Sized
+    {
+        ::bincode::deserialize(m.body()).map(Bincode)
+    }
+}
+
+/// Wraps a serde type so it is sent over a queue as CBOR, which is compact like bincode but
+/// self-describing like JSON, so fields can be added to a type without breaking its consumers.
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cbor<T>(pub T);
+
+#[cfg(feature = "cbor")]
+impl<T> Cbor<T> {
+    /// Consumes the wrapper returning the inner value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl<T: Serialize> ToMessageBody<::serde_cbor::Error> for Cbor<T> {
+    fn to_message_body(self) -> Result<Vec<u8>, ::serde_cbor::Error> {
+        ::serde_cbor::to_vec(&self.0)
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl<T: Serialize> ToMessageBodyRef<::serde_cbor::Error> for Cbor<T> {
+    fn to_message_body_ref(&self) -> Result<Vec<u8>, ::serde_cbor::Error> {
+        ::serde_cbor::to_vec(&self.0)
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl<T: DeserializeOwned> FromMessageBody<::serde_cbor::Error> for Cbor<T> {
+    fn from_message_body(m: Message) -> Result<Self, ::serde_cbor::Error>
+        where Self: Sized
+    {
+        ::serde_cbor::from_slice(m.body()).map(Cbor)
+    }
+}
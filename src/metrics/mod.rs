@@ -0,0 +1,56 @@
+//! Hooks for observing queue activity.
+
+use std::cell::RefCell;
+use std::sync::Arc;
+use crate::BusError;
+
+/// Receives events from the queues it is registered on, e.g. to count them for a monitoring
+/// system. See `QueueBuilder::with_metrics`. Every hook does nothing by default.
+///
+/// Hooks are called on the consumer's thread while it works, so should return quickly.
+pub trait Metrics: Send + Sync {
+    /// A message was pushed to `queue`.
+    fn on_push(&self, _queue: &str) {}
+    /// A message was popped from `queue`, including messages claimed by `pop_delivery`.
+    fn on_pop(&self, _queue: &str) {}
+    /// A message that could not be delivered was moved to the dead-letter table of `queue`.
+    fn on_dead_letter(&self, _queue: &str) {}
+    /// Pushing to or popping from `queue` failed in the database.
+    fn on_error(&self, _queue: &str, _error: &BusError) {}
+}
+
+/// Metrics that ignore every event, used by queues built without any.
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// The metrics a queue reports to: those it was built with, and any added since, e.g. by
+/// `Queue::register_metrics`.
+pub(crate) struct Sinks(RefCell<Vec<Arc<dyn Metrics>>>);
+
+impl Sinks {
+    pub fn new(metrics: Arc<dyn Metrics>) -> Self {
+        Sinks(RefCell::new(vec![metrics]))
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub fn add(&self, metrics: Arc<dyn Metrics>) {
+        self.0.borrow_mut().push(metrics);
+    }
+
+    pub fn on_push(&self, queue: &str) {
+        self.0.borrow().iter().for_each(|m| m.on_push(queue));
+    }
+
+    pub fn on_pop(&self, queue: &str) {
+        self.0.borrow().iter().for_each(|m| m.on_pop(queue));
+    }
+
+    pub fn on_dead_letter(&self, queue: &str) {
+        self.0.borrow().iter().for_each(|m| m.on_dead_letter(queue));
+    }
+
+    pub fn on_error(&self, queue: &str, error: &BusError) {
+        self.0.borrow().iter().for_each(|m| m.on_error(queue, error));
+    }
+}
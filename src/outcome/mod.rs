@@ -0,0 +1,34 @@
+//! Message processing outcomes.
+use std::time::Duration;
+
+/// Whether a consumer successfully processed a message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// The work function completed.
+    Success,
+    /// The work function returned an error, described by the contained string.
+    Failure(String),
+}
+
+/// Reported by a consumer after it has processed a message.
+#[derive(Debug, Clone)]
+pub struct ProcessResult {
+    /// Id of the processed message.
+    pub id: i64,
+    /// Outcome of the work function.
+    pub outcome: Outcome,
+    /// Time spent in the work function.
+    pub duration: Duration,
+}
+
+/// How a `Queue::pop_wait_detailed` call ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WaitOutcome<B> {
+    /// A message was popped, either straight away or after waiting.
+    Message(B),
+    /// A push notification arrived, but another consumer took the message first or it was not
+    /// yet visible.
+    NotifiedButEmpty,
+    /// Nothing arrived before the timeout.
+    TimedOut,
+}
@@ -0,0 +1,117 @@
+//! Connection pooling.
+
+use postgres::Connection;
+use std::error::Error;
+use std::fmt;
+use crate::{default_application_name, set_application_name, BusConnection, BusError, BusResult,
+            Connections, PqBus, SharedQueue, Ssl, DEFAULT_RETRY_ATTEMPTS,
+            DEFAULT_RETRY_DELAY_MS};
+
+/// Opens postgres connections for the pool.
+pub struct ConnectionManager {
+    uri: String,
+    ssl: Ssl,
+    application_name: Option<String>,
+}
+
+/// Failure to open or validate a pooled connection.
+#[derive(Debug)]
+pub struct ManagerError(String);
+
+impl fmt::Display for ManagerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for ManagerError {}
+
+impl r2d2::ManageConnection for ConnectionManager {
+    type Connection = Connection;
+    type Error = ManagerError;
+
+    fn connect(&self) -> Result<Connection, ManagerError> {
+        let conn = Connection::connect(self.uri.as_ref(), self.ssl.mode())
+            .map_err(|e| ManagerError(format!("Unable to connect to {}: {}", self.uri, e)))?;
+        if let Some(ref application_name) = self.application_name {
+            set_application_name(&conn, application_name)
+                .map_err(|e| ManagerError(format!("{}", e)))?;
+        }
+        Ok(conn)
+    }
+
+    fn is_valid(&self, conn: &mut Connection) -> Result<(), ManagerError> {
+        conn.batch_execute("").map_err(|e| ManagerError(format!("{}", e)))
+    }
+
+    fn has_broken(&self, conn: &mut Connection) -> bool {
+        conn.is_desynchronized()
+    }
+}
+
+/// A pool of connections to a bus that can be shared between threads.
+///
+/// `get` checks a connection out as a `PqBus`, which returns it to the pool when dropped. Queues
+/// borrow the `PqBus` they are created from, so they can't outlive the checkout. Statements are
+/// cached per connection, so creating the same queue again on a reused connection doesn't prepare
+/// them again. A reused connection is still listening on the queues of its earlier holders, which
+/// can cause spurious wakeups in blocking pops.
+#[derive(Clone)]
+pub struct PqBusPool {
+    name: String,
+    uri: String,
+    ssl: Ssl,
+    pool: r2d2::Pool<ConnectionManager>,
+}
+
+impl PqBusPool {
+    /// Constructs a pool of up to `size` connections to the bus `name`, connecting with `ssl`.
+    pub fn new(uri: String, name: String, size: u32, ssl: Ssl) -> BusResult<Self> {
+        let pool = r2d2::Pool::builder()
+            .max_size(size)
+            .build(ConnectionManager {
+                       uri: uri.clone(),
+                       ssl,
+                       application_name: default_application_name(&uri, &name),
+                   })
+            .map_err(|e| BusError::Pool(format!("{}", e)))?;
+        info!("Created pool of {} connections to bus {}", size, name);
+        Ok(PqBusPool {
+               name,
+               uri,
+               ssl,
+               pool,
+           })
+    }
+
+    /// Checks out a connection, waiting for one to be returned if all are in use.
+    pub fn get(&self) -> BusResult<PqBus> {
+        let conn = self.pool.get().map_err(|e| BusError::Pool(format!("{}", e)))?;
+        Ok(PqBus {
+               name: self.name.clone(),
+               conn: Connections::new(BusConnection::Pooled(conn)),
+               uri: Some(self.uri.clone()),
+               ssl: self.ssl,
+               schema: None,
+               application_name: default_application_name(&self.uri, &self.name),
+               retry_attempts: DEFAULT_RETRY_ATTEMPTS,
+               retry_delay_ms: DEFAULT_RETRY_DELAY_MS,
+               #[cfg(not(feature = "polling-only"))]
+               listening: Default::default(),
+               next_select: Default::default(),
+           })
+    }
+
+    /// Constructs a queue on the pool's bus that can be shared between threads. See
+    /// `SharedQueue`.
+    pub fn shared_queue<B, N>(&self, name: N) -> BusResult<SharedQueue<B>>
+        where N: Into<String>
+    {
+        SharedQueue::new(self.clone(), name)
+    }
+
+    /// Returns the maximum number of connections in the pool.
+    pub fn max_size(&self) -> u32 {
+        self.pool.max_size()
+    }
+}
@@ -0,0 +1,113 @@
+//! Queues shared between threads.
+
+use std::marker::PhantomData;
+use std::time::Duration;
+use crate::{create_queue_tables, BusResult, FromMessageBody, PopError, PqBusPool, PushError, Queue,
+            QueueDef, QueueOptions, ToMessageBody};
+
+/// A queue that can be shared between threads, e.g. in an `Arc`.
+///
+/// `Queue` borrows its connection, which can only be used from one thread, so each call here
+/// checks a connection out of the pool and builds a lightweight queue on it for the length of the
+/// call. The tables are created and the queue's SQL formatted once, when the `SharedQueue` is.
+/// The queue only prepares the statement the call runs, in the connection's statement cache, so
+/// calls after the first on each connection only pay for the checkout. Only `pop_wait` listens
+/// for pushes, for as long as it waits.
+///
+/// The queue has the settings of a `QueueBuilder` left at its defaults. The builder's options,
+/// e.g. a maximum depth or deduplication, aren't supported, so a queue shared with queues built
+/// with them must not rely on them for pushes made here.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let pool = pqbus::new_pool("postgres://postgres@localhost/pqbus", "myapp", 8).unwrap();
+/// let queue = Arc::new(pool.shared_queue::<String, _>("jobs").unwrap());
+/// let producer = {
+///     let queue = queue.clone();
+///     thread::spawn(move || queue.push("job".to_string()))
+/// };
+/// producer.join().unwrap().unwrap();
+/// ```
+pub struct SharedQueue<B> {
+    pool: PqBusPool,
+    def: QueueDef,
+    // Only message bodies cross threads, and only through calls.
+    phantom: PhantomData<fn(B) -> B>,
+}
+
+impl<B> SharedQueue<B> {
+    /// Constructs the queue `name` on the pool's bus, creating its tables if needed.
+    pub fn new<N>(pool: PqBusPool, name: N) -> BusResult<Self>
+        where N: Into<String>
+    {
+        let name = name.into();
+        let def = {
+            let bus = pool.get()?;
+            let options = QueueOptions {
+                lazy_statements: true,
+                ..QueueOptions::default()
+            };
+            let def = QueueDef::new(&bus, &name, options)?;
+            create_queue_tables(bus.conn.current(),
+                                &def.table_name,
+                                &def.dlq_table_name,
+                                &def.bus,
+                                &def.name)?;
+            def
+        };
+        Ok(SharedQueue {
+            pool,
+            def,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Pushes a message into the queue. See `Queue::push`.
+    pub fn push<E>(&self, obj: B) -> Result<(), PushError<E>>
+        where B: ToMessageBody<E>
+    {
+        self.with_queue(|queue| queue.push(obj))?
+    }
+
+    /// Pops a message from the queue if there is one pending. See `Queue::pop`.
+    pub fn pop<E>(&self) -> Result<Option<B>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        self.with_queue(|queue| queue.pop())?
+    }
+
+    /// Pops a message from the queue, waiting up to `timeout` for one if there are none pending.
+    /// The connection stays checked out while waiting. See `Queue::pop_wait`.
+    pub fn pop_wait<E>(&self, timeout: Duration) -> Result<Option<B>, PopError<E>>
+        where B: FromMessageBody<E>
+    {
+        self.with_listening_queue(|queue| queue.pop_wait(timeout))?
+    }
+
+    /// Returns the number of messages in the queue. See `Queue::size`.
+    pub fn size(&self) -> BusResult<i64> {
+        self.with_queue(|queue| queue.size())?
+    }
+
+    /// Runs `f` on the queue built on a checked out connection, without listening for pushes.
+    fn with_queue<T, F>(&self, f: F) -> BusResult<T>
+        where F: FnOnce(&Queue<B>) -> T
+    {
+        let bus = self.pool.get()?;
+        let queue = Queue::from_def(&bus, &self.def, false, false)?;
+        Ok(f(&queue))
+    }
+
+    /// Runs `f` on the queue built on a checked out connection, listening for pushes to it.
+    fn with_listening_queue<T, F>(&self, f: F) -> BusResult<T>
+        where F: FnOnce(&Queue<B>) -> T
+    {
+        let bus = self.pool.get()?;
+        let queue = Queue::from_def(&bus, &self.def, true, false)?;
+        Ok(f(&queue))
+    }
+}
@@ -0,0 +1,81 @@
+//! Statements a queue prepares when it's built, or on first use. See
+//! `QueueBuilder::lazy_statements`.
+
+use postgres::Connection;
+use postgres::rows::Rows;
+use postgres::stmt::Statement;
+use postgres::types::ToSql;
+use std::io::Read;
+use std::ptr;
+use std::sync::Arc;
+use crate::PqBus;
+
+/// A statement of a queue on its bus's connection.
+///
+/// A statement prepared lazily isn't kept here but in the connection's statement cache, which
+/// prepares it the first time it's run and hands it back after that. Keeping it in a cell here
+/// would make `Queue` invariant over the lifetime of its bus. Once the bus has reconnected, the
+/// statement is prepared in the new connection's cache the same way.
+pub struct QueueStatement<'a> {
+    bus: &'a PqBus,
+    /// Connection `stmt` was prepared on.
+    conn: &'a Connection,
+    sql: Arc<str>,
+    stmt: Option<Statement<'a>>,
+}
+
+impl<'a> QueueStatement<'a> {
+    /// Prepares `sql` on the bus's connection now, or the first time it's run if `lazy`.
+    pub fn new(bus: &'a PqBus, sql: Arc<str>, lazy: bool) -> postgres::Result<Self> {
+        let conn = bus.conn.current();
+        let stmt = if lazy { None } else { Some(conn.prepare_cached(&sql)?) };
+        Ok(QueueStatement {
+            bus,
+            conn,
+            sql,
+            stmt,
+        })
+    }
+
+    /// Runs the statement, passing the rows it produces to `read`.
+    pub fn query<T, F>(&self, params: &[&dyn ToSql], mut read: F) -> postgres::Result<T>
+        where F: FnMut(&Rows) -> T
+    {
+        self.run(|stmt| stmt.query(params).map(|rows| read(&rows)))
+    }
+
+    /// Runs the statement, returning the number of rows it modified.
+    pub fn execute(&self, params: &[&dyn ToSql]) -> postgres::Result<u64> {
+        self.run(|stmt| stmt.execute(params))
+    }
+
+    /// Runs the statement, a `COPY ... FROM STDIN`, with the data read from `data`. Not retried,
+    /// as the data read before the connection was lost can't be read again.
+    pub fn copy_in<R: Read>(&self, params: &[&dyn ToSql], data: &mut R) -> postgres::Result<u64> {
+        self.run_on(self.bus.conn.current(), |stmt| stmt.copy_in(params, data))
+    }
+
+    /// Runs the statement with `run`, retrying once on a new connection if the bus reconnects
+    /// after losing its connection.
+    fn run<T, F>(&self, mut run: F) -> postgres::Result<T>
+        where F: FnMut(&Statement) -> postgres::Result<T>
+    {
+        let conn = self.bus.conn.current();
+        match self.run_on(conn, &mut run) {
+            Err(e) => {
+                self.bus.recover(conn, e)?;
+                self.run_on(self.bus.conn.current(), run)
+            }
+            result => result,
+        }
+    }
+
+    fn run_on<T, F>(&self, conn: &Connection, run: F) -> postgres::Result<T>
+        where F: FnOnce(&Statement) -> postgres::Result<T>
+    {
+        match self.stmt {
+            Some(ref stmt) if ptr::eq(conn, self.conn) => run(stmt),
+            _ => run(&conn.prepare_cached(&self.sql)?),
+        }
+    }
+}
@@ -0,0 +1,14 @@
+//! Queue statistics.
+
+/// Counts of the messages in a queue by state, taken at the same instant. See `Queue::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Messages a consumer could pop now.
+    pub pending: i64,
+    /// Messages claimed by a consumer and not yet acknowledged.
+    pub in_flight: i64,
+    /// Messages pushed with a delay that has not yet passed.
+    pub delayed: i64,
+    /// All messages in the queue.
+    pub total: i64,
+}
@@ -0,0 +1,68 @@
+//! Message bodies streamed into and out of a queue. See `Queue::push_reader` and
+//! `Queue::pop_to_writer`.
+
+use std::cmp;
+use std::io::{self, Read};
+
+/// Bytes of a body read at a time, and read from the database at a time by `pop_to_writer`.
+pub const CHUNK_SIZE: usize = 1 << 20;
+
+/// Reads a body from `body` as a single-column row of `COPY ... FROM STDIN` in text format,
+/// escaping it as hex a chunk at a time so it is never held in memory whole.
+pub struct CopyRow<R> {
+    body: R,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> CopyRow<R> {
+    pub fn new(body: R) -> Self {
+        CopyRow {
+            body,
+            // The `\x` starting bytea's hex format, with its backslash escaped for COPY.
+            buf: b"\\\\x".to_vec(),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Replaces the buffer with the next chunk of the body in hex, or the end of the row.
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = vec![0; CHUNK_SIZE / 2];
+        let read = loop {
+            match self.body.read(&mut chunk) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        self.buf.clear();
+        self.pos = 0;
+        if read == 0 {
+            self.buf.push(b'\n');
+            self.done = true;
+        }
+        for byte in &chunk[..read] {
+            self.buf.push(HEX[usize::from(byte >> 4)]);
+            self.buf.push(HEX[usize::from(byte & 0xf)]);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for CopyRow<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let n = cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
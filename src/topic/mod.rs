@@ -0,0 +1,195 @@
+//! Publishing to every queue bound to a topic.
+
+use crate::{create_queue_tables, invalid_name, returned_id, QueueTransaction};
+use crate::{BusError, BusResult, PqBus, PushError, Queue, ToMessageBody};
+#[cfg(not(feature = "polling-only"))]
+use crate::channel_name;
+
+/// A named topic on a bus. Messages published to it are pushed into each queue bound to it at
+/// the time, so the consumers of every bound queue receive their own copy.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let bus = pqbus::new("postgres://postgres@localhost/pqbus", "myapp").unwrap();
+/// let topic = bus.topic("signups").unwrap();
+/// topic.bind("welcome_emails").unwrap();
+/// topic.bind("analytics").unwrap();
+/// topic.publish("sgibbs").unwrap();
+/// ```
+pub struct Topic<'a> {
+    bus: &'a PqBus,
+    name: String,
+    bindings_table: String,
+}
+
+impl<'a> Topic<'a> {
+    /// Constructs the topic `name` on `bus`, creating the bus's bindings table if needed.
+    pub fn new<N>(bus: &'a PqBus, name: N) -> BusResult<Self>
+        where N: Into<String>
+    {
+        let name = name.into();
+        if invalid_name(&name) {
+            return Err(BusError::InvalidTopicName(name));
+        }
+
+        let bindings_table = bus.qualify(format!("pqbus_{}_topic_bindings", bus.name));
+        // A queue bound as built with `bind_queue` may have its own table and channel. Both are
+        // null for queues bound by name.
+        bus.conn
+            .batch_execute(&format!(r#"
+                CREATE TABLE IF NOT EXISTS {t} (
+                    topic VARCHAR NOT NULL,
+                    queue VARCHAR NOT NULL,
+                    PRIMARY KEY (topic, queue)
+                );
+                ALTER TABLE {t}
+                ADD COLUMN IF NOT EXISTS table_name VARCHAR DEFAULT NULL,
+                ADD COLUMN IF NOT EXISTS channel VARCHAR DEFAULT NULL
+                "#,
+                                    t = bindings_table))
+            .map_err(BusError::Topic)?;
+
+        Ok(Topic {
+            bus,
+            name,
+            bindings_table,
+        })
+    }
+
+    /// Binds the queue `queue` to the topic, creating the queue if needed. It receives messages
+    /// published from now on, but none published before. Publishes go to the queue's default
+    /// table and channel. See `bind_queue` for a queue built with others.
+    pub fn bind<N>(&self, queue: N) -> BusResult<()>
+        where N: Into<String>
+    {
+        let queue = queue.into();
+        if invalid_name(&queue) {
+            return Err(BusError::InvalidQueueName(queue));
+        }
+
+        create_queue_tables(&self.bus.conn,
+                            &self.bus.table_name(&queue),
+                            &self.bus.dlq_table_name(&queue),
+                            &self.bus.name,
+                            &queue)?;
+        self.record_binding(&queue, None, None)
+    }
+
+    /// Binds `queue` to the topic as it was built, so messages published from now on go to its
+    /// table and wake consumers on its channel, e.g. one set with `QueueBuilder::notify_channel`.
+    ///
+    /// Published messages are inserted as they are, so queues built with compression,
+    /// deduplication or a maximum depth are refused, as their pushes do more than insert.
+    pub fn bind_queue<B>(&self, queue: &Queue<B>) -> BusResult<()> {
+        #[cfg(feature = "compression")]
+        let compresses = queue.compression.is_some();
+        #[cfg(not(feature = "compression"))]
+        let compresses = false;
+        if compresses || queue.dedup || queue.max_depth.is_some() {
+            return Err(BusError::Generic(format!("Can't bind {}.{} to topic {} as it \
+                                                  compresses, deduplicates or limits the depth \
+                                                  of its messages",
+                                                 queue.bus,
+                                                 queue.name,
+                                                 self.name)));
+        }
+        #[cfg(not(feature = "polling-only"))]
+        let channel = Some(queue.channel.as_str());
+        #[cfg(feature = "polling-only")]
+        let channel = None;
+        self.record_binding(&queue.name, Some(&queue.table_name), channel)
+    }
+
+    fn record_binding(&self,
+                      queue: &str,
+                      table_name: Option<&str>,
+                      channel: Option<&str>)
+                      -> BusResult<()> {
+        self.bus
+            .conn
+            .execute(&format!("INSERT INTO {} (topic, queue, table_name, channel) \
+                               VALUES ($1, $2, $3, $4) \
+                               ON CONFLICT (topic, queue) DO UPDATE \
+                               SET table_name = excluded.table_name, channel = excluded.channel",
+                              self.bindings_table),
+                     &[&self.name, &queue, &table_name, &channel])
+            .map_err(BusError::Topic)?;
+        info!("Bound queue {}.{} to topic {}", self.bus.name, queue, self.name);
+        Ok(())
+    }
+
+    /// Stops publishing to the queue `queue`. Messages already pushed to it are left in place.
+    pub fn unbind<N>(&self, queue: N) -> BusResult<()>
+        where N: Into<String>
+    {
+        let queue = queue.into();
+        self.bus
+            .conn
+            .execute(&format!("DELETE FROM {} WHERE topic = $1 AND queue = $2",
+                              self.bindings_table),
+                     &[&self.name, &queue])
+            .map_err(BusError::Topic)?;
+        info!("Unbound queue {}.{} from topic {}", self.bus.name, queue, self.name);
+        Ok(())
+    }
+
+    /// Returns the names of the queues bound to the topic.
+    pub fn bindings(&self) -> BusResult<Vec<String>> {
+        let rows = self.bus
+            .conn
+            .query(&format!("SELECT queue FROM {} WHERE topic = $1 ORDER BY queue",
+                            self.bindings_table),
+                   &[&self.name])
+            .map_err(BusError::Topic)?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Pushes a copy of `obj` into every bound queue in a single transaction, waking each
+    /// queue's consumers. Returns the number of queues it was pushed to.
+    ///
+    /// The bindings are read in the transaction and held until it ends, so a queue unbound
+    /// meanwhile waits for the publish, and one bound meanwhile receives only later messages.
+    pub fn publish<T, E>(&self, obj: T) -> Result<u64, PushError<E>>
+        where T: ToMessageBody<E>
+    {
+        let body = obj.to_message_body().map_err(PushError::BodySeralize)?;
+
+        let conn = &self.bus.conn;
+        let trans = QueueTransaction::begin(conn).map_err(PushError::from)?;
+        let bindings = conn.query(&format!("SELECT queue, table_name, channel FROM {} \
+                                            WHERE topic = $1 ORDER BY queue FOR SHARE",
+                                           self.bindings_table),
+                                  &[&self.name])
+            .map_err(BusError::Topic)?;
+        let queues: Vec<String> = bindings.iter().map(|row| row.get("queue")).collect();
+        for (queue, row) in queues.iter().zip(bindings.iter()) {
+            let table_name = row.get::<_, Option<String>>("table_name")
+                .unwrap_or_else(|| self.bus.table_name(queue));
+            let rows = conn.query(&format!("INSERT INTO {} (message) VALUES ($1) RETURNING id",
+                                           table_name),
+                                  &[&body])
+                .map_err(PushError::from)?;
+            let id = returned_id(&rows).map_err(PushError::from)?;
+            #[cfg(not(feature = "polling-only"))]
+            {
+                let channel = row.get::<_, Option<String>>("channel")
+                    .unwrap_or_else(|| channel_name(&table_name));
+                conn.execute("SELECT pg_notify($1, $2)", &[&channel, &id.to_string()])
+                    .map_err(PushError::from)?;
+            }
+            debug!("Message {} published to queue {}.{} from topic {}",
+                   id,
+                   self.bus.name,
+                   queue,
+                   self.name);
+        }
+        trans.commit().map_err(PushError::from)?;
+        info!("Message published to {} queues from topic {}.{}",
+              queues.len(),
+              self.bus.name,
+              self.name);
+
+        Ok(queues.len() as u64)
+    }
+}
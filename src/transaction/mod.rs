@@ -0,0 +1,67 @@
+//! Transactions a queue does its work in, nested in the caller's if one is open.
+
+use postgres::Connection;
+use postgres::transaction::Transaction;
+
+/// A transaction on a queue's connection, or a savepoint if a transaction is already open on it,
+/// e.g. one the caller pushes to with `Queue::push_in_transaction`.
+///
+/// postgres panics on beginning a transaction while another is open, and the open one isn't at
+/// hand to take a savepoint from, so the savepoint is taken with SQL. Statements are run on the
+/// connection itself. Rolled back when dropped, unless committed.
+pub struct QueueTransaction<'a> {
+    conn: &'a Connection,
+    trans: Option<Transaction<'a>>,
+    open: bool,
+}
+
+impl<'a> QueueTransaction<'a> {
+    /// Begins a transaction on `conn`, or a savepoint in the one already open.
+    pub fn begin(conn: &'a Connection) -> postgres::Result<Self> {
+        // A connection is active while it has no transaction open.
+        let trans = if conn.is_active() {
+            Some(conn.transaction()?)
+        } else {
+            conn.batch_execute("SAVEPOINT pqbus")?;
+            None
+        };
+        Ok(QueueTransaction {
+            conn,
+            trans,
+            open: true,
+        })
+    }
+
+    /// Commits the transaction, or releases the savepoint into the enclosing transaction.
+    pub fn commit(mut self) -> postgres::Result<()> {
+        self.open = false;
+        match self.trans.take() {
+            Some(trans) => trans.commit(),
+            None => self.conn.batch_execute("RELEASE SAVEPOINT pqbus"),
+        }
+    }
+
+    /// Rolls back the transaction, or the savepoint, leaving the enclosing transaction usable.
+    pub fn finish(mut self) -> postgres::Result<()> {
+        self.open = false;
+        match self.trans.take() {
+            Some(trans) => trans.finish(),
+            None => rollback_savepoint(self.conn),
+        }
+    }
+}
+
+impl<'a> Drop for QueueTransaction<'a> {
+    fn drop(&mut self) {
+        // A transaction of our own rolls itself back when dropped.
+        if self.open && self.trans.is_none() {
+            if let Err(e) = rollback_savepoint(self.conn) {
+                warn!("Failed to roll back savepoint: {}", e);
+            }
+        }
+    }
+}
+
+fn rollback_savepoint(conn: &Connection) -> postgres::Result<()> {
+    conn.batch_execute("ROLLBACK TO SAVEPOINT pqbus; RELEASE SAVEPOINT pqbus")
+}
@@ -1,18 +1,36 @@
+#![allow(clippy::needless_borrowed_reference)]
+
 extern crate pqbus;
 extern crate env_logger;
 extern crate postgres;
 extern crate retry;
+#[cfg(feature = "prometheus")]
+extern crate prometheus;
+#[cfg(any(feature = "serde", feature = "bincode", feature = "cbor"))]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(any(feature = "bincode", feature = "cbor"))]
+extern crate serde_json;
 
 use postgres::{Connection, SslMode};
+use postgres::io::{NegotiateSsl, Stream, StreamWrapper};
 use retry::retry;
 
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use std::env;
-use std::sync::{Arc, Mutex};
+use std::error::Error;
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::ops::ControlFlow;
 use std::str::FromStr;
 use std::thread;
+use std::string::FromUtf8Error;
 
-use pqbus::{Queue, BusError};
+use pqbus::{ConnectionFailure, DeleteFilter, PqBus, Queue, BusError, CallbackError, PopError,
+            PushError, Outcome, FromMessageBody, ToMessageBodyRef, Message, Metrics, Ssl,
+            WaitOutcome};
 
 struct TestInit;
 
@@ -45,6 +63,57 @@ fn drop_table(name: &str) {
     conn().unwrap().execute(&format!("drop table if exists {} cascade", name), &[]).unwrap();
 }
 
+/// Forwards connections to the test database, and kills them on demand by closing both ends,
+/// so their backends exit and clients find them closed. postgres panics on reading the error a
+/// backend sends as `pg_terminate_backend` kills it during a wait for notifications.
+struct Proxy {
+    uri: String,
+    conns: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Proxy {
+    fn start() -> Proxy {
+        let uri = db_uri();
+        let host_start = uri.find('@').map_or_else(|| uri.find("://").unwrap() + 3, |i| i + 1);
+        let host_end = host_start + uri[host_start..].find('/').unwrap();
+        let upstream = match &uri[host_start..host_end] {
+            host if host.contains(':') => host.to_string(),
+            host => format!("{}:5432", host),
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let conns = Arc::new(Mutex::new(Vec::new()));
+        let accepted = conns.clone();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = client.unwrap();
+                let server = TcpStream::connect(&upstream).unwrap();
+                for (from, to) in [(&client, &server), (&server, &client)] {
+                    let (mut from, mut to) = (from.try_clone().unwrap(), to.try_clone().unwrap());
+                    thread::spawn(move || {
+                        let _ = std::io::copy(&mut from, &mut to);
+                        let _ = to.shutdown(Shutdown::Write);
+                    });
+                }
+                accepted.lock().unwrap().extend(vec![client, server]);
+            }
+        });
+
+        Proxy {
+            uri: format!("{}127.0.0.1:{}{}", &uri[..host_start], port, &uri[host_end..]),
+            conns,
+        }
+    }
+
+    /// Kills every connection forwarded so far.
+    fn kill(&self) {
+        for conn in self.conns.lock().unwrap().drain(..) {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+    }
+}
+
 #[test]
 fn test_connect_fail() {
     test_setup();
@@ -62,6 +131,45 @@ fn test_valid_bus_name() {
     }
 }
 
+#[test]
+fn test_bus_name_validation() {
+    test_setup();
+    // Names are checked before connecting, so valid ones fail on the unreachable uri instead.
+    for name in &["a", "work", "Work_2", "a_b_c", "Z9"] {
+        match pqbus::new_with_retry("postgres://postgres@127.0.0.1:1/pqbus_test", *name, 1, 0) {
+            Err(BusError::Connection(..)) => (),
+            _ => panic!("expected {} to be valid", name),
+        }
+    }
+    for name in &["", "1a", "_a", "bad-name", "a b", "a.b", "a;drop", "é"] {
+        match pqbus::new(db_uri(), *name) {
+            Err(BusError::InvalidBusName(ref n)) if n == name => (),
+            _ => panic!("expected {} to be invalid", name),
+        }
+    }
+}
+
+#[test]
+fn test_error_equality() {
+    test_setup();
+    let invalid = pqbus::new(db_uri(), "bad-name").err().unwrap();
+    assert_eq!(BusError::InvalidBusName("bad-name".to_string()), invalid);
+    assert_eq!(pqbus::new(db_uri(), "bad-name").err().unwrap(), invalid);
+    assert_ne!(BusError::InvalidBusName("other-name".to_string()), invalid);
+    assert_ne!(BusError::InvalidQueueName("bad-name".to_string()), invalid);
+
+    drop_table("pqbus_error_equality_a_queue");
+    let bus = pqbus::new(db_uri(), "error_equality").unwrap();
+    let queue: Queue<String> = bus.queue_builder("a").with_max_depth(1).build().unwrap();
+    queue.push("1".to_string()).unwrap();
+    assert_eq!(Err(PushError::QueueFull), queue.push("2".to_string()));
+
+    // Postgres errors compare by SQLSTATE.
+    let sql = |q| bus.connection().execute(q, &[]).map_err(BusError::Sql);
+    assert_eq!(sql("SELECT 1/0"), sql("SELECT 1/(1-1)"));
+    assert_ne!(sql("SELECT 1/0"), sql("SELECT * FROM no_such_table"));
+}
+
 #[test]
 fn test_valid_queue_name() {
     test_setup();
@@ -263,6 +371,44 @@ fn test_multithread_push_pop_many() {
     }
 }
 
+#[test]
+fn test_concurrent_deliveries_exactly_once() {
+    test_setup();
+    drop_table("pqbus_concurrent_deliveries_a_queue");
+    let bus = pqbus::new(db_uri(), "concurrent_deliveries").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+
+    let message_count = 500;
+    let worker_count = 10;
+    for i in 0..message_count {
+        queue.push(format!("{}", i)).unwrap();
+    }
+
+    let results = Arc::new(Mutex::new(vec![]));
+    let threads: Vec<_> = (0..worker_count)
+        .map(|_| {
+            let results = results.clone();
+            thread::spawn(move || {
+                let bus = pqbus::new(db_uri(), "concurrent_deliveries").unwrap();
+                let queue: Queue<String> = bus.queue("a").unwrap();
+                while let Some(delivery) = queue.pop_delivery::<FromUtf8Error>().unwrap() {
+                    let n: i32 = FromStr::from_str(delivery.message()).unwrap();
+                    delivery.ack().unwrap();
+                    results.lock().unwrap().push(n);
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    let mut results = results.lock().unwrap();
+    results.sort();
+    assert_eq!((0..message_count).collect::<Vec<i32>>(), *results);
+    assert!(queue.is_empty().unwrap());
+}
+
 #[test]
 fn test_pop_wait_none() {
     test_setup();
@@ -319,6 +465,25 @@ fn test_messages_iter_nth() {
     assert_eq!("3", &third.unwrap());
 }
 
+#[test]
+fn test_messages_batched() {
+    test_setup();
+    drop_table("pqbus_batched_a_queue");
+    let bus = pqbus::new(db_uri(), "batched").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    for i in 0..25 {
+        queue.push(i.to_string()).unwrap();
+    }
+
+    let batches: Vec<Vec<String>> =
+        queue.messages_batched::<FromUtf8Error>(10).map(|b| b.unwrap()).collect();
+    let sizes: Vec<usize> = batches.iter().map(|b| b.len()).collect();
+    assert_eq!(vec![10, 10, 5], sizes);
+    assert_eq!("0", batches[0][0]);
+    assert_eq!("24", batches[2][4]);
+    assert_eq!(0, queue.size().unwrap());
+}
+
 #[test]
 fn test_messages_waiting_iter() {
     test_setup();
@@ -339,3 +504,3029 @@ fn test_messages_waiting_iter() {
 
     assert_eq!(4, i);
 }
+
+#[test]
+fn test_wake_and_count() {
+    test_setup();
+    drop_table("pqbus_wake_and_count_a_queue");
+    let bus = pqbus::new(db_uri(), "wake_and_count").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    assert!(queue.is_empty().unwrap());
+
+    let producer = thread::spawn(|| {
+        let bus = pqbus::new(db_uri(), "wake_and_count").unwrap();
+        let queue = bus.queue("a").unwrap();
+        queue.push("1".to_string()).unwrap();
+        queue.push("2".to_string()).unwrap();
+        queue.push("3".to_string()).unwrap();
+    });
+    producer.join().unwrap();
+
+    assert_eq!(3, queue.wake_and_count(Duration::new(2, 0)).unwrap());
+}
+
+#[test]
+fn test_pop_deadline() {
+    test_setup();
+    drop_table("pqbus_pop_deadline_a_queue");
+    let bus = pqbus::new(db_uri(), "pop_deadline").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+
+    let start = Instant::now();
+    assert_eq!(None, queue.pop_deadline(start + Duration::from_millis(500)).unwrap());
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(500));
+    assert!(elapsed < Duration::from_secs(3));
+
+    let producer = thread::spawn(|| {
+        thread::sleep(Duration::from_millis(200));
+        let bus = pqbus::new(db_uri(), "pop_deadline").unwrap();
+        let queue = bus.queue("a").unwrap();
+        queue.push("1".to_string()).unwrap();
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    assert_eq!(Some("1".to_string()), queue.pop_deadline(deadline).unwrap());
+    producer.join().unwrap();
+}
+
+#[test]
+fn test_pop_deadline_under_notification_storm() {
+    test_setup();
+    drop_table("pqbus_notify_storm_a_queue");
+    let bus = pqbus::new(db_uri(), "notify_storm").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+
+    // Notifications without a message each wake the consumer to find the queue still empty.
+    let stop = Arc::new(AtomicBool::new(false));
+    let notifier = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let c = conn().unwrap();
+            while !stop.load(Ordering::SeqCst) {
+                c.execute("SELECT pg_notify('pqbus_notify_storm_a_queue', '')", &[]).unwrap();
+                thread::sleep(Duration::from_millis(20));
+            }
+        })
+    };
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_millis(500);
+    assert_eq!(None, queue.pop_deadline::<FromUtf8Error>(deadline).unwrap());
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(500));
+    assert!(elapsed < Duration::from_millis(800), "waited {:?}", elapsed);
+
+    // An elapsed deadline checks once without waiting.
+    queue.push("1".to_string()).unwrap();
+    assert_eq!(Some("1".to_string()), queue.pop_deadline(start).unwrap());
+    let start = Instant::now();
+    assert_eq!(None, queue.pop_deadline::<FromUtf8Error>(start).unwrap());
+    assert!(start.elapsed() < Duration::from_millis(100));
+
+    stop.store(true, Ordering::SeqCst);
+    notifier.join().unwrap();
+}
+
+struct Tracked {
+    id: i32,
+    body: String,
+}
+
+impl FromMessageBody<FromUtf8Error> for Tracked {
+    fn from_message_body(m: Message) -> Result<Self, FromUtf8Error> {
+        let id = m.id().expect("popped message has an id");
+        Ok(Tracked {
+               id,
+               body: String::from_utf8(m.to_body())?,
+           })
+    }
+}
+
+#[test]
+fn test_popped_message_ids() {
+    test_setup();
+    drop_table("pqbus_message_ids_a_queue");
+    let bus = pqbus::new(db_uri(), "message_ids").unwrap();
+    let producer: Queue<String> = bus.queue("a").unwrap();
+    for i in 0..5 {
+        producer.push(format!("{}", i)).unwrap();
+    }
+
+    let queue: Queue<Tracked> = bus.queue("a").unwrap();
+    let mut last = 0;
+    for i in 0..5 {
+        let tracked = queue.pop().unwrap().unwrap();
+        assert_eq!(format!("{}", i), tracked.body);
+        assert!(tracked.id > last);
+        last = tracked.id;
+    }
+    assert!(queue.pop().unwrap().is_none());
+}
+
+#[test]
+fn test_push_delayed() {
+    test_setup();
+    drop_table("pqbus_push_delayed_a_queue");
+    let bus = pqbus::new(db_uri(), "push_delayed").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+
+    queue.push_delayed("later".to_string(), Duration::from_secs(2)).unwrap();
+    assert_eq!(1, queue.size().unwrap());
+    assert_eq!(0, queue.pending().unwrap());
+    assert_eq!(None, queue.pop().unwrap());
+    assert_eq!(None, queue.peek().unwrap());
+
+    thread::sleep(Duration::from_millis(2100));
+    assert_eq!(1, queue.pending().unwrap());
+    assert_eq!(Some("later".to_string()), queue.pop().unwrap());
+}
+
+#[test]
+fn test_visible_at_migration() {
+    test_setup();
+    drop_table("pqbus_visible_at_migration_a_queue");
+    conn()
+        .unwrap()
+        .execute("CREATE TABLE pqbus_visible_at_migration_a_queue (id SERIAL PRIMARY KEY, \
+                  message bytea NOT NULL, lock VARCHAR DEFAULT NULL)",
+                 &[])
+        .unwrap();
+    conn()
+        .unwrap()
+        .execute("INSERT INTO pqbus_visible_at_migration_a_queue (message) VALUES ('old')",
+                 &[])
+        .unwrap();
+
+    let bus = pqbus::new(db_uri(), "visible_at_migration").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    assert_eq!(Some("old".to_string()), queue.pop().unwrap());
+}
+
+struct Picky(String);
+
+/// A conversion error that can't be displayed, as pops don't require it.
+#[derive(Debug, PartialEq)]
+struct Rejected(Vec<u8>);
+
+impl FromMessageBody<Rejected> for Picky {
+    fn from_message_body(m: Message) -> Result<Self, Rejected> {
+        let body = m.to_body();
+        match String::from_utf8(body.clone()) {
+            Ok(ref s) if s == "bad" => Err(Rejected(body)),
+            Ok(s) => Ok(Picky(s)),
+            Err(_) => Err(Rejected(body)),
+        }
+    }
+}
+
+#[test]
+fn test_dead_letter_on_deserialize_failure() {
+    test_setup();
+    drop_table("pqbus_dead_letter_a_queue");
+    drop_table("pqbus_dead_letter_a_dlq");
+    let bus = pqbus::new(db_uri(), "dead_letter").unwrap();
+    let producer: Queue<String> = bus.queue("a").unwrap();
+    producer.push("bad".to_string()).unwrap();
+    producer.push("good".to_string()).unwrap();
+
+    let queue: Queue<Picky> = bus.queue("a").unwrap();
+    let mut popped = vec![];
+    let mut errors = vec![];
+    loop {
+        match queue.pop() {
+            Ok(None) => break,
+            Ok(Some(Picky(body))) => popped.push(body),
+            Err(PopError::BodyDeseralize(e)) => errors.push(e),
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
+    }
+    assert_eq!(vec!["good".to_string()], popped);
+    assert_eq!(vec![Rejected(b"bad".to_vec())], errors);
+    assert!(queue.is_empty().unwrap());
+
+    let dead = queue.dead_letters().unwrap();
+    assert_eq!(1, dead.len());
+    assert_eq!(b"bad", dead[0].message().body());
+    assert!(dead[0].message().id().is_some());
+    assert_eq!("Failed to convert message to test::Picky: test::Rejected", dead[0].error());
+}
+
+#[test]
+fn test_max_attempts_dead_letters() {
+    test_setup();
+    drop_table("pqbus_max_attempts_a_queue");
+    drop_table("pqbus_max_attempts_a_dlq");
+    let bus = pqbus::new(db_uri(), "max_attempts").unwrap();
+    let queue: Queue<String> = bus.queue_builder("a").max_attempts(3).build().unwrap();
+    queue.push("flaky".to_string()).unwrap();
+
+    // Peeking is not a delivery.
+    for _ in 0..5 {
+        assert_eq!(Some("flaky".to_string()), queue.peek().unwrap());
+    }
+
+    for _ in 0..3 {
+        let delivery = queue.pop_delivery().unwrap().unwrap();
+        assert_eq!("flaky", delivery.message());
+        delivery.nack().unwrap();
+    }
+
+    assert!(queue.pop_delivery().unwrap().is_none());
+    assert!(queue.is_empty().unwrap());
+    let dead = queue.dead_letters().unwrap();
+    assert_eq!(1, dead.len());
+    assert_eq!(b"flaky", dead[0].message().body());
+    assert_eq!("Exceeded 3 delivery attempts", dead[0].error());
+}
+
+#[test]
+fn test_priority_pop_order() {
+    test_setup();
+    drop_table("pqbus_priority_a_queue");
+    drop_table("pqbus_priority_b_queue");
+    let bus = pqbus::new(db_uri(), "priority").unwrap();
+
+    let queue: Queue<String> = bus.queue_builder("a").priority().build().unwrap();
+    for p in &[1, 5, 3] {
+        queue.push_with_priority(format!("{}", p), *p).unwrap();
+    }
+    assert_eq!(Some("5".to_string()), queue.peek().unwrap());
+    assert_eq!(Some("5".to_string()), queue.pop().unwrap());
+    assert_eq!(Some("3".to_string()), queue.pop().unwrap());
+    assert_eq!(Some("1".to_string()), queue.pop().unwrap());
+
+    // Plain queues stay first in, first out.
+    let fifo: Queue<String> = bus.queue("b").unwrap();
+    for p in &[1, 5, 3] {
+        fifo.push_with_priority(format!("{}", p), *p).unwrap();
+    }
+    assert_eq!(Some("1".to_string()), fifo.pop().unwrap());
+    assert_eq!(Some("5".to_string()), fifo.pop().unwrap());
+    assert_eq!(Some("3".to_string()), fifo.pop().unwrap());
+}
+
+#[test]
+fn test_lifo_pop_order() {
+    test_setup();
+    drop_table("pqbus_lifo_fifo_queue");
+    drop_table("pqbus_lifo_lifo_queue");
+    drop_table("pqbus_lifo_priority_queue");
+    let bus = pqbus::new(db_uri(), "lifo").unwrap();
+
+    let fifo: Queue<String> =
+        bus.queue_builder("fifo").ordering(pqbus::Ordering::Fifo).build().unwrap();
+    let lifo: Queue<String> =
+        bus.queue_builder("lifo").ordering(pqbus::Ordering::Lifo).build().unwrap();
+    for i in 1..4 {
+        fifo.push(i.to_string()).unwrap();
+        lifo.push(i.to_string()).unwrap();
+    }
+    for i in 1..4 {
+        assert_eq!(Some(i.to_string()), fifo.pop().unwrap());
+    }
+    assert_eq!(Some("3".to_string()), lifo.peek().unwrap());
+    for i in (1..4).rev() {
+        assert_eq!(Some(i.to_string()), lifo.pop().unwrap());
+    }
+
+    // Priority still comes first, with the newest of equal priority next.
+    let queue: Queue<String> =
+        bus.queue_builder("priority").priority().ordering(pqbus::Ordering::Lifo).build().unwrap();
+    for (body, p) in &[("a", 1), ("b", 5), ("c", 1), ("d", 5)] {
+        queue.push_with_priority(body.to_string(), *p).unwrap();
+    }
+    let popped: Vec<String> = (0..4).map(|_| queue.pop().unwrap().unwrap()).collect();
+    assert_eq!(vec!["d", "b", "c", "a"], popped);
+}
+
+#[test]
+fn test_push_returning_id() {
+    test_setup();
+    drop_table("pqbus_push_returning_id_a_queue");
+    let bus = pqbus::new(db_uri(), "push_returning_id").unwrap();
+    let queue: Queue<Tracked> = bus.queue("a").unwrap();
+    let producer: Queue<String> = bus.queue("a").unwrap();
+
+    let ids: Vec<i32> = (0..3)
+        .map(|i| producer.push_returning_id(format!("{}", i)).unwrap())
+        .collect();
+    assert!(ids[0] < ids[1]);
+    assert!(ids[1] < ids[2]);
+
+    for id in ids {
+        assert_eq!(id, queue.pop().unwrap().unwrap().id);
+    }
+}
+
+#[test]
+fn test_delivery_requeue() {
+    test_setup();
+    drop_table("pqbus_requeue_a_queue");
+    drop_table("pqbus_requeue_lifo_queue");
+    let bus = pqbus::new(db_uri(), "requeue").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    queue.push("1".to_string()).unwrap();
+    queue.push("2".to_string()).unwrap();
+
+    let delivery = queue.pop_delivery::<FromUtf8Error>().unwrap().unwrap();
+    assert_eq!("1", delivery.message());
+    let id = delivery.id();
+    delivery.requeue(Duration::from_secs(1)).unwrap();
+
+    assert_eq!(Some("2".to_string()), queue.pop().unwrap());
+    assert_eq!(None, queue.pop::<FromUtf8Error>().unwrap());
+    assert_eq!(1, queue.size().unwrap());
+
+    thread::sleep(Duration::from_millis(1100));
+    let delivery = queue.pop_delivery::<FromUtf8Error>().unwrap().unwrap();
+    assert_eq!("1", delivery.message());
+    assert_eq!(id, delivery.id());
+    delivery.ack().unwrap();
+
+    queue.push("3".to_string()).unwrap();
+    queue.push("4".to_string()).unwrap();
+    let delivery = queue.pop_delivery::<FromUtf8Error>().unwrap().unwrap();
+    delivery.requeue(Duration::from_secs(0)).unwrap();
+    queue.push("5".to_string()).unwrap();
+    assert_eq!(Some("4".to_string()), queue.pop().unwrap());
+    assert_eq!(Some("3".to_string()), queue.pop().unwrap());
+    assert_eq!(Some("5".to_string()), queue.pop().unwrap());
+
+    // A LIFO queue pops the newest first, so the back is behind the oldest.
+    let lifo: Queue<String> =
+        bus.queue_builder("lifo").ordering(pqbus::Ordering::Lifo).build().unwrap();
+    for i in 1..4 {
+        lifo.push(format!("{}", i)).unwrap();
+    }
+    let delivery = lifo.pop_delivery::<FromUtf8Error>().unwrap().unwrap();
+    assert_eq!("3", delivery.message());
+    delivery.requeue(Duration::from_secs(0)).unwrap();
+    assert_eq!(Some("2".to_string()), lifo.pop().unwrap());
+    assert_eq!(Some("1".to_string()), lifo.pop().unwrap());
+    assert_eq!(Some("3".to_string()), lifo.pop().unwrap());
+}
+
+#[test]
+fn test_deliveries_redeliver_unacked() {
+    test_setup();
+    drop_table("pqbus_deliveries_a_queue");
+    let bus = pqbus::new(db_uri(), "deliveries").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    for i in 0..3 {
+        queue.push(format!("{}", i)).unwrap();
+    }
+
+    for (i, delivery) in queue.deliveries().enumerate() {
+        let delivery = delivery.unwrap();
+        if i < 2 {
+            assert_eq!(&format!("{}", i), delivery.message());
+            delivery.ack().unwrap();
+        } else {
+            assert_eq!("2", delivery.message());
+            drop(delivery);
+            break;
+        }
+    }
+
+    assert_eq!(1, queue.size().unwrap());
+    let mut again = queue.deliveries();
+    let redelivered = again.next().unwrap().unwrap();
+    assert_eq!("2", redelivered.message());
+    redelivered.ack().unwrap();
+    assert!(again.next().is_none());
+    assert!(queue.is_empty().unwrap());
+}
+
+#[test]
+fn test_consumer_id_lock() {
+    test_setup();
+    drop_table("pqbus_consumer_id_a_queue");
+    let bus = pqbus::new(db_uri(), "consumer_id").unwrap();
+    let queue: Queue<String> = bus.queue_builder("a").consumer_id("worker_1").build().unwrap();
+    queue.push("1".to_string()).unwrap();
+
+    let delivery = queue.pop_delivery().unwrap().unwrap();
+    let rows = bus.connection()
+        .query("SELECT lock FROM pqbus_consumer_id_a_queue WHERE id = $1",
+               &[&delivery.id()])
+        .unwrap();
+    let lock: String = rows.get(0).get("lock");
+    assert_eq!("worker_1", lock);
+    delivery.ack().unwrap();
+
+    let invalid = bus.queue_builder("a").consumer_id("bad id").build::<String>();
+    match invalid {
+        Err(BusError::InvalidConsumerId(id)) => assert_eq!("bad id", id),
+        _ => panic!("expected InvalidConsumerId"),
+    }
+}
+
+#[test]
+fn test_reclaim_expired() {
+    test_setup();
+    drop_table("pqbus_reclaim_a_queue");
+    let bus = pqbus::new(db_uri(), "reclaim").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    queue.push("1".to_string()).unwrap();
+
+    // Simulate a consumer that crashes before acknowledging.
+    ::std::mem::forget(queue.pop_delivery().unwrap().unwrap());
+    assert_eq!(None, queue.pop().unwrap());
+    assert_eq!(0, queue.reclaim_expired(Duration::from_secs(1)).unwrap());
+
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(1, queue.reclaim_expired(Duration::from_secs(1)).unwrap());
+    assert_eq!(Some("1".to_string()), queue.pop().unwrap());
+}
+
+#[test]
+fn test_delivery_extend_lock() {
+    test_setup();
+    drop_table("pqbus_extend_lock_a_queue");
+    let bus = pqbus::new(db_uri(), "extend_lock").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    queue.push("1".to_string()).unwrap();
+
+    let delivery = queue.pop_delivery::<FromUtf8Error>().unwrap().unwrap();
+    thread::sleep(Duration::from_millis(600));
+    assert!(delivery.extend_lock(Duration::from_secs(1)).unwrap());
+
+    // Past the original timeout, but not the extended one.
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(0, queue.reclaim_expired(Duration::from_secs(1)).unwrap());
+    assert_eq!(None, queue.pop().unwrap());
+    delivery.ack().unwrap();
+    assert!(queue.is_empty().unwrap());
+
+    // A reclaimed message can no longer be extended.
+    queue.push("2".to_string()).unwrap();
+    let delivery = queue.pop_delivery::<FromUtf8Error>().unwrap().unwrap();
+    assert_eq!(1, queue.reclaim_expired(Duration::from_secs(0)).unwrap());
+    assert!(!delivery.extend_lock(Duration::from_secs(1)).unwrap());
+}
+
+#[cfg(feature = "pool")]
+#[test]
+fn test_pool_with_ssl() {
+    test_setup();
+    let pool = pqbus::new_pool_with_ssl(db_uri(), "pool_ssl", 1, Ssl::None).unwrap();
+    pool.get().unwrap().reconnect().unwrap();
+
+    // Pooled connections negotiate SSL as asked, where the server supports it. Every attempt is
+    // refused, so building the pool waits out its connection timeout, and is left to fail.
+    static COUNTED: CountSsl = CountSsl(AtomicUsize::new(0));
+    thread::spawn(|| pqbus::new_pool_with_ssl(db_uri(), "pool_ssl", 1, Ssl::Require(&COUNTED)));
+    thread::sleep(Duration::from_millis(500));
+    let ssl: String = conn().unwrap().query("SHOW ssl", &[]).unwrap().get(0).get(0);
+    if ssl == "on" {
+        assert!(COUNTED.0.load(Ordering::SeqCst) > 0);
+    }
+}
+
+#[cfg(feature = "pool")]
+#[test]
+fn test_pool_shared_between_threads() {
+    test_setup();
+    drop_table("pqbus_pool_a_queue");
+    let pool = pqbus::new_pool(db_uri(), "pool", 4).unwrap();
+    assert_eq!(4, pool.max_size());
+
+    let producers: Vec<_> = (0..8)
+        .map(|t| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                let bus = pool.get().unwrap();
+                let queue = bus.queue("a").unwrap();
+                for i in 0..10 {
+                    queue.push(format!("{}-{}", t, i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for p in producers {
+        p.join().unwrap();
+    }
+
+    let received = Arc::new(Mutex::new(vec![]));
+    let consumers: Vec<_> = (0..8)
+        .map(|_| {
+            let pool = pool.clone();
+            let received = received.clone();
+            thread::spawn(move || {
+                let bus = pool.get().unwrap();
+                let queue: Queue<String> = bus.queue("a").unwrap();
+                while let Some(m) = queue.pop().unwrap() {
+                    received.lock().unwrap().push(m);
+                }
+            })
+        })
+        .collect();
+    for c in consumers {
+        c.join().unwrap();
+    }
+
+    let mut received = received.lock().unwrap().clone();
+    received.sort();
+    received.dedup();
+    assert_eq!(80, received.len());
+}
+
+#[cfg(feature = "pool")]
+#[test]
+fn test_shared_queue_between_threads() {
+    test_setup();
+    drop_table("pqbus_shared_a_queue");
+    let pool = pqbus::new_pool(db_uri(), "shared", 4).unwrap();
+    let queue: Arc<pqbus::SharedQueue<String>> = Arc::new(pool.shared_queue("a").unwrap());
+
+    let received = Arc::new(Mutex::new(vec![]));
+    let workers: Vec<_> = (0..6)
+        .map(|t| {
+            let queue = queue.clone();
+            let received = received.clone();
+            thread::spawn(move || {
+                for i in 0..10 {
+                    queue.push(format!("{}-{}", t, i)).unwrap();
+                    if let Some(m) = queue.pop::<FromUtf8Error>().unwrap() {
+                        received.lock().unwrap().push(m);
+                    }
+                }
+            })
+        })
+        .collect();
+    for w in workers {
+        w.join().unwrap();
+    }
+    while let Some(m) = queue.pop::<FromUtf8Error>().unwrap() {
+        received.lock().unwrap().push(m);
+    }
+
+    let mut received = received.lock().unwrap().clone();
+    received.sort();
+    received.dedup();
+    assert_eq!(60, received.len());
+    assert_eq!(0, queue.size().unwrap());
+}
+
+#[cfg(feature = "pool")]
+#[test]
+fn test_shared_queue_pop_wait() {
+    test_setup();
+    drop_table("pqbus_shared_wait_a_queue");
+    let pool = pqbus::new_pool(db_uri(), "shared_wait", 2).unwrap();
+    let queue: Arc<pqbus::SharedQueue<String>> = Arc::new(pool.shared_queue("a").unwrap());
+
+    let consumer = {
+        let queue = queue.clone();
+        thread::spawn(move || {
+            let start = Instant::now();
+            let m = queue.pop_wait::<FromUtf8Error>(Duration::from_secs(5)).unwrap();
+            (m, start.elapsed())
+        })
+    };
+    thread::sleep(Duration::from_millis(300));
+    queue.push("woken".to_string()).unwrap();
+
+    let (m, waited) = consumer.join().unwrap();
+    assert_eq!(Some("woken".to_string()), m);
+    assert!(waited < Duration::from_secs(4));
+}
+
+#[test]
+fn test_reconnect_after_connection_loss() {
+    test_setup();
+    drop_table("pqbus_reconnect_a_queue");
+    let proxy = Proxy::start();
+    let bus = pqbus::new(proxy.uri.clone(), "reconnect").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    queue.push("before".to_string()).unwrap();
+
+    let backend_pid = |bus: &PqBus| -> i32 {
+        bus.connection().query("SELECT pg_backend_pid() AS pid", &[]).unwrap().get(0).get("pid")
+    };
+    let terminate = |pid: i32| {
+        conn().unwrap().execute("SELECT pg_terminate_backend($1)", &[&pid]).unwrap();
+    };
+
+    // The push fails on the lost connection, and is retried once the queue has reconnected.
+    let pid = backend_pid(&bus);
+    terminate(pid);
+    queue.push("after".to_string()).unwrap();
+    assert_ne!(pid, backend_pid(&bus));
+
+    assert_eq!(Some("before".to_string()), queue.pop().unwrap());
+    assert_eq!(Some("after".to_string()), queue.pop().unwrap());
+
+    // A pop blocked on the lost connection reconnects, and listens on the queue again.
+    let pid = backend_pid(&bus);
+    let pusher = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        proxy.kill();
+        thread::sleep(Duration::from_millis(500));
+        let bus = pqbus::new(db_uri(), "reconnect").unwrap();
+        let queue: Queue<String> = bus.queue("a").unwrap();
+        queue.push("pushed".to_string()).unwrap();
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    assert_eq!(Some("pushed".to_string()), queue.pop_deadline(deadline).unwrap());
+    pusher.join().unwrap();
+    assert_ne!(pid, backend_pid(&bus));
+
+    bus.reconnect().unwrap();
+    assert!(queue.is_empty().unwrap());
+
+    let err = bus.queue::<_, String>("not valid").err().unwrap();
+    assert!(!err.is_connection_error());
+
+    // A bus given its connection can't reconnect, so the error stays.
+    let bus = PqBus::from_connection(conn().unwrap(), "reconnect").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    terminate(backend_pid(&bus));
+    let err = queue.push("lost".to_string()).unwrap_err();
+    assert!(err.is_connection_error());
+}
+
+#[test]
+fn test_from_connection() {
+    test_setup();
+    drop_table("pqbus_from_connection_a_queue");
+    let c = conn().unwrap();
+    c.execute("SET application_name = 'pqbus_from_connection'", &[]).unwrap();
+    let bus = PqBus::from_connection(c, "from_connection").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+
+    queue.push("1".to_string()).unwrap();
+    assert_eq!(Some("1".to_string()), queue.pop().unwrap());
+
+    let child = thread::spawn(|| {
+        let bus = PqBus::from_connection(conn().unwrap(), "from_connection").unwrap();
+        let queue: Queue<String> = bus.queue("a").unwrap();
+        queue.pop_blocking::<FromUtf8Error>()
+    });
+    thread::sleep(Duration::from_millis(200));
+    queue.push("2".to_string()).unwrap();
+    assert_eq!("2", child.join().unwrap().unwrap());
+
+    match PqBus::from_connection(conn().unwrap(), "bad name") {
+        Err(BusError::InvalidBusName(_)) => (),
+        _ => panic!("expected InvalidBusName"),
+    }
+}
+
+#[test]
+fn test_ping() {
+    test_setup();
+    let bus = pqbus::new(db_uri(), "ping").unwrap();
+    bus.ping().unwrap();
+    assert!(bus.is_connected());
+
+    let rows = bus.connection().query("SELECT pg_backend_pid() AS pid", &[]).unwrap();
+    let pid: i32 = rows.get(0).get("pid");
+    conn().unwrap().execute("SELECT pg_terminate_backend($1)", &[&pid]).unwrap();
+
+    assert!(bus.ping().unwrap_err().is_connection_error());
+    assert!(!bus.is_connected());
+}
+
+#[test]
+fn test_cancel_blocking_pops() {
+    test_setup();
+    drop_table("pqbus_cancel_a_queue");
+    let cancel = Arc::new(AtomicBool::new(false));
+
+    let flag = cancel.clone();
+    let consumer = thread::spawn(move || {
+        let bus = pqbus::new(db_uri(), "cancel").unwrap();
+        let queue: Queue<String> = bus.queue("a").unwrap();
+        let mut received = vec![];
+        let count = queue.pop_callback_cancellable::<_, FromUtf8Error>(|m| received.push(m), &flag)
+            .unwrap();
+        assert_eq!(None, queue.pop_blocking_cancellable::<FromUtf8Error>(&flag).unwrap());
+        (count, received)
+    });
+
+    let bus = pqbus::new(db_uri(), "cancel").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    queue.push("1".to_string()).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    cancel.store(true, Ordering::SeqCst);
+    let (count, received) = consumer.join().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(1, count);
+    assert_eq!(vec!["1".to_string()], received);
+
+    cancel.store(false, Ordering::SeqCst);
+    queue.push("2".to_string()).unwrap();
+    assert_eq!(Some("2".to_string()),
+               queue.pop_blocking_cancellable::<FromUtf8Error>(&cancel).unwrap());
+}
+
+#[test]
+fn test_pump() {
+    test_setup();
+    drop_table("pqbus_pump_a_queue");
+    let stop = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+
+    let flag = stop.clone();
+    let pump = thread::spawn(move || {
+        let bus = pqbus::new(db_uri(), "pump").unwrap();
+        let mut queue: Queue<String> = bus.queue("a").unwrap();
+        queue.set_cancel_check_interval(Duration::from_millis(100));
+        queue.pump::<FromUtf8Error>(tx, flag)
+    });
+
+    let bus = pqbus::new(db_uri(), "pump").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    for i in 0..3 {
+        queue.push(i.to_string()).unwrap();
+    }
+    let received: Vec<String> = rx.iter().take(3).map(|m| m.unwrap()).collect();
+    assert_eq!(vec!["0", "1", "2"], received);
+
+    let start = Instant::now();
+    stop.store(true, Ordering::SeqCst);
+    assert_eq!(3, pump.join().unwrap());
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert!(rx.recv().is_err());
+}
+
+#[test]
+fn test_messages_blocking_limited() {
+    test_setup();
+    drop_table("pqbus_blocking_limited_a_queue");
+    let bus = pqbus::new(db_uri(), "blocking_limited").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    for i in 0..4 {
+        queue.push(i.to_string()).unwrap();
+    }
+
+    let cancel = AtomicBool::new(false);
+    let messages: Vec<String> = queue.messages_blocking_limited::<FromUtf8Error>(3, &cancel)
+        .map(|m| m.unwrap())
+        .collect();
+    assert_eq!(vec!["0", "1", "2"], messages);
+    assert_eq!(1, queue.size().unwrap());
+
+    queue.pop::<FromUtf8Error>().unwrap();
+    let flag = Arc::new(AtomicBool::new(false));
+    let canceller = {
+        let flag = flag.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            flag.store(true, Ordering::SeqCst);
+        })
+    };
+    let start = Instant::now();
+    assert_eq!(0, queue.messages_blocking_limited::<FromUtf8Error>(3, &flag).count());
+    assert!(start.elapsed() < Duration::from_secs(1));
+    canceller.join().unwrap();
+}
+
+#[test]
+fn test_pop_callback_until() {
+    test_setup();
+    drop_table("pqbus_pop_callback_until_a_queue");
+    let bus = pqbus::new(db_uri(), "pop_callback_until").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    for i in 0..5 {
+        queue.push(format!("{}", i)).unwrap();
+    }
+
+    let mut seen = vec![];
+    let processed = queue.pop_callback_until(|m| {
+                                                 seen.push(m);
+                                                 if seen.len() == 3 {
+                                                     ControlFlow::Break(())
+                                                 } else {
+                                                     ControlFlow::Continue(())
+                                                 }
+                                             })
+        .unwrap();
+    assert_eq!(3, processed);
+    assert_eq!(vec!["0", "1", "2"], seen);
+    assert_eq!(2, queue.size().unwrap());
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_compression() {
+    use pqbus::Compression;
+
+    test_setup();
+    drop_table("pqbus_compression_a_queue");
+    let bus = pqbus::new(db_uri(), "compression").unwrap();
+    let payload = "{\"name\": \"sgibbs\"}".repeat(60000);
+    assert!(payload.len() > 1024 * 1024);
+
+    // Rows pushed before compression was enabled still pop.
+    let plain: Queue<String> = bus.queue("a").unwrap();
+    plain.push("legacy".to_string()).unwrap();
+
+    for codec in &[Compression::Gzip, Compression::Zstd] {
+        let queue: Queue<String> = bus.queue_builder("a").with_compression(*codec).build().unwrap();
+        queue.push(payload.clone()).unwrap();
+    }
+
+    let rows = bus.connection()
+        .query("SELECT octet_length(message) AS len FROM pqbus_compression_a_queue ORDER BY id",
+               &[])
+        .unwrap();
+    let lens: Vec<i32> = rows.iter().map(|r| r.get("len")).collect();
+    assert_eq!(6, lens[0]);
+    assert!(lens[1] < 64 * 1024, "gzip stored {} bytes", lens[1]);
+    assert!(lens[2] < 64 * 1024, "zstd stored {} bytes", lens[2]);
+
+    assert_eq!(Some("legacy".to_string()), plain.pop().unwrap());
+    assert_eq!(Some(payload.clone()), plain.pop().unwrap());
+    assert_eq!(Some(payload), plain.pop().unwrap());
+}
+
+#[cfg(not(feature = "polling-only"))]
+#[test]
+fn test_notify_payload_is_message_id() {
+    test_setup();
+    drop_table("pqbus_notify_payload_a_queue");
+    let bus = pqbus::new(db_uri(), "notify_payload").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+
+    let listener = conn().unwrap();
+    listener.execute("LISTEN pqbus_notify_payload_a_queue", &[]).unwrap();
+    let id = queue.push_returning_id("1".to_string()).unwrap();
+
+    let notifications = listener.notifications();
+    let n = notifications.timeout_iter(Duration::from_secs(2)).next().unwrap().unwrap();
+    assert_eq!(Ok(id), n.payload.parse());
+}
+
+#[cfg(not(feature = "polling-only"))]
+#[test]
+fn test_notify_channel() {
+    test_setup();
+    drop_table("pqbus_notify_channel_a_queue");
+    drop_table("pqbus_notify_channel_b_queue");
+    let bus = pqbus::new(db_uri(), "notify_channel").unwrap();
+    match bus.queue_builder("a").notify_channel("not-a-name").build::<String>() {
+        Err(BusError::InvalidChannelName(ref c)) if c == "not-a-name" => (),
+        _ => panic!("expected the channel name to be rejected"),
+    }
+    let b: Queue<String> = bus.queue_builder("b").notify_channel("Shared").build().unwrap();
+
+    let waiter = thread::spawn(|| {
+        let bus = pqbus::new(db_uri(), "notify_channel").unwrap();
+        let a: Queue<String> = bus.queue_builder("a").notify_channel("shared").build().unwrap();
+        match a.pop_wait_detailed(Duration::from_secs(5)).unwrap() {
+            WaitOutcome::NotifiedButEmpty => (),
+            _ => panic!("expected a push to b to wake a"),
+        }
+    });
+    thread::sleep(Duration::from_millis(500));
+    b.push("1".to_string()).unwrap();
+    waiter.join().unwrap();
+}
+
+#[cfg(not(feature = "polling-only"))]
+#[test]
+fn test_push_if_listeners() {
+    test_setup();
+    drop_table("pqbus_push_if_listeners_a_queue");
+    let producer_bus = pqbus::new(db_uri(), "push_if_listeners").unwrap();
+    let producer: Queue<String> = producer_bus.queue("a").unwrap();
+    assert!(!producer.push_if_listeners("skipped".to_string()).unwrap());
+    assert!(producer.is_empty().unwrap());
+
+    let consumer_bus = pqbus::new(db_uri(), "push_if_listeners").unwrap();
+    let consumer: Queue<String> = consumer_bus.queue("a").unwrap();
+    // A second queue on the channel doesn't take the lock again.
+    let other: Queue<String> = consumer_bus.queue("a").unwrap();
+    assert!(producer.push_if_listeners("pushed".to_string()).unwrap());
+    assert_eq!(Some("pushed".to_string()), consumer.pop().unwrap());
+
+    drop(other);
+    assert!(producer.push_if_listeners("still listening".to_string()).unwrap());
+    drop(consumer);
+    assert!(!producer.push_if_listeners("gone".to_string()).unwrap());
+
+    let _listener: Queue<String> =
+        consumer_bus.queue_builder("a").reconnect_listener().build().unwrap();
+    assert!(producer.push_if_listeners("own connection".to_string()).unwrap());
+}
+
+#[cfg(not(feature = "polling-only"))]
+#[test]
+fn test_reconnect_listener() {
+    test_setup();
+    drop_table("pqbus_reconnect_listener_queue_queue");
+    let bus = pqbus::new(db_uri(), "reconnect_listener").unwrap();
+    let queue: Queue<String> = bus.queue("queue").unwrap();
+
+    let listener_pid = || -> Vec<i32> {
+        let conn = conn().unwrap();
+        let rows = conn
+            .query("SELECT pid FROM pg_locks \
+                    WHERE locktype = 'advisory' AND (classid, objid, objsubid) = \
+                          (hashtext('pqbus_listener')::oid, \
+                           hashtext('reconnect_listener')::oid, 2)",
+                   &[])
+            .unwrap();
+        rows.iter().map(|row| row.get("pid")).collect()
+    };
+
+    let proxy = Proxy::start();
+    let uri = proxy.uri.clone();
+    let consumer = thread::spawn(move || {
+        let bus = pqbus::new(uri, "reconnect_listener").unwrap();
+        let queue: Queue<String> = bus.queue_builder("queue")
+            .notify_channel("reconnect_listener")
+            .reconnect_listener()
+            .build()
+            .unwrap();
+        queue.pop_blocking().unwrap()
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    // Only the listener holds the listener lock of its channel so far. Its backend is killed
+    // while the consumer waits on it, along with the consumer's bus connection.
+    let killed = listener_pid();
+    assert_eq!(1, killed.len());
+    proxy.kill();
+    thread::sleep(Duration::from_millis(500));
+    let replaced = listener_pid();
+    assert_eq!(1, replaced.len());
+    assert_ne!(killed, replaced);
+
+    let producer: Queue<String> = bus.queue_builder("queue")
+        .notify_channel("reconnect_listener")
+        .build()
+        .unwrap();
+    producer.push("after".to_string()).unwrap();
+    assert_eq!("after", consumer.join().unwrap());
+    assert_eq!(None, queue.pop().unwrap());
+}
+
+#[cfg(not(feature = "polling-only"))]
+#[test]
+fn test_listener_terminated_between_waits() {
+    test_setup();
+    drop_table("pqbus_listener_between_a_queue");
+    let proxy = Proxy::start();
+    let bus = pqbus::new(proxy.uri.clone(), "listener_between").unwrap();
+    let queue: Queue<String> = bus.queue_builder("a")
+        .notify_channel("listener_between")
+        .reconnect_listener()
+        .build()
+        .unwrap();
+
+    proxy.kill();
+    thread::sleep(Duration::from_millis(200));
+
+    // The lost listener is found as soon as it's waited on, and replaced.
+    let start = Instant::now();
+    assert_eq!(None, queue.pop_wait::<FromUtf8Error>(Duration::from_secs(5)).unwrap());
+    assert!(start.elapsed() < Duration::from_secs(4));
+
+    let pusher = thread::spawn(|| {
+        thread::sleep(Duration::from_millis(300));
+        let bus = pqbus::new(db_uri(), "listener_between").unwrap();
+        let queue: Queue<String> =
+            bus.queue_builder("a").notify_channel("listener_between").build().unwrap();
+        queue.push("after".to_string()).unwrap();
+    });
+    let received = queue.pop_wait::<FromUtf8Error>(Duration::from_secs(5)).unwrap();
+    assert_eq!(Some("after".to_string()), received);
+    pusher.join().unwrap();
+}
+
+#[test]
+fn test_select_blocking() {
+    test_setup();
+    drop_table("pqbus_select_a_queue");
+    drop_table("pqbus_select_b_queue");
+    let bus = pqbus::new(db_uri(), "select").unwrap();
+    let a: Queue<String> = bus.queue("a").unwrap();
+    let b: Queue<String> = bus.queue("b").unwrap();
+
+    let selector = thread::spawn(|| {
+        let bus = pqbus::new(db_uri(), "select").unwrap();
+        let a: Queue<String> = bus.queue("a").unwrap();
+        let b: Queue<String> = bus.queue("b").unwrap();
+        let mut selected = vec![];
+        for _ in 0..2 {
+            let (i, m) = bus.select_blocking(&[&a, &b], Duration::from_secs(5)).unwrap().unwrap();
+            selected.push((i, m));
+        }
+        selected
+    });
+    thread::sleep(Duration::from_millis(300));
+    b.push("to b".to_string()).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    a.push("to a".to_string()).unwrap();
+    assert_eq!(vec![(1, "to b".to_string()), (0, "to a".to_string())],
+               selector.join().unwrap());
+
+    // Queues with messages ready together take turns.
+    for i in 0..2 {
+        a.push(format!("a{}", i)).unwrap();
+        b.push(format!("b{}", i)).unwrap();
+    }
+    let timeout = Duration::from_secs(1);
+    let turns: Vec<usize> = (0..4)
+        .map(|_| bus.select_blocking::<_, FromUtf8Error>(&[&a, &b], timeout).unwrap().unwrap().0)
+        .collect();
+    assert_eq!(vec![0, 1, 0, 1], turns);
+    assert_eq!(None,
+               bus.select_blocking::<_, FromUtf8Error>(&[&a, &b], Duration::from_millis(100))
+                   .unwrap());
+
+    let other = pqbus::new(db_uri(), "select").unwrap();
+    let foreign: Queue<String> = other.queue("a").unwrap();
+    assert!(bus.select_blocking::<_, FromUtf8Error>(&[&a, &foreign], timeout).is_err());
+}
+
+/// Not `Clone`, so pushing it twice must borrow it.
+#[derive(Debug, PartialEq)]
+struct Point {
+    x: i32,
+    y: i32,
+}
+
+impl ToMessageBodyRef<String> for Point {
+    fn to_message_body_ref(&self) -> Result<Vec<u8>, String> {
+        Ok(format!("{},{}", self.x, self.y).into_bytes())
+    }
+}
+
+impl FromMessageBody<String> for Point {
+    fn from_message_body(m: Message) -> Result<Self, String> {
+        let body = String::from_utf8(m.to_body()).map_err(|e| e.to_string())?;
+        let mut parts = body.split(',').map(|p| p.parse::<i32>().map_err(|e| e.to_string()));
+        match (parts.next(), parts.next()) {
+            (Some(x), Some(y)) => Ok(Point { x: x?, y: y? }),
+            _ => Err(format!("Not a point: {}", body)),
+        }
+    }
+}
+
+#[test]
+fn test_pop_callback_result_stops_on_work_error() {
+    test_setup();
+    drop_table("pqbus_callback_result_a_queue");
+    let bus = pqbus::new(db_uri(), "callback_result").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    for i in 1..4 {
+        queue.push(i.to_string()).unwrap();
+    }
+
+    let mut seen = vec![];
+    let result = queue.pop_callback_result::<_, FromUtf8Error, _>(|m| {
+        seen.push(m.clone());
+        if m == "2" {
+            Err(format!("failed on {}", m))
+        } else {
+            Ok(())
+        }
+    });
+
+    match result {
+        Err(CallbackError::Work(e)) => assert_eq!("failed on 2", e),
+        _ => panic!("expected the work error"),
+    }
+    assert_eq!(vec!["1".to_string(), "2".to_string()], seen);
+    assert_eq!(2, queue.size().unwrap());
+    assert_eq!(Some("2".to_string()), queue.pop::<FromUtf8Error>().unwrap());
+}
+
+#[test]
+fn test_push_duplicate_message() {
+    test_setup();
+    drop_table("pqbus_push_duplicate_a_queue");
+    let bus = pqbus::new(db_uri(), "push_duplicate").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    conn()
+        .unwrap()
+        .execute("CREATE UNIQUE INDEX ON pqbus_push_duplicate_a_queue (message)", &[])
+        .unwrap();
+
+    queue.push("1".to_string()).unwrap();
+    match queue.push("1".to_string()) {
+        Err(PushError::DuplicateMessage(_)) => (),
+        other => panic!("expected DuplicateMessage, got {:?}", other),
+    }
+    match queue.push_batch(vec!["2".to_string(), "2".to_string()]) {
+        Err(PushError::DuplicateMessage(_)) => (),
+        other => panic!("expected DuplicateMessage, got {:?}", other),
+    }
+    assert_eq!(1, queue.size().unwrap());
+}
+
+#[test]
+fn test_errors_chain_sources() {
+    test_setup();
+    let bus = pqbus::new(db_uri(), "error_source").unwrap();
+    let result: Result<Queue<String>, BusError> =
+        bus.queue_builder("a").with_table_name("pqbus_missing_schema.a").build();
+    let err: Box<dyn Error> = Box::new(result.err().unwrap());
+    assert!(err.to_string().starts_with("Failed to create queue"));
+    let source = err.source().unwrap();
+    assert!(source.to_string().contains("pqbus_missing_schema"), "{}", source);
+
+    let pop: PopError<FromUtf8Error> =
+        PopError::BodyDeseralize(String::from_utf8(vec![0xff]).unwrap_err());
+    let err: Box<dyn Error> = Box::new(pop);
+    assert!(err.source().unwrap().is::<FromUtf8Error>());
+
+    let push: PushError<FromUtf8Error> = PushError::QueueFull;
+    assert!(push.source().is_none());
+}
+
+#[test]
+fn test_pop_error_is_retryable() {
+    test_setup();
+    let c = conn().unwrap();
+    let raise = |code: &str| {
+        c.batch_execute(&format!("DO $$ BEGIN RAISE EXCEPTION 'simulated' USING ERRCODE = '{}'; \
+                                  END $$",
+                                 code))
+            .unwrap_err()
+    };
+
+    let serialization: PopError<FromUtf8Error> = PopError::Pop(raise("40001"));
+    assert!(serialization.is_retryable());
+    let deadlock: PopError<FromUtf8Error> = PopError::Pop(raise("40P01"));
+    assert!(deadlock.is_retryable());
+    let missing: PopError<FromUtf8Error> = PopError::Pop(raise("42P01"));
+    assert!(!missing.is_retryable());
+    let generic: PopError<FromUtf8Error> = PopError::Generic("simulated".to_string());
+    assert!(!generic.is_retryable());
+}
+
+#[test]
+fn test_push_ref() {
+    test_setup();
+    drop_table("pqbus_push_ref_a_queue");
+    drop_table("pqbus_push_ref_b_queue");
+    let bus = pqbus::new(db_uri(), "push_ref").unwrap();
+    let a: Queue<Point> = bus.queue("a").unwrap();
+    let b: Queue<Point> = bus.queue("b").unwrap();
+
+    let point = Point { x: 3, y: -4 };
+    a.push_ref(&point).unwrap();
+    b.push_ref(&point).unwrap();
+
+    assert_eq!(Some(Point { x: 3, y: -4 }), a.pop().unwrap());
+    assert_eq!(Some(point), b.pop().unwrap());
+
+    let strings: Queue<String> = bus.queue("a").unwrap();
+    let message = "shared".to_string();
+    strings.push_ref(&message).unwrap();
+    assert_eq!(Some(message), strings.pop::<FromUtf8Error>().unwrap());
+}
+
+#[test]
+fn test_typed_queues() {
+    test_setup();
+    drop_table("pqbus_typed_queues_strings_queue");
+    drop_table("pqbus_typed_queues_points_queue");
+    let bus = pqbus::new(db_uri(), "typed_queues").unwrap();
+
+    let strings = bus.string_queue("strings").unwrap();
+    strings.push("typed".to_string()).unwrap();
+    assert_eq!(Some("typed".to_string()), strings.pop().unwrap());
+
+    let points = bus.typed_queue::<Point, _>("points").unwrap();
+    points.push_ref(&Point { x: 1, y: 2 }).unwrap();
+    assert_eq!(Some(Point { x: 1, y: 2 }), points.pop().unwrap());
+}
+
+#[test]
+fn test_topic_fan_out() {
+    test_setup();
+    for table in &["pqbus_topic_a_queue", "pqbus_topic_b_queue", "pqbus_topic_c_queue",
+                   "pqbus_topic_topic_bindings"] {
+        drop_table(table);
+    }
+    let bus = pqbus::new(db_uri(), "topic").unwrap();
+    let topic = bus.topic("signups").unwrap();
+    topic.bind("a").unwrap();
+    topic.bind("b").unwrap();
+    topic.bind("b").unwrap();
+    assert_eq!(vec!["a".to_string(), "b".to_string()], topic.bindings().unwrap());
+
+    assert_eq!(2, topic.publish("1").unwrap());
+    topic.bind("c").unwrap();
+
+    let a: Queue<String> = bus.queue("a").unwrap();
+    let b: Queue<String> = bus.queue("b").unwrap();
+    let c: Queue<String> = bus.queue("c").unwrap();
+    assert_eq!(Some("1".to_string()), a.pop::<FromUtf8Error>().unwrap());
+    assert_eq!(Some("1".to_string()), b.pop::<FromUtf8Error>().unwrap());
+    assert!(c.is_empty().unwrap());
+
+    topic.unbind("a").unwrap();
+    assert_eq!(2, topic.publish("2").unwrap());
+    assert!(a.is_empty().unwrap());
+    assert_eq!(Some("2".to_string()), c.pop::<FromUtf8Error>().unwrap());
+}
+
+#[cfg(not(feature = "polling-only"))]
+#[test]
+fn test_topic_bind_queue() {
+    test_setup();
+    drop_table("pqbus_topic_queue_a_queue");
+    drop_table("pqbus_topic_queue_topic_bindings");
+    let bus = pqbus::new(db_uri(), "topic_queue").unwrap();
+    let topic = bus.topic("signups").unwrap();
+
+    let dedup: Queue<String> = bus.queue_builder("a").with_dedup().build().unwrap();
+    assert!(topic.bind_queue(&dedup).is_err());
+    assert!(topic.bindings().unwrap().is_empty());
+
+    // Consumers listening on the queue's own channel are woken by publishes.
+    let queue: Queue<String> =
+        bus.queue_builder("a").notify_channel("topic_queue_custom").build().unwrap();
+    topic.bind_queue(&queue).unwrap();
+    let consumer = thread::spawn(|| {
+        let bus = pqbus::new(db_uri(), "topic_queue").unwrap();
+        let queue: Queue<String> =
+            bus.queue_builder("a").notify_channel("topic_queue_custom").build().unwrap();
+        let start = Instant::now();
+        let m = queue.pop_wait::<FromUtf8Error>(Duration::from_secs(5)).unwrap();
+        (m, start.elapsed())
+    });
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(1, topic.publish("woken").unwrap());
+
+    let (m, waited) = consumer.join().unwrap();
+    assert_eq!(Some("woken".to_string()), m);
+    assert!(waited < Duration::from_secs(4));
+}
+
+#[test]
+fn test_binary_message() {
+    test_setup();
+    drop_table("pqbus_binary_a_queue");
+    let bus = pqbus::new(db_uri(), "binary").unwrap();
+    let queue: Queue<Vec<u8>> = bus.queue("a").unwrap();
+
+    let body = vec![0x00, 0xff, 0xfe, 0x80, 0xc3, 0x28];
+    assert!(String::from_utf8(body.clone()).is_err());
+    queue.push(body.clone()).unwrap();
+    queue.push_ref(&body).unwrap();
+
+    assert_eq!(Some(body.clone()), queue.pop().unwrap());
+    assert_eq!(Some(body), queue.pop().unwrap());
+}
+
+#[test]
+fn test_headers() {
+    test_setup();
+    drop_table("pqbus_headers_a_queue");
+    let bus = pqbus::new(db_uri(), "headers").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    let raw: Queue<Message> = bus.queue("a").unwrap();
+
+    let mut headers = HashMap::new();
+    headers.insert("trace-id".to_string(), "abc-123".to_string());
+    headers.insert("content-type".to_string(), "text/plain".to_string());
+    queue.push_with_headers("1".to_string(), headers.clone()).unwrap();
+    queue.push("2".to_string()).unwrap();
+
+    let popped = raw.pop().unwrap().unwrap();
+    assert_eq!(b"1", popped.body());
+    assert_eq!(&headers, popped.headers());
+    let plain = raw.pop().unwrap().unwrap();
+    assert!(plain.headers().is_empty());
+
+    queue.push_with_headers("3".to_string(), headers).unwrap();
+    let delivery = queue.pop_delivery::<FromUtf8Error>().unwrap().unwrap();
+    assert_eq!("abc-123", delivery.headers()["trace-id"]);
+    delivery.ack().unwrap();
+}
+
+#[test]
+fn test_pop_filtered() {
+    test_setup();
+    drop_table("pqbus_pop_filtered_a_queue");
+    let bus = pqbus::new(db_uri(), "pop_filtered").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+
+    for &(body, kind) in &[("1", "order"), ("2", "refund"), ("3", "order")] {
+        let mut headers = HashMap::new();
+        headers.insert("type".to_string(), kind.to_string());
+        headers.insert("priority".to_string(), "low".to_string());
+        queue.push_with_headers(body.to_string(), headers).unwrap();
+    }
+    queue.push("4".to_string()).unwrap();
+
+    let refunds = [("type", "refund")];
+    assert_eq!(Some("2".to_string()), queue.pop_filtered::<FromUtf8Error>(&refunds).unwrap());
+    assert_eq!(None, queue.pop_filtered::<FromUtf8Error>(&refunds).unwrap());
+
+    let orders = [("type", "order"), ("priority", "low")];
+    assert_eq!(Some("1".to_string()), queue.pop_filtered::<FromUtf8Error>(&orders).unwrap());
+    let urgent = [("type", "order"), ("priority", "high")];
+    assert_eq!(None, queue.pop_filtered::<FromUtf8Error>(&urgent).unwrap());
+
+    assert_eq!(Some("3".to_string()), queue.pop_filtered::<FromUtf8Error>(&[]).unwrap());
+    assert_eq!(Some("4".to_string()), queue.pop_filtered::<FromUtf8Error>(&[]).unwrap());
+    assert_eq!(0, queue.size().unwrap());
+}
+
+#[test]
+fn test_delete_where() {
+    test_setup();
+    drop_table("pqbus_delete_where_a_queue");
+    let bus = pqbus::new(db_uri(), "delete_where").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+
+    let push = |body: &str, kind: &str| {
+        let mut headers = HashMap::new();
+        headers.insert("type".to_string(), kind.to_string());
+        queue.push_with_headers(body.to_string(), headers).unwrap();
+    };
+    push("1", "order");
+    push("2", "refund");
+    thread::sleep(Duration::from_millis(300));
+    push("3", "order");
+    push("4", "refund");
+    queue.push("5".to_string()).unwrap();
+
+    let old_orders = DeleteFilter::new()
+        .older_than(Duration::from_millis(200))
+        .with_header("type", "order");
+    assert_eq!(1, queue.delete_where(&old_orders).unwrap());
+    let refunds = DeleteFilter::new().with_header("type", "refund");
+    assert_eq!(2, queue.delete_where(&refunds).unwrap());
+
+    let remaining: Vec<String> = queue.messages::<FromUtf8Error>().map(|m| m.unwrap()).collect();
+    assert_eq!(vec!["3", "5"], remaining);
+}
+
+#[test]
+fn test_oldest_pending_age() {
+    test_setup();
+    drop_table("pqbus_pending_age_a_queue");
+    let bus = pqbus::new(db_uri(), "pending_age").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    assert_eq!(None, queue.oldest_pending_age().unwrap());
+
+    queue.push("1".to_string()).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    queue.push("2".to_string()).unwrap();
+    let age = queue.oldest_pending_age().unwrap().unwrap();
+    assert!(age >= Duration::from_millis(300), "age {:?}", age);
+    assert!(age < Duration::from_secs(5), "age {:?}", age);
+
+    // Claimed messages are no longer pending.
+    let delivery = queue.pop_delivery::<FromUtf8Error>().unwrap().unwrap();
+    assert!(queue.oldest_pending_age().unwrap().unwrap() < Duration::from_millis(300));
+    delivery.ack().unwrap();
+}
+
+#[test]
+fn test_ttl() {
+    test_setup();
+    drop_table("pqbus_ttl_a_queue");
+    let bus = pqbus::new(db_uri(), "ttl").unwrap();
+    let queue: Queue<String> = bus.queue_builder("a")
+        .with_ttl(Duration::from_secs(1))
+        .build()
+        .unwrap();
+    assert_eq!(0, queue.expire().unwrap());
+
+    queue.push("stale".to_string()).unwrap();
+    thread::sleep(Duration::from_millis(1200));
+    queue.push("fresh".to_string()).unwrap();
+    assert_eq!(1, queue.pending().unwrap());
+    assert_eq!(1, queue.expire().unwrap());
+    assert_eq!(1, queue.size().unwrap());
+    assert_eq!(Some("fresh".to_string()), queue.pop().unwrap());
+    assert_eq!(None, queue.pop().unwrap());
+
+    // A TTL of zero never expires messages.
+    drop_table("pqbus_ttl_b_queue");
+    let queue: Queue<String> = bus.queue_builder("b")
+        .with_ttl(Duration::from_secs(0))
+        .build()
+        .unwrap();
+    queue.push("1".to_string()).unwrap();
+    assert_eq!(0, queue.expire().unwrap());
+    assert_eq!(Some("1".to_string()), queue.pop().unwrap());
+}
+
+#[test]
+fn test_push_expiring() {
+    test_setup();
+    drop_table("pqbus_push_expiring_a_queue");
+    let bus = pqbus::new(db_uri(), "push_expiring").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+
+    let now = SystemTime::now();
+    queue.push_expiring("expired".to_string(), now - Duration::from_secs(60)).unwrap();
+    queue.push_expiring("later".to_string(), now + Duration::from_secs(60)).unwrap();
+    queue.push_expiring("soon".to_string(), now + Duration::from_millis(500)).unwrap();
+    assert_eq!(2, queue.pending().unwrap());
+    assert_eq!(Some("later".to_string()), queue.peek().unwrap());
+    assert_eq!(Some("later".to_string()), queue.pop().unwrap());
+
+    thread::sleep(Duration::from_millis(700));
+    assert_eq!(None, queue.pop().unwrap());
+    assert_eq!(2, queue.expire().unwrap());
+    assert!(queue.is_empty().unwrap());
+}
+
+#[test]
+fn test_move_to() {
+    test_setup();
+    drop_table("pqbus_move_work_queue");
+    drop_table("pqbus_move_retry_queue");
+    let bus = pqbus::new(db_uri(), "move").unwrap();
+    let work: Queue<String> = bus.queue("work").unwrap();
+    let retry: Queue<String> = bus.queue("retry").unwrap();
+
+    for i in 0..5 {
+        work.push(format!("{}", i)).unwrap();
+    }
+    assert_eq!(3, work.move_to(&retry, 3).unwrap());
+    assert_eq!(2, work.size().unwrap());
+    assert_eq!(3, retry.size().unwrap());
+    assert_eq!(2, work.move_to(&retry, 10).unwrap());
+    assert_eq!(0, work.move_to(&retry, 10).unwrap());
+    assert!(work.is_empty().unwrap());
+
+    let moved: Vec<String> = (0..5).map(|_| retry.pop().unwrap().unwrap()).collect();
+    assert_eq!(vec!["0", "1", "2", "3", "4"], moved);
+
+    // Queues on another connection can't share the transaction.
+    let other_bus = pqbus::new(db_uri(), "move").unwrap();
+    let other: Queue<String> = other_bus.queue("retry").unwrap();
+    assert!(work.move_to(&other, 1).is_err());
+}
+
+#[test]
+fn test_message_helpers() {
+    let message = Message::with_id(7, b"hello".to_vec());
+    assert_eq!(5, message.len());
+    assert!(!message.is_empty());
+    assert_eq!(Ok("hello"), message.as_str());
+    assert!(Message::new(vec![]).is_empty());
+    assert!(Message::new(vec![0xff, 0xfe]).as_str().is_err());
+
+    let copy = message.clone();
+    assert_eq!(Some(7), copy.id());
+    assert_eq!("hello".to_string(), String::from_message_body(copy).unwrap());
+    assert_eq!(b"hello", message.body());
+}
+
+#[test]
+fn test_push_in_transaction() {
+    test_setup();
+    drop_table("pqbus_push_in_transaction_a_queue");
+    let bus = pqbus::new(db_uri(), "push_in_transaction").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+
+    let trans = bus.connection().transaction().unwrap();
+    queue.push_in_transaction(&trans, "rolled back".to_string()).unwrap();
+    assert_eq!(1, queue.size().unwrap());
+    trans.finish().unwrap();
+    assert!(queue.is_empty().unwrap());
+    assert_eq!(None, queue.pop_wait(Duration::from_millis(100)).unwrap());
+
+    let trans = bus.connection().transaction().unwrap();
+    queue.push_in_transaction(&trans, "committed".to_string()).unwrap();
+    trans.commit().unwrap();
+    assert_eq!(Some("committed".to_string()), queue.pop().unwrap());
+
+    // Transactions on another connection are refused.
+    let other = conn().unwrap();
+    let trans = other.transaction().unwrap();
+    match queue.push_in_transaction(&trans, "elsewhere".to_string()) {
+        Err(PushError::Generic(_)) => (),
+        _ => panic!("expected a push on another connection to fail"),
+    }
+}
+
+#[test]
+fn test_queue_inside_caller_transaction() {
+    test_setup();
+    drop_table("pqbus_caller_trans_a_queue");
+    let bus = pqbus::new(db_uri(), "caller_trans").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    queue.push("kept".to_string()).unwrap();
+
+    // Work done inside the caller's transaction is undone with it.
+    let trans = bus.connection().transaction().unwrap();
+    let other: Queue<String> = bus.queue("b").unwrap();
+    assert_eq!(Some("kept".to_string()), queue.pop().unwrap());
+    assert_eq!(1, queue.push_with_size("pushed".to_string()).unwrap());
+    assert_eq!(&["pushed"], queue.claim_batch(5).unwrap().messages());
+    queue.push_in_transaction(&trans, "moved".to_string()).unwrap();
+    assert_eq!(1, queue.move_to(&other, 5).unwrap());
+    drop(trans);
+
+    assert_eq!(Some("kept".to_string()), queue.pop().unwrap());
+    assert!(queue.pop().unwrap().is_none());
+}
+
+#[test]
+fn test_pop_delete() {
+    test_setup();
+    drop_table("pqbus_pop_delete_a_queue");
+    let bus = pqbus::new(db_uri(), "pop_delete").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    queue.push("1".to_string()).unwrap();
+    queue.push("2".to_string()).unwrap();
+
+    // The row is gone as soon as it is popped, whatever the consumer does with it.
+    drop(queue.pop_delete().unwrap());
+    assert_eq!(1, queue.size().unwrap());
+    assert_eq!(Some("2".to_string()), queue.pop_delete().unwrap());
+    assert_eq!(None, queue.pop_delete().unwrap());
+    assert!(queue.is_empty().unwrap());
+
+    // Unconvertible messages are lost, not dead-lettered.
+    let raw: Queue<Vec<u8>> = bus.queue("a").unwrap();
+    raw.push(vec![0xff]).unwrap();
+    assert!(queue.pop_delete().is_err());
+    assert!(queue.is_empty().unwrap());
+    assert!(queue.dead_letters().unwrap().is_empty());
+}
+
+#[test]
+fn test_pop_as() {
+    test_setup();
+    drop_table("pqbus_pop_as_a_queue");
+    let bus = pqbus::new(db_uri(), "pop_as").unwrap();
+    let queue: Queue<Vec<u8>> = bus.queue("a").unwrap();
+    queue.push(b"text".to_vec()).unwrap();
+    queue.push(vec![0xff, 0x00]).unwrap();
+
+    assert_eq!(Some("text".to_string()), queue.pop_as::<String, _>().unwrap());
+
+    // The second message isn't UTF-8, so stays pending for another type.
+    assert!(queue.pop_as::<String, _>().is_err());
+    assert_eq!(1, queue.pending().unwrap());
+    assert!(queue.dead_letters().unwrap().is_empty());
+    let message = queue.pop_as::<Message, _>().unwrap().unwrap();
+    assert_eq!(&[0xff, 0x00], message.body());
+    assert!(queue.is_empty().unwrap());
+}
+
+/// Fails the test if a message is ever converted to it.
+struct NeverConverted;
+
+impl FromMessageBody<String> for NeverConverted {
+    fn from_message_body(_m: Message) -> Result<Self, String> {
+        panic!("message was converted")
+    }
+}
+
+#[test]
+fn test_pop_raw() {
+    test_setup();
+    drop_table("pqbus_pop_raw_a_queue");
+    let bus = pqbus::new(db_uri(), "pop_raw").unwrap();
+    let producer: Queue<String> = bus.queue("a").unwrap();
+    let queue: Queue<NeverConverted> = bus.queue("a").unwrap();
+    assert!(queue.pop_raw().unwrap().is_none());
+
+    let json = r#"{"user":"sgibbs","tags":["a","b"]}"#;
+    let mut headers = HashMap::new();
+    headers.insert("content-type".to_string(), "application/json".to_string());
+    let id = producer.push_returning_id(json.to_string()).unwrap();
+    producer.push_with_headers(json.to_string(), headers.clone()).unwrap();
+
+    let message = queue.pop_raw().unwrap().unwrap();
+    assert_eq!(json.as_bytes(), message.body());
+    assert_eq!(Some(id), message.id());
+    assert!(message.headers().is_empty());
+    let message = queue.pop_raw().unwrap().unwrap();
+    assert_eq!(json.as_bytes(), message.body());
+    assert_eq!(&headers, message.headers());
+    assert!(queue.is_empty().unwrap());
+}
+
+/// Reads `len` pseudo-random bytes, so large bodies needn't be held in memory to push them.
+struct Noise {
+    state: u64,
+    len: usize,
+}
+
+impl std::io::Read for Noise {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.len);
+        for byte in &mut buf[..n] {
+            self.state = self.state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            *byte = (self.state >> 56) as u8;
+        }
+        self.len -= n;
+        Ok(n)
+    }
+}
+
+/// Checksums the bytes written to it.
+#[derive(Default)]
+struct Checksum {
+    sum: u64,
+    len: usize,
+}
+
+impl std::io::Write for Checksum {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for &byte in buf {
+            self.sum = self.sum.rotate_left(5) ^ u64::from(byte);
+        }
+        self.len += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_raw_messages() {
+    test_setup();
+    drop_table("pqbus_raw_messages_a_queue");
+    let bus = pqbus::new(db_uri(), "raw_messages").unwrap();
+    let queue: Queue<NeverConverted> = bus.queue("a").unwrap();
+    let bytes: Queue<Vec<u8>> = bus.queue("a").unwrap();
+    let strings: Queue<String> = bus.queue("a").unwrap();
+
+    strings.push("claimed".to_string()).unwrap();
+    strings.push("text".to_string()).unwrap();
+    bytes.push(vec![0, 159, 146, 150]).unwrap();
+    bytes.push(Vec::new()).unwrap();
+    let mut headers = HashMap::new();
+    headers.insert("kind".to_string(), "json".to_string());
+    strings.push_with_headers(r#"{"a":1}"#.to_string(), headers.clone()).unwrap();
+
+    // A message claimed by a delivery is left to its consumer.
+    let delivery = strings.pop_delivery::<FromUtf8Error>().unwrap().unwrap();
+    assert_eq!("claimed", delivery.message());
+
+    let raw: Vec<Message> = queue.raw_messages().map(|m| m.unwrap()).collect();
+    let bodies: Vec<&[u8]> = raw.iter().map(|m| m.body()).collect();
+    assert_eq!(vec![&b"text"[..], &[0, 159, 146, 150], b"", br#"{"a":1}"#], bodies);
+    assert_eq!(&headers, raw[3].headers());
+    assert!(raw.iter().all(|m| m.id().is_some()));
+    assert_eq!(1, queue.size().unwrap());
+    delivery.ack().unwrap();
+    assert!(queue.is_empty().unwrap());
+
+    // The blocking variants wait for pushes.
+    let producer = thread::spawn(|| {
+        let bus = pqbus::new(db_uri(), "raw_messages").unwrap();
+        let queue: Queue<Vec<u8>> = bus.queue("a").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        for body in &[b"x", b"y", b"z"] {
+            queue.push(body.to_vec()).unwrap();
+        }
+    });
+    let cancel = AtomicBool::new(false);
+    let raw: Vec<Vec<u8>> = queue.raw_messages_blocking_limited(2, &cancel)
+        .map(|m| m.unwrap().to_body())
+        .collect();
+    assert_eq!(vec![b"x".to_vec(), b"y".to_vec()], raw);
+    assert_eq!(b"z", queue.raw_messages_blocking().next().unwrap().unwrap().body());
+    producer.join().unwrap();
+}
+
+#[test]
+fn test_application_name() {
+    test_setup();
+    fn application_name(bus: &pqbus::PqBus) -> String {
+        let rows = bus.connection().query("SELECT current_setting('application_name')", &[]);
+        rows.unwrap().get(0).get(0)
+    }
+
+    let bus = pqbus::new(db_uri(), "appname").unwrap();
+    assert_eq!("pqbus:appname", application_name(&bus));
+    let bus = bus.with_application_name("custom").unwrap();
+    assert_eq!("custom", application_name(&bus));
+
+    let uri = format!("{}?application_name=fromuri", db_uri());
+    let bus = pqbus::new(uri, "appname").unwrap();
+    assert_eq!("fromuri", application_name(&bus));
+}
+
+#[test]
+fn test_push_reader_pop_to_writer() {
+    test_setup();
+    drop_table("pqbus_stream_a_queue");
+    let bus = pqbus::new(db_uri(), "stream").unwrap();
+    let queue: Queue<Vec<u8>> = bus.queue("a").unwrap();
+    assert!(!queue.pop_to_writer(Checksum::default()).unwrap());
+
+    let len = 10 * 1024 * 1024;
+    let mut expected = Checksum::default();
+    std::io::copy(&mut Noise { state: 7, len }, &mut expected).unwrap();
+    queue.push_reader(Noise { state: 7, len }).unwrap();
+    queue.push_reader(&b"small"[..]).unwrap();
+    assert_eq!(2, queue.size().unwrap());
+
+    let mut popped = Checksum::default();
+    assert!(queue.pop_to_writer(&mut popped).unwrap());
+    assert_eq!(len, popped.len);
+    assert_eq!(expected.sum, popped.sum);
+    assert_eq!(Some(b"small".to_vec()), queue.pop().unwrap());
+    assert!(queue.is_empty().unwrap());
+
+    // Chunks are read without the server reading the whole body.
+    let storage: String = bus.connection()
+        .query("SELECT attstorage::text FROM pg_attribute \
+                WHERE attrelid = 'pqbus_stream_a_queue'::regclass AND attname = 'message'",
+               &[])
+        .unwrap()
+        .get(0)
+        .get(0);
+    assert_eq!("e", storage);
+
+    // Queues that need the whole body before storing it are refused.
+    let dedup: Queue<Vec<u8>> = bus.queue_builder("a").with_dedup().build().unwrap();
+    assert!(dedup.push_reader(&b"refused"[..]).is_err());
+    let limited: Queue<Vec<u8>> = bus.queue_builder("a").with_max_depth(10).build().unwrap();
+    assert!(limited.push_reader(&b"refused"[..]).is_err());
+    #[cfg(feature = "compression")]
+    {
+        let compressed: Queue<Vec<u8>> =
+            bus.queue_builder("a").with_compression(pqbus::Compression::Gzip).build().unwrap();
+        assert!(compressed.push_reader(&b"refused"[..]).is_err());
+    }
+    assert!(queue.is_empty().unwrap());
+}
+
+#[test]
+fn test_lazy_statements() {
+    test_setup();
+    drop_table("pqbus_lazy_statements_eager_queue");
+    drop_table("pqbus_lazy_statements_lazy_queue");
+    let bus = pqbus::new(db_uri(), "lazy_statements").unwrap();
+    let prepared = || -> i64 {
+        let rows = bus.connection().query("SELECT count(*) FROM pg_prepared_statements", &[]);
+        rows.unwrap().get(0).get(0)
+    };
+
+    let before = prepared();
+    let _eager: Queue<String> = bus.queue("eager").unwrap();
+    let eager = prepared() - before;
+
+    let before = prepared();
+    let queue: Queue<String> = bus.queue_builder("lazy").lazy_statements().build().unwrap();
+    let lazy = prepared() - before;
+    assert!(lazy < eager, "lazy queue prepared {} statements, eager {}", lazy, eager);
+
+    queue.push("one".to_string()).unwrap();
+    queue.push("two".to_string()).unwrap();
+    assert_eq!(Some("one".to_string()), queue.pop().unwrap());
+    assert_eq!(Some("two".to_string()), queue.pop().unwrap());
+    assert_eq!(None, queue.pop().unwrap());
+    assert!(prepared() - before > lazy);
+}
+
+#[test]
+fn test_approx_size() {
+    test_setup();
+    drop_table("pqbus_approx_size_a_queue");
+    let bus = pqbus::new(db_uri(), "approx_size").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    assert_eq!(0, queue.approx_size().unwrap());
+    assert!(queue.is_empty().unwrap());
+
+    for i in 0..10 {
+        queue.push(format!("{}", i)).unwrap();
+    }
+    assert!(!queue.is_empty().unwrap());
+    assert!(queue.approx_size().unwrap() <= queue.size().unwrap());
+    conn().unwrap().execute("ANALYZE pqbus_approx_size_a_queue", &[]).unwrap();
+    assert_eq!(10, queue.approx_size().unwrap());
+    assert_eq!(10, queue.size().unwrap());
+
+    // Claimed messages are not pending, but still in the queue.
+    let deliveries: Vec<_> =
+        (0..10).map(|_| queue.pop_delivery::<FromUtf8Error>().unwrap()).collect();
+    assert!(!queue.has_pending().unwrap());
+    assert!(!queue.is_empty().unwrap());
+    drop(deliveries);
+    assert!(queue.has_pending().unwrap());
+}
+
+#[test]
+fn test_has_pending() {
+    test_setup();
+    drop_table("pqbus_has_pending_a_queue");
+    let bus = pqbus::new(db_uri(), "has_pending").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    assert!(queue.is_empty().unwrap());
+    assert!(!queue.has_pending().unwrap());
+
+    queue.push_delayed("later".to_string(), Duration::from_secs(60)).unwrap();
+    assert!(!queue.is_empty().unwrap());
+    assert!(!queue.has_pending().unwrap());
+
+    queue.push("now".to_string()).unwrap();
+    assert!(queue.has_pending().unwrap());
+}
+
+#[test]
+fn test_max_depth() {
+    test_setup();
+    drop_table("pqbus_max_depth_a_queue");
+    let bus = pqbus::new(db_uri(), "max_depth").unwrap();
+    let queue: Queue<String> = bus.queue_builder("a").with_max_depth(3).build().unwrap();
+    for i in 0..3 {
+        queue.push(i.to_string()).unwrap();
+    }
+
+    match queue.push("3".to_string()) {
+        Err(PushError::QueueFull::<FromUtf8Error>) => (),
+        _ => panic!("expected QueueFull"),
+    }
+    match queue.push_batch(vec!["3".to_string(), "4".to_string()]) {
+        Err(PushError::QueueFull::<FromUtf8Error>) => (),
+        _ => panic!("expected QueueFull"),
+    }
+    assert_eq!(3, queue.size().unwrap());
+
+    assert_eq!(Some("0".to_string()), queue.pop::<FromUtf8Error>().unwrap());
+    queue.push("3".to_string()).unwrap();
+    assert_eq!(3, queue.size().unwrap());
+}
+
+#[test]
+fn test_stats() {
+    test_setup();
+    drop_table("pqbus_stats_a_queue");
+    let bus = pqbus::new(db_uri(), "stats").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    for i in 0..3 {
+        queue.push(i.to_string()).unwrap();
+    }
+    queue.push_delayed("later".to_string(), Duration::from_secs(60)).unwrap();
+
+    let delivery = queue.pop_delivery::<FromUtf8Error>().unwrap().unwrap();
+    let stats = queue.stats().unwrap();
+    assert_eq!(2, stats.pending);
+    assert_eq!(1, stats.in_flight);
+    assert_eq!(1, stats.delayed);
+    assert_eq!(4, stats.total);
+    assert_eq!(stats.total, stats.pending + stats.in_flight + stats.delayed);
+
+    delivery.ack().unwrap();
+    let stats = queue.stats().unwrap();
+    assert_eq!(0, stats.in_flight);
+    assert_eq!(3, stats.total);
+}
+
+#[test]
+fn test_drain() {
+    test_setup();
+    drop_table("pqbus_drain_a_queue");
+    let bus = pqbus::new(db_uri(), "drain").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    for i in 0..10 {
+        queue.push(i.to_string()).unwrap();
+    }
+
+    let drained: Vec<String> = queue.drain().unwrap();
+    let expected: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+    assert_eq!(expected, drained);
+    assert!(queue.is_empty().unwrap());
+    assert!(queue.drain::<FromUtf8Error>().unwrap().is_empty());
+}
+
+#[test]
+fn test_queue_builder_options() {
+    test_setup();
+    drop_table("pqbus_builder_a_queue");
+    let bus = pqbus::new(db_uri(), "builder").unwrap();
+    let queue: Queue<String> =
+        bus.queue_builder("a").priority().with_max_depth(2).build().unwrap();
+
+    queue.push_with_priority("low".to_string(), 1).unwrap();
+    queue.push_with_priority("high".to_string(), 5).unwrap();
+    match queue.push("over".to_string()) {
+        Err(PushError::QueueFull) => (),
+        _ => panic!("expected QueueFull"),
+    }
+
+    assert_eq!(Some("high".to_string()), queue.pop().unwrap());
+    assert_eq!(Some("low".to_string()), queue.pop().unwrap());
+    assert_eq!(None, queue.pop::<FromUtf8Error>().unwrap());
+}
+
+#[test]
+fn test_pending_index() {
+    test_setup();
+    drop_table("pqbus_pending_index_a_queue");
+    let bus = pqbus::new(db_uri(), "pending_index").unwrap();
+    let _queue: Queue<String> = bus.queue("a").unwrap();
+
+    let c = conn().unwrap();
+    let rows = c.query("SELECT indexname FROM pg_indexes WHERE tablename = \
+                        'pqbus_pending_index_a_queue' AND indexdef LIKE '%lock IS NULL%' \
+                        ORDER BY indexname",
+                       &[])
+        .unwrap();
+    let indexes: Vec<String> = rows.iter().map(|r| r.get(0)).collect();
+    assert_eq!(vec!["pqbus_pending_index_a_queue_pending_position_idx",
+                    "pqbus_pending_index_a_queue_pending_priority_position_idx"],
+               indexes);
+
+    // With most of the table locked, the pop scan should go to the partial index.
+    c.batch_execute(r#"
+        INSERT INTO pqbus_pending_index_a_queue (message, lock)
+        SELECT 'x', CASE WHEN i <= 19990 THEN 'worker' END
+        FROM   generate_series(1, 20000) i;
+        ANALYZE pqbus_pending_index_a_queue;
+        "#)
+        .unwrap();
+    let rows = c.query("EXPLAIN SELECT id FROM pqbus_pending_index_a_queue \
+                        WHERE lock IS NULL AND visible_at <= now() \
+                        ORDER BY coalesce(requeued_seq, id) LIMIT 1",
+                       &[])
+        .unwrap();
+    let plan: Vec<String> = rows.iter().map(|r| r.get(0)).collect();
+    assert!(plan.iter()
+                .any(|l| l.contains("pqbus_pending_index_a_queue_pending_position_idx")),
+            "plan: {:?}",
+            plan);
+}
+
+#[test]
+fn test_pop_wait_detailed() {
+    test_setup();
+    drop_table("pqbus_wait_detailed_a_queue");
+    let bus = pqbus::new(db_uri(), "wait_detailed").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+
+    let start = Instant::now();
+    let outcome = queue.pop_wait_detailed::<FromUtf8Error>(Duration::from_millis(200)).unwrap();
+    assert_eq!(WaitOutcome::TimedOut, outcome);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    queue.push("1".to_string()).unwrap();
+    let outcome = queue.pop_wait_detailed::<FromUtf8Error>(Duration::from_millis(200)).unwrap();
+    assert_eq!(WaitOutcome::Message("1".to_string()), outcome);
+}
+
+#[test]
+fn test_push_with_size() {
+    test_setup();
+    drop_table("pqbus_push_with_size_a_queue");
+    let bus = pqbus::new(db_uri(), "push_with_size").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+
+    let sizes: Vec<i64> =
+        (0..3).map(|i| queue.push_with_size(i.to_string()).unwrap()).collect();
+    assert_eq!(vec![1, 2, 3], sizes);
+    queue.pop::<FromUtf8Error>().unwrap();
+    assert_eq!(3, queue.push_with_size("3".to_string()).unwrap());
+}
+
+#[derive(Default)]
+struct CountingMetrics {
+    pushes: AtomicUsize,
+    pops: AtomicUsize,
+    dead_letters: AtomicUsize,
+}
+
+impl Metrics for CountingMetrics {
+    fn on_push(&self, _queue: &str) {
+        self.pushes.fetch_add(1, Ordering::SeqCst);
+    }
+    fn on_pop(&self, _queue: &str) {
+        self.pops.fetch_add(1, Ordering::SeqCst);
+    }
+    fn on_dead_letter(&self, _queue: &str) {
+        self.dead_letters.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_metrics() {
+    test_setup();
+    drop_table("pqbus_metrics_a_queue");
+    drop_table("pqbus_metrics_a_dlq");
+    let bus = pqbus::new(db_uri(), "metrics").unwrap();
+    let metrics = Arc::new(CountingMetrics::default());
+    let queue: Queue<String> =
+        bus.queue_builder("a").with_metrics(metrics.clone()).build().unwrap();
+
+    queue.push("1".to_string()).unwrap();
+    queue.push_batch(vec!["2".to_string(), "3".to_string()]).unwrap();
+    assert_eq!(3, metrics.pushes.load(Ordering::SeqCst));
+
+    queue.pop::<FromUtf8Error>().unwrap();
+    queue.pop_delivery::<FromUtf8Error>().unwrap().unwrap().ack().unwrap();
+    queue.pop::<FromUtf8Error>().unwrap();
+    queue.pop::<FromUtf8Error>().unwrap();
+    assert_eq!(3, metrics.pops.load(Ordering::SeqCst));
+
+    let raw: Queue<Vec<u8>> = bus.queue("a").unwrap();
+    raw.push(vec![0xff]).unwrap();
+    assert!(queue.pop::<FromUtf8Error>().is_err());
+    assert_eq!(1, metrics.dead_letters.load(Ordering::SeqCst));
+    assert_eq!(3, metrics.pops.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_dedup() {
+    test_setup();
+    drop_table("pqbus_dedup_a_queue");
+    drop_table("pqbus_dedup_b_queue");
+    let bus = pqbus::new(db_uri(), "dedup").unwrap();
+    let queue: Queue<String> = bus.queue_builder("a").with_dedup().build().unwrap();
+
+    queue.push("1".to_string()).unwrap();
+    queue.push("1".to_string()).unwrap();
+    assert_eq!(1, queue.size().unwrap());
+    match queue.push_returning_id("1".to_string()) {
+        Err(PushError::Deduplicated) => (),
+        other => panic!("expected Deduplicated, got {:?}", other),
+    }
+    let batch = vec!["1".to_string(), "2".to_string(), "2".to_string()];
+    assert_eq!(1, queue.push_batch(batch).unwrap());
+    assert_eq!(2, queue.size().unwrap());
+
+    // Once consumed, the same message can be pushed again.
+    assert_eq!(Some("1".to_string()), queue.pop().unwrap());
+    queue.push("1".to_string()).unwrap();
+    assert_eq!(2, queue.size().unwrap());
+
+    let windowed: Queue<String> =
+        bus.queue_builder("b").with_dedup_window(Duration::from_millis(500)).build().unwrap();
+    windowed.push("1".to_string()).unwrap();
+    windowed.push("1".to_string()).unwrap();
+    assert_eq!(1, windowed.size().unwrap());
+    thread::sleep(Duration::from_millis(600));
+    windowed.push("1".to_string()).unwrap();
+    assert_eq!(2, windowed.size().unwrap());
+}
+
+#[test]
+fn test_was_created() {
+    test_setup();
+    drop_table("pqbus_was_created_a_queue");
+    drop_table("pqbus_was_created_b_queue");
+    let bus = pqbus::new(db_uri(), "was_created").unwrap();
+    let first: Queue<String> = bus.queue("a").unwrap();
+    assert!(first.was_created());
+    let second: Queue<String> = bus.queue("a").unwrap();
+    assert!(!second.was_created());
+
+    let children: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(|| {
+                let bus = pqbus::new(db_uri(), "was_created").unwrap();
+                let queue: Queue<String> = bus.queue("b").unwrap();
+                queue.was_created()
+            })
+        })
+        .collect();
+    let created = children.into_iter().map(|c| c.join().unwrap()).filter(|&c| c).count();
+    assert_eq!(1, created);
+}
+
+#[test]
+fn test_assume_table_exists() {
+    test_setup();
+    let c = conn().unwrap();
+    drop_table("pqbus_assume_exists_a_queue");
+    drop_table("pqbus_assume_exists_a_dlq");
+    drop_table("pqbus_assume_exists_missing_queue");
+    c.batch_execute(r#"
+        CREATE TABLE pqbus_assume_exists_a_queue (
+            id SERIAL PRIMARY KEY,
+            message bytea NOT NULL,
+            lock VARCHAR DEFAULT NULL,
+            visible_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            delivery_attempts INT NOT NULL DEFAULT 0,
+            priority INT NOT NULL DEFAULT 0,
+            locked_at TIMESTAMPTZ DEFAULT NULL,
+            headers JSONB NOT NULL DEFAULT '{}',
+            body_hash BYTEA DEFAULT NULL,
+            dedup_until TIMESTAMPTZ DEFAULT NULL,
+            enqueued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            expires_at TIMESTAMPTZ DEFAULT NULL,
+            requeued_seq BIGINT DEFAULT NULL
+        );
+        CREATE TABLE pqbus_assume_exists_a_dlq (
+            id SERIAL PRIMARY KEY,
+            message_id INT NOT NULL,
+            message bytea NOT NULL,
+            error TEXT NOT NULL,
+            failed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+        "#)
+        .unwrap();
+
+    let bus = pqbus::new(db_uri(), "assume_exists").unwrap();
+    let queue: Queue<String> = bus.queue_builder("a").assume_table_exists().build().unwrap();
+    let rows = c.query("SELECT count(*) FROM pg_indexes WHERE tablename = \
+                        'pqbus_assume_exists_a_queue' AND indexname LIKE '%headers_idx'",
+                       &[])
+        .unwrap();
+    assert_eq!(0, rows.get(0).get::<_, i64>(0));
+
+    queue.push("1".to_string()).unwrap();
+    assert_eq!("1", queue.pop_wait(Duration::from_secs(2)).unwrap().unwrap());
+
+    let child = thread::spawn(|| {
+        let bus = pqbus::new(db_uri(), "assume_exists").unwrap();
+        let queue: Queue<String> = bus.queue_builder("a").assume_table_exists().build().unwrap();
+        queue.pop_wait(Duration::from_secs(2))
+    });
+    thread::sleep(Duration::from_millis(200));
+    queue.push("2".to_string()).unwrap();
+    assert_eq!("2", child.join().unwrap().unwrap().unwrap());
+
+    let missing = bus.queue_builder("missing").assume_table_exists().build::<String>();
+    assert!(missing.is_err());
+}
+
+#[test]
+fn test_incompatible_schema() {
+    test_setup();
+    let c = conn().unwrap();
+    drop_table("pqbus_incompatible_a_queue");
+    c.batch_execute("CREATE TABLE pqbus_incompatible_a_queue (id SERIAL PRIMARY KEY, \
+                     payload TEXT, lock VARCHAR)")
+        .unwrap();
+
+    let bus = pqbus::new(db_uri(), "incompatible").unwrap();
+    match bus.queue::<_, String>("a") {
+        Err(BusError::IncompatibleSchema(ref table, ref missing)) => {
+            assert_eq!("pqbus_incompatible_a_queue", table);
+            assert_eq!(&vec!["message".to_string()], missing);
+        }
+        Err(e) => panic!("expected IncompatibleSchema, got {}", e),
+        Ok(_) => panic!("expected IncompatibleSchema"),
+    }
+    // The table is left as it was.
+    let rows = c.query("SELECT count(*) FROM information_schema.columns \
+                        WHERE table_name = 'pqbus_incompatible_a_queue'",
+                       &[])
+        .unwrap();
+    assert_eq!(3, rows.get(0).get::<_, i64>(0));
+}
+
+#[test]
+fn test_bus_schema() {
+    test_setup();
+    let c = conn().unwrap();
+    c.execute("DROP SCHEMA IF EXISTS tenant_a CASCADE", &[]).unwrap();
+    drop_table("pqbus_schema_a_queue");
+
+    let bus = pqbus::new(db_uri(), "schema").unwrap().with_schema("tenant_a").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    let rows = c.query("SELECT table_schema::text FROM information_schema.tables \
+                        WHERE table_name = 'pqbus_schema_a_queue'",
+                       &[])
+        .unwrap();
+    let schemas: Vec<String> = rows.iter().map(|r| r.get(0)).collect();
+    assert_eq!(vec!["tenant_a"], schemas);
+    assert_eq!(vec!["a"], bus.list_queues().unwrap());
+
+    let child = thread::spawn(|| {
+        let bus = pqbus::new(db_uri(), "schema").unwrap().with_schema("tenant_a").unwrap();
+        let queue: Queue<String> = bus.queue("a").unwrap();
+        queue.pop_wait::<FromUtf8Error>(Duration::from_secs(2))
+    });
+    thread::sleep(Duration::from_millis(200));
+    queue.push("1".to_string()).unwrap();
+    assert_eq!(Some("1".to_string()), child.join().unwrap().unwrap());
+
+    let public = pqbus::new(db_uri(), "schema").unwrap();
+    assert!(public.list_queues().unwrap().is_empty());
+
+    drop(queue);
+    bus.delete_queue("a").unwrap();
+    assert!(bus.list_queues().unwrap().is_empty());
+
+    match pqbus::new(db_uri(), "schema").unwrap().with_schema("tenant a") {
+        Err(BusError::InvalidSchemaName(_)) => (),
+        _ => panic!("expected InvalidSchemaName"),
+    }
+}
+
+#[test]
+fn test_custom_table_name() {
+    test_setup();
+    let c = conn().unwrap();
+    c.execute("CREATE SCHEMA IF NOT EXISTS pqbus_jobs", &[]).unwrap();
+    drop_table("pqbus_jobs.my_queue");
+    drop_table("pqbus_jobs.my_queue_dlq");
+
+    let bus = pqbus::new(db_uri(), "custom_table").unwrap();
+    let queue: Queue<String> =
+        bus.queue_builder("a").with_table_name("pqbus_jobs.my_queue").build().unwrap();
+    queue.push("1".to_string()).unwrap();
+    assert_eq!(1, queue.size().unwrap());
+    let rows = c.query("SELECT count(*) FROM pqbus_jobs.my_queue", &[]).unwrap();
+    assert_eq!(1, rows.get(0).get::<_, i64>(0));
+
+    assert_eq!("1", queue.pop_wait(Duration::from_secs(2)).unwrap().unwrap());
+
+    let child = thread::spawn(|| {
+        let bus = pqbus::new(db_uri(), "custom_table").unwrap();
+        let queue: Queue<String> =
+            bus.queue_builder("a").with_table_name("pqbus_jobs.my_queue").build().unwrap();
+        queue.pop_wait(Duration::from_secs(2))
+    });
+    thread::sleep(Duration::from_millis(200));
+    queue.push("2".to_string()).unwrap();
+    assert_eq!("2", child.join().unwrap().unwrap().unwrap());
+
+    let invalid = bus.queue_builder("a").with_table_name("pqbus_jobs.my queue").build::<String>();
+    match invalid {
+        Err(BusError::InvalidTableName(_)) => (),
+        _ => panic!("expected InvalidTableName"),
+    }
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_push_pop() {
+    test_setup();
+    drop_table("pqbus_async_a_queue");
+    let bus = pqbus::new_async(db_uri(), "async").await.unwrap();
+    let queue: pqbus::AsyncQueue<String> = bus.queue("a").await.unwrap();
+
+    let consumer = tokio::spawn(async move { queue.pop_async().await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!consumer.is_finished());
+
+    let bus = pqbus::new_async(db_uri(), "async").await.unwrap();
+    let queue: pqbus::AsyncQueue<String> = bus.queue("a").await.unwrap();
+    queue.push_async("1".to_string()).await.unwrap();
+
+    let popped = tokio::time::timeout(Duration::from_secs(2), consumer).await.unwrap().unwrap();
+    assert_eq!("1", popped.unwrap());
+}
+
+#[cfg(not(feature = "polling-only"))]
+#[test]
+fn test_drop_unlistens() {
+    test_setup();
+    let bus = pqbus::new(db_uri(), "drop_unlisten").unwrap();
+    let listening = || -> Vec<String> {
+        let rows = bus.connection().query("SELECT pg_listening_channels()", &[]).unwrap();
+        rows.iter().map(|r| r.get(0)).collect()
+    };
+    let channel = "pqbus_drop_unlisten_a_queue".to_string();
+
+    let first: Queue<String> = bus.queue("a").unwrap();
+    let second: Queue<String> = bus.queue("a").unwrap();
+    assert!(listening().contains(&channel));
+
+    drop(first);
+    assert!(listening().contains(&channel));
+
+    drop(second);
+    assert!(!listening().contains(&channel));
+}
+
+#[cfg(feature = "polling-only")]
+#[test]
+fn test_polling_only_push_pop() {
+    test_setup();
+    drop_table("pqbus_polling_only_a_queue");
+    let bus = pqbus::new(db_uri(), "polling_only").unwrap();
+    let mut queue: Queue<String> = bus.queue("a").unwrap();
+    queue.set_poll_interval(Duration::from_millis(50));
+    assert!(queue.is_empty().unwrap());
+
+    let child = thread::spawn(|| {
+        let bus = pqbus::new(db_uri(), "polling_only").unwrap();
+        let queue: Queue<String> = bus.queue("a").unwrap();
+        queue.pop_wait(Duration::new(2, 0))
+    });
+
+    queue.push("a".to_string()).unwrap();
+    assert_eq!("a", &child.join().unwrap().unwrap().unwrap());
+
+    let channels = bus.connection().query("SELECT pg_listening_channels()", &[]).unwrap();
+    assert!(channels.is_empty());
+}
+
+#[test]
+fn test_pop_callback_results() {
+    test_setup();
+    drop_table("pqbus_pop_callback_results_a_queue");
+    let bus = pqbus::new(db_uri(), "pop_callback_results").unwrap();
+    let queue = bus.queue("a").unwrap();
+    assert!(queue.is_empty().unwrap());
+
+    queue.push("ok".to_string()).unwrap();
+    queue.push("fail".to_string()).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let bus = pqbus::new(db_uri(), "pop_callback_results").unwrap();
+        let queue: Queue<String> = bus.queue("a").unwrap();
+        queue.pop_callback_results(|m| if m == "ok" { Ok(()) } else { Err(m) }, tx)
+    });
+
+    let first = rx.recv_timeout(Duration::new(5, 0)).unwrap();
+    let second = rx.recv_timeout(Duration::new(5, 0)).unwrap();
+
+    assert!(first.id < second.id);
+    assert_eq!(Outcome::Success, first.outcome);
+    assert_eq!(Outcome::Failure("fail".to_string()), second.outcome);
+}
+
+#[test]
+fn test_claim_batch_shares_batch_id() {
+    test_setup();
+    drop_table("pqbus_claim_batch_id_a_queue");
+    let bus = pqbus::new(db_uri(), "claim_batch_id").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    assert!(queue.is_empty().unwrap());
+
+    for i in 0..4 {
+        queue.push(format!("{}", i)).unwrap();
+    }
+
+    let batch = queue.claim_batch(3).unwrap();
+    assert_eq!(&["0", "1", "2"], batch.messages());
+
+    let locks = bus.connection()
+        .query("SELECT lock FROM pqbus_claim_batch_id_a_queue WHERE lock IS NOT NULL", &[])
+        .unwrap();
+    assert_eq!(3, locks.len());
+    for row in &locks {
+        let lock: String = row.get("lock");
+        assert_eq!(batch.id(), &lock);
+    }
+}
+
+#[test]
+fn test_reclaim_stale_batch() {
+    test_setup();
+    drop_table("pqbus_reclaim_batch_a_queue");
+    let bus = pqbus::new(db_uri(), "reclaim_batch").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    for i in 0..4 {
+        queue.push(format!("{}", i)).unwrap();
+    }
+
+    let batch = queue.claim_batch(3).unwrap();
+    let delivery = queue.pop_delivery::<FromUtf8Error>().unwrap().unwrap();
+    let locks = queue.in_flight_locks().unwrap();
+    assert_eq!(2, locks.len());
+    assert_eq!(Some(&3), locks.get(batch.id()));
+    assert_eq!(Some(&1), locks.get("me"));
+
+    assert!(queue.reclaim_stale(Duration::from_secs(60)).unwrap().is_empty());
+    let mut expected = vec![batch.id().to_string(), "me".to_string()];
+    expected.sort();
+    assert_eq!(expected, queue.reclaim_stale(Duration::from_secs(0)).unwrap());
+    assert!(queue.in_flight_locks().unwrap().is_empty());
+    assert_eq!(4, queue.pending().unwrap());
+    drop(delivery);
+}
+
+#[test]
+fn test_ack_nack_batch() {
+    test_setup();
+    drop_table("pqbus_ack_batch_a_queue");
+    let bus = pqbus::new(db_uri(), "ack_batch").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    for i in 0..5 {
+        queue.push(format!("{}", i)).unwrap();
+    }
+
+    let first = queue.claim_batch(2).unwrap();
+    let second = queue.claim_batch(2).unwrap();
+    assert_eq!(&["2", "3"], second.messages());
+    assert!(first.id() != second.id());
+    assert_eq!(1, queue.pending().unwrap());
+
+    assert_eq!(2, queue.nack_batch(second.id()).unwrap());
+    assert_eq!(2, queue.ack_batch(first.id()).unwrap());
+    assert_eq!(0, queue.ack_batch(first.id()).unwrap());
+    assert_eq!(3, queue.size().unwrap());
+    assert_eq!(&["2", "3", "4"], queue.claim_batch(5).unwrap().messages());
+}
+
+#[test]
+fn test_pop_batch() {
+    test_setup();
+    drop_table("pqbus_pop_batch_a_queue");
+    drop_table("pqbus_pop_batch_a_dlq");
+    let bus = pqbus::new(db_uri(), "pop_batch").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    let bytes: Queue<Vec<u8>> = bus.queue("a").unwrap();
+    for i in 0..4 {
+        queue.push(format!("{}", i)).unwrap();
+    }
+
+    assert_eq!(vec!["0", "1"], queue.pop_batch::<FromUtf8Error>(2).unwrap());
+    assert_eq!(2, queue.size().unwrap());
+
+    // A message that fails to convert is moved aside without losing the rest of the batch.
+    bytes.push(vec![0, 159, 146, 150]).unwrap();
+    match queue.pop_batch::<FromUtf8Error>(5) {
+        Err(PopError::BodyDeseralize(_)) => {}
+        _ => panic!("expected a conversion error"),
+    }
+    assert_eq!(1, queue.dead_letters().unwrap().len());
+    assert_eq!(vec!["2", "3"], queue.pop_batch::<FromUtf8Error>(5).unwrap());
+    assert!(queue.pop_batch::<FromUtf8Error>(5).unwrap().is_empty());
+}
+
+#[test]
+fn test_claim_batch_reuses_statement() {
+    test_setup();
+    drop_table("pqbus_claim_batch_limit_a_queue");
+    let bus = pqbus::new(db_uri(), "claim_batch_limit").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    for i in 0..10 {
+        queue.push(format!("{}", i)).unwrap();
+    }
+
+    let prepared = || -> i64 {
+        bus.connection()
+            .query("SELECT count(*) FROM pg_prepared_statements", &[])
+            .unwrap()
+            .get(0)
+            .get(0)
+    };
+    let before = prepared();
+    assert_eq!(1, queue.claim_batch(1).unwrap().messages().len());
+    assert_eq!(2, queue.claim_batch(2).unwrap().messages().len());
+    assert_eq!(5, queue.claim_batch(5).unwrap().messages().len());
+    assert_eq!(2, queue.claim_batch(100).unwrap().messages().len());
+    assert_eq!(before, prepared());
+}
+
+#[test]
+fn test_try_pop_up_to() {
+    test_setup();
+    drop_table("pqbus_try_pop_up_to_a_queue");
+    let bus = pqbus::new(db_uri(), "try_pop_up_to").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    for i in 0..20 {
+        queue.push(format!("{}", i)).unwrap();
+    }
+
+    let (messages, remaining) = queue.try_pop_up_to(5).unwrap();
+    assert_eq!(vec!["0", "1", "2", "3", "4"], messages);
+    assert_eq!(15, remaining);
+
+    let (messages, remaining) = queue.try_pop_up_to(100).unwrap();
+    assert_eq!(15, messages.len());
+    assert_eq!(0, remaining);
+    assert_eq!((vec![], 0), queue.try_pop_up_to(5).unwrap());
+}
+
+#[test]
+fn test_multithread_pop_batch() {
+    test_setup();
+    drop_table("pqbus_multithread_pop_batch_a_queue");
+    let bus = pqbus::new(db_uri(), "multithread_pop_batch").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    assert!(queue.is_empty().unwrap());
+
+    let count = 500;
+    queue.push_batch((0..count).map(|i| format!("{}", i))).unwrap();
+
+    let results = Arc::new(Mutex::new(vec![]));
+    let mut threads = vec![];
+    for _i in 0..5 {
+        let results = results.clone();
+        threads.push(thread::spawn(move || {
+            let bus = pqbus::new(db_uri(), "multithread_pop_batch").unwrap();
+            let queue: Queue<String> = bus.queue("a").unwrap();
+            loop {
+                let batch = queue.pop_batch(7).unwrap();
+                if batch.is_empty() {
+                    break;
+                }
+                let mut mine = results.lock().unwrap();
+                for m in batch {
+                    mine.push(i32::from_str(&m).unwrap());
+                }
+            }
+        }));
+    }
+
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    let mut results = results.lock().unwrap();
+    results.sort();
+    assert_eq!((0..count).collect::<Vec<i32>>(), *results);
+}
+
+#[test]
+fn test_pop_deletes_message() {
+    test_setup();
+    drop_table("pqbus_pop_deletes_a_queue");
+    let bus = pqbus::new(db_uri(), "pop_deletes").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    assert!(queue.is_empty().unwrap());
+
+    for i in 0..5 {
+        queue.push(format!("{}", i)).unwrap();
+    }
+    assert_eq!(5, queue.size().unwrap());
+
+    for _i in 0..5 {
+        assert!(queue.pop().unwrap().is_some());
+    }
+    assert!(queue.pop().unwrap().is_none());
+    assert_eq!(0, queue.size().unwrap());
+}
+
+#[test]
+fn test_delivery_ack_nack() {
+    test_setup();
+    drop_table("pqbus_delivery_ack_nack_a_queue");
+    let bus = pqbus::new(db_uri(), "delivery_ack_nack").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    assert!(queue.is_empty().unwrap());
+
+    queue.push("a".to_string()).unwrap();
+
+    {
+        let delivery = queue.pop_delivery().unwrap().unwrap();
+        assert_eq!("a", delivery.message());
+        assert!(queue.pop_delivery().unwrap().is_none());
+    }
+
+    let delivery = queue.pop_delivery().unwrap().unwrap();
+    assert_eq!("a", delivery.message());
+    delivery.nack().unwrap();
+
+    let delivery = queue.pop_delivery().unwrap().unwrap();
+    delivery.ack().unwrap();
+    assert!(queue.is_empty().unwrap());
+}
+
+#[test]
+fn test_delivery_dropped_after_connection_loss() {
+    test_setup();
+    drop_table("pqbus_delivery_lost_a_queue");
+    let proxy = Proxy::start();
+    let bus = pqbus::new(proxy.uri.clone(), "delivery_lost").unwrap();
+    let queue: Queue<String> = bus.queue_builder("a").lazy_statements().build().unwrap();
+    queue.push("a".to_string()).unwrap();
+
+    // The connection is lost under the unsettled delivery, and found lost before it's dropped.
+    // Releasing the message on it would trip a debug assertion in postgres.
+    let delivery = queue.pop_delivery::<FromUtf8Error>().unwrap().unwrap();
+    proxy.kill();
+    assert!(bus.ping().unwrap_err().is_connection_error());
+    drop(delivery);
+
+    // The message stays claimed, for `reclaim_expired` to release.
+    let bus = pqbus::new(db_uri(), "delivery_lost").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    assert_eq!(None, queue.pop::<FromUtf8Error>().unwrap());
+    assert_eq!(1, queue.size().unwrap());
+}
+
+#[derive(Debug)]
+struct RefuseSsl;
+
+impl NegotiateSsl for RefuseSsl {
+    fn negotiate_ssl(&self,
+                     _host: &str,
+                     _stream: Stream)
+                     -> Result<Box<dyn StreamWrapper>, Box<dyn Error + Sync + Send>> {
+        Err("refused".into())
+    }
+}
+
+/// Refuses SSL as `RefuseSsl` does, counting the connections that asked for it.
+#[cfg(feature = "pool")]
+#[derive(Debug)]
+struct CountSsl(AtomicUsize);
+
+#[cfg(feature = "pool")]
+impl NegotiateSsl for CountSsl {
+    fn negotiate_ssl(&self,
+                     _host: &str,
+                     _stream: Stream)
+                     -> Result<Box<dyn StreamWrapper>, Box<dyn Error + Sync + Send>> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Err("refused".into())
+    }
+}
+
+#[test]
+fn test_connection_failure_kinds() {
+    test_setup();
+    let bad_role = "postgres://pqbus_no_such_role@localhost/pqbus_test";
+    match pqbus::new_with_retry(bad_role, "fail", 1, 0) {
+        Err(BusError::Connection(ref uri, ConnectionFailure::Authentication(_))) => {
+            assert_eq!(bad_role, uri)
+        }
+        Err(e) => panic!("expected an authentication failure, got {}", e),
+        Ok(_) => panic!("connected as a role that does not exist"),
+    }
+
+    let bad_host = "postgres://postgres@127.0.0.1:1/pqbus_test";
+    match pqbus::new_with_retry(bad_host, "fail", 1, 0) {
+        Err(BusError::Connection(_, ref e @ ConnectionFailure::Unreachable(_))) => {
+            assert!(e.connect_error().is_some())
+        }
+        Err(e) => panic!("expected an unreachable server, got {}", e),
+        Ok(_) => panic!("connected to a closed port"),
+    }
+
+    match pqbus::new_with_retry("postgres://postgres@localhost/pqbus_no_such_db", "fail", 1, 0) {
+        Err(BusError::Connection(_, ConnectionFailure::UnknownDatabase(_))) => (),
+        Err(e) => panic!("expected an unknown database, got {}", e),
+        Ok(_) => panic!("connected to a database that does not exist"),
+    }
+
+    match pqbus::new_with_retry(db_uri(), "fail", 0, 0) {
+        Err(BusError::Connection(_, ref e @ ConnectionFailure::Retry(_))) => {
+            assert!(e.connect_error().is_none())
+        }
+        _ => panic!("expected no attempt to connect"),
+    }
+}
+
+#[test]
+fn test_new_with_ssl() {
+    test_setup();
+    assert!(pqbus::new_with_ssl(db_uri(), "ssl", Ssl::None).is_ok());
+
+    static REFUSE: RefuseSsl = RefuseSsl;
+    let bus = pqbus::new_with_ssl(db_uri(), "ssl", Ssl::Require(&REFUSE));
+    match bus {
+        Err(BusError::Connection(uri, _)) => assert_eq!(db_uri(), uri),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn test_push_batch() {
+    test_setup();
+    drop_table("pqbus_push_batch_a_queue");
+    let bus = pqbus::new(db_uri(), "push_batch").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    assert!(queue.is_empty().unwrap());
+
+    let pushed = queue.push_batch((0..1000).map(|i| format!("{}", i))).unwrap();
+    assert_eq!(1000, pushed);
+    assert_eq!(1000, queue.size().unwrap());
+    assert_eq!("0", &queue.pop().unwrap().unwrap());
+}
+
+#[test]
+fn test_peek() {
+    test_setup();
+    drop_table("pqbus_peek_a_queue");
+    let bus = pqbus::new(db_uri(), "peek").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    assert!(queue.peek().unwrap().is_none());
+
+    queue.push("a".to_string()).unwrap();
+    queue.push("b".to_string()).unwrap();
+
+    assert_eq!("a", &queue.peek().unwrap().unwrap());
+    assert_eq!("a", &queue.peek().unwrap().unwrap());
+    assert_eq!(2, queue.size().unwrap());
+
+    assert_eq!("a", &queue.pop().unwrap().unwrap());
+    assert_eq!("b", &queue.peek().unwrap().unwrap());
+
+    // A message that can't be converted is reported rather than hidden.
+    queue.pop::<FromUtf8Error>().unwrap();
+    let bytes: Queue<Vec<u8>> = bus.queue("a").unwrap();
+    bytes.push(vec![0xff, 0xfe]).unwrap();
+    match queue.peek::<FromUtf8Error>() {
+        Err(PopError::BodyDeseralize(_)) => (),
+        other => panic!("expected a conversion error, got {:?}", other.map_err(|e| e.to_string())),
+    }
+    assert_eq!(1, queue.size().unwrap());
+
+    #[cfg(feature = "compression")]
+    {
+        queue.purge().unwrap();
+        bytes.push(b"\0pqz\x63garbage".to_vec()).unwrap();
+        assert!(queue.peek::<FromUtf8Error>().is_err());
+    }
+}
+
+#[test]
+fn test_purge() {
+    test_setup();
+    drop_table("pqbus_purge_a_queue");
+    let bus = pqbus::new(db_uri(), "purge").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    assert_eq!(0, queue.purge().unwrap());
+
+    for i in 0..5 {
+        queue.push(format!("{}", i)).unwrap();
+    }
+
+    assert_eq!(5, queue.purge().unwrap());
+    assert!(queue.is_empty().unwrap());
+}
+
+#[test]
+fn test_purge_and_reset() {
+    test_setup();
+    drop_table("pqbus_purge_and_reset_a_queue");
+    let bus = pqbus::new(db_uri(), "purge_and_reset").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+
+    queue.push("a".to_string()).unwrap();
+    assert_eq!(2, queue.push_returning_id("b".to_string()).unwrap());
+    assert_eq!(2, queue.purge_and_reset().unwrap());
+    assert!(queue.is_empty().unwrap());
+
+    assert_eq!(1, queue.push_returning_id("c".to_string()).unwrap());
+}
+
+#[test]
+fn test_maintain() {
+    test_setup();
+    drop_table("pqbus_maintain_a_queue");
+    let bus = pqbus::new(db_uri(), "maintain").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    queue.push_batch((0..200).map(|i| i.to_string())).unwrap();
+    while queue.pop().unwrap().is_some() {}
+    assert!(queue.is_empty().unwrap());
+
+    queue.maintain().unwrap();
+    let c = conn().unwrap();
+    let rows = c.query("SELECT last_vacuum IS NOT NULL FROM pg_stat_user_tables \
+                        WHERE relname = 'pqbus_maintain_a_queue'",
+                       &[])
+        .unwrap();
+    assert!(rows.get(0).get::<_, bool>(0));
+    queue.push("after".to_string()).unwrap();
+    assert_eq!(Some("after".to_string()), queue.pop().unwrap());
+}
+
+#[test]
+fn test_delete_queue() {
+    test_setup();
+    drop_table("pqbus_delete_queue_a_queue");
+    let bus = pqbus::new(db_uri(), "delete_queue").unwrap();
+    {
+        let queue: Queue<String> = bus.queue("a").unwrap();
+        queue.push("a".to_string()).unwrap();
+    }
+
+    bus.delete_queue("a").unwrap();
+
+    let tables = bus.connection()
+        .query("SELECT table_name FROM information_schema.tables WHERE table_name = $1",
+               &[&"pqbus_delete_queue_a_queue"])
+        .unwrap();
+    assert!(tables.is_empty());
+
+    match bus.delete_queue("bad-name") {
+        Err(BusError::InvalidQueueName(n)) => assert_eq!("bad-name", &n),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn test_list_queues() {
+    test_setup();
+    for name in &["a", "b_c", "d"] {
+        drop_table(&format!("pqbus_list_queues_bus_{}_queue", name));
+    }
+    drop_table("pqbus_list_queues_bus_other");
+    drop_table("pqbus_list_queues_bus_x_y_queue");
+    let bus = pqbus::new(db_uri(), "list_queues_bus").unwrap();
+    assert!(bus.list_queues().unwrap().is_empty());
+
+    {
+        let _a: Queue<String> = bus.queue("a").unwrap();
+        let _b: Queue<String> = bus.queue("b_c").unwrap();
+        let _c: Queue<String> = bus.queue("d").unwrap();
+    }
+    bus.connection().execute("CREATE TABLE pqbus_list_queues_bus_other (id int)", &[]).unwrap();
+
+    // A bus whose name extends this one's keeps its queues to itself.
+    let longer = pqbus::new(db_uri(), "list_queues_bus_x").unwrap();
+    {
+        let _y: Queue<String> = longer.queue("y").unwrap();
+    }
+    assert_eq!(vec!["y"], longer.list_queues().unwrap());
+
+    // Tables from before queue tables were commented are listed by name.
+    bus.connection().execute("COMMENT ON TABLE pqbus_list_queues_bus_d_queue IS NULL", &[]).unwrap();
+
+    assert_eq!(vec!["a", "b_c", "d"], bus.list_queues().unwrap());
+}
+
+#[test]
+fn test_new_with_retry() {
+    test_setup();
+    let start = Instant::now();
+    let bus = pqbus::new_with_retry("postgres://postgres@127.0.0.1:1/pqbus_test", "retry", 3, 200);
+    let elapsed = start.elapsed();
+
+    assert!(bus.is_err());
+    assert!(elapsed >= Duration::from_millis(400));
+    assert!(elapsed < Duration::new(3, 0));
+}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct User {
+    name: String,
+    age: u32,
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_json_push_pop() {
+    use pqbus::Json;
+
+    test_setup();
+    drop_table("pqbus_json_a_queue");
+    let bus = pqbus::new(db_uri(), "json").unwrap();
+    let queue: Queue<Json<User>> = bus.queue("a").unwrap();
+    assert!(queue.is_empty().unwrap());
+
+    let user = User {
+        name: "sgibbs".to_string(),
+        age: 30,
+    };
+    queue.push(Json(user)).unwrap();
+
+    let popped = queue.pop().unwrap().unwrap().into_inner();
+    assert_eq!(User {
+                   name: "sgibbs".to_string(),
+                   age: 30,
+               },
+               popped);
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Reading {
+    sensor: u32,
+    value: i64,
+    samples: Vec<u16>,
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn test_bincode_push_pop() {
+    use pqbus::Bincode;
+
+    test_setup();
+    drop_table("pqbus_bincode_a_queue");
+    let bus = pqbus::new(db_uri(), "bincode").unwrap();
+    let queue: Queue<Bincode<Reading>> = bus.queue("a").unwrap();
+
+    let reading = Reading {
+        sensor: 7,
+        value: -123456,
+        samples: vec![1000, 2000, 3000, 4000],
+    };
+    let json_len = serde_json::to_vec(&reading).unwrap().len() as i32;
+    queue.push(Bincode(reading)).unwrap();
+
+    let c = conn().unwrap();
+    let rows = c.query("SELECT octet_length(message) FROM pqbus_bincode_a_queue", &[]).unwrap();
+    let stored_len: i32 = rows.get(0).get(0);
+    assert!(stored_len < json_len, "{} >= {}", stored_len, json_len);
+
+    let popped = queue.pop().unwrap().unwrap().into_inner();
+    assert_eq!(Reading {
+                   sensor: 7,
+                   value: -123456,
+                   samples: vec![1000, 2000, 3000, 4000],
+               },
+               popped);
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Order {
+    id: u64,
+    items: Vec<String>,
+}
+
+/// `Order` as a later version of its producer sends it.
+#[cfg(feature = "cbor")]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct OrderV2 {
+    id: u64,
+    items: Vec<String>,
+    note: Option<String>,
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn test_cbor_push_pop() {
+    use pqbus::Cbor;
+
+    test_setup();
+    drop_table("pqbus_cbor_a_queue");
+    let bus = pqbus::new(db_uri(), "cbor").unwrap();
+    let queue: Queue<Cbor<Order>> = bus.queue("a").unwrap();
+
+    let order = Order {
+        id: 42,
+        items: vec!["tea".to_string(), "scone".to_string()],
+    };
+    let json_len = serde_json::to_vec(&order).unwrap().len() as i32;
+    queue.push(Cbor(order)).unwrap();
+
+    let c = conn().unwrap();
+    let rows = c.query("SELECT octet_length(message) FROM pqbus_cbor_a_queue", &[]).unwrap();
+    let stored_len: i32 = rows.get(0).get(0);
+    assert!(stored_len < json_len, "{} >= {}", stored_len, json_len);
+
+    let popped = queue.pop().unwrap().unwrap().into_inner();
+    assert_eq!(Order {
+                   id: 42,
+                   items: vec!["tea".to_string(), "scone".to_string()],
+               },
+               popped);
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn test_cbor_added_optional_field() {
+    use pqbus::Cbor;
+
+    test_setup();
+    drop_table("pqbus_cbor_compat_a_queue");
+    let bus = pqbus::new(db_uri(), "cbor_compat").unwrap();
+    let old: Queue<Cbor<Order>> = bus.queue("a").unwrap();
+    let new: Queue<Cbor<OrderV2>> = bus.queue("a").unwrap();
+
+    // Consumers of the old type skip the field they don't know.
+    new.push(Cbor(OrderV2 {
+            id: 1,
+            items: vec!["tea".to_string()],
+            note: Some("no milk".to_string()),
+        }))
+        .unwrap();
+    assert_eq!(Order {
+                   id: 1,
+                   items: vec!["tea".to_string()],
+               },
+               old.pop().unwrap().unwrap().into_inner());
+
+    // Consumers of the new type read messages sent without it as having none.
+    old.push(Cbor(Order {
+            id: 2,
+            items: vec![],
+        }))
+        .unwrap();
+    assert_eq!(OrderV2 {
+                   id: 2,
+                   items: vec![],
+                   note: None,
+               },
+               new.pop().unwrap().unwrap().into_inner());
+}
+
+#[cfg(feature = "prometheus")]
+#[test]
+fn test_register_metrics() {
+    test_setup();
+    drop_table("pqbus_prometheus_a_queue");
+    let bus = pqbus::new(db_uri(), "prometheus").unwrap();
+    let queue: Queue<String> = bus.queue("a").unwrap();
+    let registry = prometheus::Registry::new();
+    queue.register_metrics(&registry).unwrap();
+
+    for i in 0..3 {
+        queue.push(format!("{}", i)).unwrap();
+    }
+    queue.pop().unwrap().unwrap();
+
+    let scrape: HashMap<String, f64> = registry.gather()
+        .iter()
+        .map(|family| {
+            let metric = &family.get_metric()[0];
+            let value = match family.name() {
+                name if name.ends_with("_total") => metric.get_counter().get_value(),
+                _ => metric.get_gauge().get_value(),
+            };
+            (family.name().to_string(), value)
+        })
+        .collect();
+    assert_eq!(Some(&2.0), scrape.get("pqbus_pending"));
+    assert_eq!(Some(&2.0), scrape.get("pqbus_messages"));
+    assert_eq!(Some(&3.0), scrape.get("pqbus_pushed_total"));
+    assert_eq!(Some(&1.0), scrape.get("pqbus_popped_total"));
+
+    // Each queue may only be registered once.
+    assert!(queue.register_metrics(&registry).is_err());
+}